@@ -0,0 +1,155 @@
+//Summaries of the CSV logs of a run: the round trip times analyzer.js reports, and the outcome of uplinks and joins
+
+use std::{fmt, io, path::Path};
+
+fn invalid_data(path: &Path, line: usize, content: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}:{line}: malformed row {content:?}", path.display()))
+}
+
+//the columns of every non empty row, with its line number for errors
+fn rows(path: &Path) -> io::Result<Vec<(usize, Vec<String>)>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, line.split(',').map(|c| c.trim().to_string()).collect()))
+        .collect())
+}
+
+fn column<T: std::str::FromStr>(path: &Path, line: usize, row: &[String], index: usize) -> io::Result<T> {
+    row.get(index).and_then(|c| c.parse().ok()).ok_or_else(|| invalid_data(path, line, &row.join(",")))
+}
+
+/// Round trip times from `timestamp,rtt` rows in milliseconds, like response_times.csv.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RttSummary {
+    pub count: usize,
+    pub mean_ms: f64,
+    pub std_dev_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub above_2s: usize,
+    pub duration_min: f64, //between the earliest and the latest timestamp, rows may be out of order
+}
+
+impl RttSummary {
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> io::Result<RttSummary> {
+        let path = path.as_ref();
+        let mut rtts = Vec::new();
+        let (mut first, mut last) = (u128::MAX, 0u128);
+        for (line, row) in rows(path)? {
+            let timestamp: u128 = column(path, line, &row, 0)?;
+            rtts.push(column::<f64>(path, line, &row, 1)?);
+            first = first.min(timestamp);
+            last = last.max(timestamp);
+        }
+        if rtts.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has no rows", path.display())));
+        }
+
+        let count = rtts.len();
+        let mean_ms = rtts.iter().sum::<f64>() / count as f64;
+        let variance = rtts.iter().map(|v| (v - mean_ms).powi(2)).sum::<f64>() / (count.max(2) - 1) as f64;
+        Ok(RttSummary {
+            count,
+            mean_ms,
+            std_dev_ms: variance.sqrt(),
+            min_ms: rtts.iter().copied().fold(f64::INFINITY, f64::min),
+            max_ms: rtts.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            above_2s: rtts.iter().filter(|v| **v > 1999.0).count(),
+            duration_min: (last - first) as f64 / 60000.0,
+        })
+    }
+}
+
+impl fmt::Display for RttSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Above 2s: {}, total: {}", self.above_2s, self.count)?;
+        writeln!(f, "Average Rtt: {}", self.mean_ms)?;
+        writeln!(f, "Max Rtt: {}", self.max_ms)?;
+        writeln!(f, "Min Rtt: {}", self.min_ms)?;
+        writeln!(f, "Std deviation: {}", self.std_dev_ms)?;
+        write!(f, "Duration: {} min", self.duration_min)
+    }
+}
+
+/// Final outcome of frames from rows ending with `attempt,delivered`, like uplink_attempts.csv and alarms.csv.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliverySummary {
+    pub frames: usize,
+    pub delivered: usize,
+    pub mean_attempts: f64,
+}
+
+impl DeliverySummary {
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> io::Result<DeliverySummary> {
+        let path = path.as_ref();
+        let (mut frames, mut delivered, mut attempts) = (0, 0, 0);
+        for (line, row) in rows(path)? {
+            let n = row.len();
+            if n < 2 {
+                return Err(invalid_data(path, line, &row.join(",")));
+            }
+            attempts += column::<usize>(path, line, &row, n - 2)?;
+            delivered += column::<bool>(path, line, &row, n - 1)? as usize;
+            frames += 1;
+        }
+        Ok(DeliverySummary { frames, delivered, mean_attempts: attempts as f64 / frames.max(1) as f64 })
+    }
+
+    pub fn delivery_ratio(&self) -> f64 {
+        self.delivered as f64 / self.frames.max(1) as f64
+    }
+}
+
+impl fmt::Display for DeliverySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} delivered ({:.2}%), {:.2} attempts on average", self.delivered, self.frames, self.delivery_ratio() * 100.0, self.mean_attempts)
+    }
+}
+
+/// Successful joins from the `timestamp,dev_eui,attempts` rows of joins.csv.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinSummary {
+    pub joins: usize,
+    pub mean_attempts: f64,
+    pub max_attempts: u32,
+}
+
+impl JoinSummary {
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> io::Result<JoinSummary> {
+        let path = path.as_ref();
+        let attempts = rows(path)?.into_iter().map(|(line, row)| column::<u32>(path, line, &row, 2)).collect::<io::Result<Vec<u32>>>()?;
+        Ok(JoinSummary {
+            joins: attempts.len(),
+            mean_attempts: attempts.iter().sum::<u32>() as f64 / attempts.len().max(1) as f64,
+            max_attempts: attempts.iter().copied().max().unwrap_or(0),
+        })
+    }
+}
+
+impl fmt::Display for JoinSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} joins, {:.2} attempts on average, {} at most", self.joins, self.mean_attempts, self.max_attempts)
+    }
+}
+
+#[test]
+fn results_summaries() {
+    let path = std::env::temp_dir().join("deloran_results_summaries.csv");
+    std::fs::write(&path, "1000,400\n61000,2400\n121000,800\n").unwrap();
+    let rtt = RttSummary::from_csv(&path).unwrap();
+    assert_eq!((rtt.count, rtt.mean_ms, rtt.min_ms, rtt.max_ms, rtt.above_2s), (3, 1200.0, 400.0, 2400.0, 1));
+    assert_eq!(rtt.duration_min, 2.0);
+    std::fs::write(&path, "61000,400\n121000,2400\n1000,800\n").unwrap();
+    assert_eq!(RttSummary::from_csv(&path).unwrap().duration_min, 2.0);
+
+    std::fs::write(&path, "1000,0011223344556677,3,1,true\n2000,0011223344556677,4,3,false\n").unwrap();
+    let delivery = DeliverySummary::from_csv(&path).unwrap();
+    assert_eq!((delivery.frames, delivery.delivered, delivery.mean_attempts), (2, 1, 2.0));
+
+    std::fs::write(&path, "1000,0011223344556677,3,yes\n").unwrap();
+    assert_eq!(DeliverySummary::from_csv(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    std::fs::remove_file(&path).unwrap();
+}
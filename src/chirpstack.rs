@@ -1,5 +1,11 @@
 #![allow(non_snake_case)]
-use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+use lorawan::device::{Device, LoRaWANVersion};
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::constants::{CHIRPSTACK_PAGE_SIZE, SIMULATED_DEVICE_DESCRIPTION};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceStatus {
@@ -31,7 +37,7 @@ pub struct ChirpstackListDeviceAns {
 pub struct ChirpstackDeviceKeys {
     pub devEui: String,
     pub nwkKey: String,
-    pub appKey: String, //always 0
+    pub appKey: String, //always 0 for 1.0 devices, whose root key is nwkKey
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -41,6 +47,18 @@ pub struct ChirpstackDevice {
     pub updatedAt: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceProfile {
+    pub id: String,
+    pub name: String,
+    pub macVersion: String, //LORAWAN_1_0_0 ... LORAWAN_1_1_0
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackDeviceProfile {
+    pub deviceProfile: DeviceProfile,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceActivation {
     pub devEui: String,
@@ -57,4 +75,365 @@ pub struct DeviceActivation {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChirpstackActivation {
     pub deviceActivation: DeviceActivation,
-}
\ No newline at end of file
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewDevice {
+    pub devEui: String,
+    pub joinEui: String,
+    pub name: String,
+    pub description: String,
+    pub applicationId: String,
+    pub deviceProfileId: String,
+    pub skipFcntCheck: bool,
+    pub isDisabled: bool,
+}
+
+//the bodies of POST /api/devices and POST /api/devices/{devEui}/keys, together
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackDeviceImport {
+    pub device: NewDevice,
+    pub deviceKeys: ChirpstackDeviceKeys,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackNewDevice {
+    pub device: NewDevice,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackNewDeviceKeys {
+    pub deviceKeys: ChirpstackDeviceKeys,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackNewDeviceProfile {
+    pub deviceProfile: NewDeviceProfile,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewDeviceProfile {
+    pub tenantId: String,
+    pub name: String,
+    pub region: String,             //EU868
+    pub macVersion: String,         //LORAWAN_1_0_4 or LORAWAN_1_1_0
+    pub regParamsRevision: String,  //RP002_1_0_3
+    pub adrAlgorithmId: String,     //default
+    pub supportsOtaa: bool,
+    pub uplinkInterval: u32,        //seconds, for the device status
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackListDeviceProfileAns {
+    pub totalCount: u32,
+    pub result: Vec<DeviceProfile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChirpstackCreateAns {
+    pub id: String,
+}
+
+#[derive(Debug)]
+pub enum ChirpstackError {
+    MissingUrl,
+    MissingToken,
+    Http(reqwest::Error),                                    //the request did not get an answer
+    Status { path: String, status: u16, message: String },  //an answer other than 2xx
+    Decode { path: String, message: String },
+}
+
+impl Display for ChirpstackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChirpstackError::MissingUrl => write!(f, "no ChirpStack URL configured and CHIRPSTACK_API_URL is not set"),
+            ChirpstackError::MissingToken => write!(f, "no ChirpStack API token configured and CHIRPSTACK_API_TOKEN is not set"),
+            ChirpstackError::Http(e) => write!(f, "{e}"),
+            ChirpstackError::Status { path, status, message } => write!(f, "{path}: HTTP {status} {message}"),
+            ChirpstackError::Decode { path, message } => write!(f, "{path}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ChirpstackError {}
+
+impl From<reqwest::Error> for ChirpstackError {
+    fn from(e: reqwest::Error) -> Self {
+        ChirpstackError::Http(e)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Client of the REST API of ChirpStack v4, like its REST proxy on port 8090.
+#[derive(Debug, Clone)]
+pub struct ChirpstackClient {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl ChirpstackClient {
+    /// Missing settings are read from CHIRPSTACK_API_URL and CHIRPSTACK_API_TOKEN.
+    pub fn new(url: Option<String>, token: Option<String>) -> Result<ChirpstackClient, ChirpstackError> {
+        let url = url.or_else(|| std::env::var("CHIRPSTACK_API_URL").ok()).ok_or(ChirpstackError::MissingUrl)?;
+        let token = token.or_else(|| std::env::var("CHIRPSTACK_API_TOKEN").ok()).ok_or(ChirpstackError::MissingToken)?;
+        Ok(ChirpstackClient { http: reqwest::Client::new(), url: url.trim_end_matches('/').to_string(), token })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn send(&self, method: Method, path: &str, query: &[(&str, String)], body: Option<String>) -> Result<Option<String>, ChirpstackError> {
+        let mut request = self
+            .http
+            .request(method, format!("{}/api/{path}", self.url))
+            .query(query)
+            .header("Accept", "application/json")
+            .header("Grpc-Metadata-Authorization", format!("Bearer {}", self.token));
+        if let Some(body) = body {
+            request = request.header("Content-Type", "application/json").body(body);
+        }
+        let ans = request.send().await?;
+        let status = ans.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let text = ans.text().await?;
+        if !status.is_success() {
+            //the gRPC gateway puts the reason in a JSON message field
+            let message = serde_json::from_str::<serde_json::Value>(&text)
+                .ok()
+                .and_then(|v| v.get("message").and_then(|m| m.as_str()).map(str::to_string))
+                .unwrap_or(text);
+            return Err(ChirpstackError::Status { path: path.to_string(), status: status.as_u16(), message });
+        }
+        Ok(Some(text))
+    }
+
+    fn decode<T: DeserializeOwned>(path: &str, text: &str) -> Result<T, ChirpstackError> {
+        serde_json::from_str(text).map_err(|e| ChirpstackError::Decode { path: path.to_string(), message: e.to_string() })
+    }
+
+    fn not_found(path: &str) -> ChirpstackError {
+        ChirpstackError::Status { path: path.to_string(), status: 404, message: String::from("not found") }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<Option<T>, ChirpstackError> {
+        match self.send(Method::GET, path, query, None).await? {
+            Some(text) => Self::decode(path, &text).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_required<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<T, ChirpstackError> {
+        self.get(path, query).await?.ok_or_else(|| Self::not_found(path))
+    }
+
+    async fn post<B: Serialize>(&self, path: &str, body: &B) -> Result<String, ChirpstackError> {
+        let body = serde_json::to_string(body).map_err(|e| ChirpstackError::Decode { path: path.to_string(), message: e.to_string() })?;
+        self.send(Method::POST, path, &[], Some(body)).await?.ok_or_else(|| Self::not_found(path))
+    }
+
+    //deleting what is already gone is not an error, clean ups can be run again
+    async fn delete(&self, path: &str) -> Result<(), ChirpstackError> {
+        self.send(Method::DELETE, path, &[], None).await.map(|_| ())
+    }
+
+    /// The devices of an application, `limit` at most, fetched a page at a time.
+    pub async fn list_devices(&self, application_id: &str, limit: Option<usize>) -> Result<Vec<DeviceAns>, ChirpstackError> {
+        let mut devices = Vec::new();
+        loop {
+            let page_size = limit.map_or(CHIRPSTACK_PAGE_SIZE, |l| CHIRPSTACK_PAGE_SIZE.min(l - devices.len()));
+            let query = [("applicationId", application_id.to_string()), ("limit", page_size.to_string()), ("offset", devices.len().to_string())];
+            let page: ChirpstackListDeviceAns = self.get_required("devices", &query).await?;
+            let received = page.result.len();
+            devices.extend(page.result);
+            if received == 0 || devices.len() >= page.totalCount as usize || limit.is_some_and(|l| devices.len() >= l) {
+                return Ok(devices);
+            }
+        }
+    }
+
+    pub async fn device_keys(&self, dev_eui: &str) -> Result<ChirpstackDeviceKeys, ChirpstackError> {
+        let device: ChirpstackDevice = self.get_required(&format!("devices/{dev_eui}/keys"), &[]).await?;
+        Ok(device.deviceKeys)
+    }
+
+    /// None for devices that never joined nor were activated.
+    pub async fn activation(&self, dev_eui: &str) -> Result<Option<DeviceActivation>, ChirpstackError> {
+        let activation: Option<ChirpstackActivation> = self.get(&format!("devices/{dev_eui}/activation"), &[]).await?;
+        Ok(activation.map(|a| a.deviceActivation))
+    }
+
+    pub async fn device_profile(&self, id: &str) -> Result<DeviceProfile, ChirpstackError> {
+        let profile: ChirpstackDeviceProfile = self.get_required(&format!("device-profiles/{id}"), &[]).await?;
+        Ok(profile.deviceProfile)
+    }
+
+    pub async fn list_device_profiles(&self, tenant_id: &str) -> Result<Vec<DeviceProfile>, ChirpstackError> {
+        let mut profiles = Vec::new();
+        loop {
+            let query = [("tenantId", tenant_id.to_string()), ("limit", CHIRPSTACK_PAGE_SIZE.to_string()), ("offset", profiles.len().to_string())];
+            let page: ChirpstackListDeviceProfileAns = self.get_required("device-profiles", &query).await?;
+            let received = page.result.len();
+            profiles.extend(page.result);
+            if received == 0 || profiles.len() >= page.totalCount as usize {
+                return Ok(profiles);
+            }
+        }
+    }
+
+    /// Returns the ID of the new profile.
+    pub async fn create_device_profile(&self, profile: NewDeviceProfile) -> Result<String, ChirpstackError> {
+        let text = self.post("device-profiles", &ChirpstackNewDeviceProfile { deviceProfile: profile }).await?;
+        Ok(Self::decode::<ChirpstackCreateAns>("device-profiles", &text)?.id)
+    }
+
+    pub async fn delete_device_profile(&self, id: &str) -> Result<(), ChirpstackError> {
+        self.delete(&format!("device-profiles/{id}")).await
+    }
+
+    pub async fn create_device(&self, device: NewDevice) -> Result<(), ChirpstackError> {
+        self.post("devices", &ChirpstackNewDevice { device }).await.map(|_| ())
+    }
+
+    /// Its keys and activation go with it.
+    pub async fn delete_device(&self, dev_eui: &str) -> Result<(), ChirpstackError> {
+        self.delete(&format!("devices/{dev_eui}")).await
+    }
+
+    pub async fn create_device_keys(&self, keys: ChirpstackDeviceKeys) -> Result<(), ChirpstackError> {
+        let path = format!("devices/{}/keys", keys.devEui);
+        self.post(&path, &ChirpstackNewDeviceKeys { deviceKeys: keys }).await.map(|_| ())
+    }
+
+    pub async fn delete_device_keys(&self, dev_eui: &str) -> Result<(), ChirpstackError> {
+        self.delete(&format!("devices/{dev_eui}/keys")).await
+    }
+
+    pub async fn activate_device(&self, activation: DeviceActivation) -> Result<(), ChirpstackError> {
+        let path = format!("devices/{}/activate", activation.devEui);
+        self.post(&path, &ChirpstackActivation { deviceActivation: activation }).await.map(|_| ())
+    }
+
+    pub async fn deactivate_device(&self, dev_eui: &str) -> Result<(), ChirpstackError> {
+        self.delete(&format!("devices/{dev_eui}/activation")).await
+    }
+
+    /// Creates a simulated device with its keys, and its session if it has one, so that ChirpStack accepts its frames.
+    pub async fn provision_device(&self, device: &Device, application_id: &str, device_profile_id: &str) -> Result<(), ChirpstackError> {
+        let dev_eui = hex(&**device.dev_eui());
+        let lorawan_1_1 = *device.version() == LoRaWANVersion::V1_1;
+        self.create_device(NewDevice {
+            devEui: dev_eui.clone(),
+            joinEui: hex(&**device.join_eui()),
+            name: format!("sim-{dev_eui}"),
+            description: String::from(SIMULATED_DEVICE_DESCRIPTION),
+            applicationId: application_id.to_string(),
+            deviceProfileId: device_profile_id.to_string(),
+            skipFcntCheck: false,
+            isDisabled: false,
+        })
+        .await?;
+        self.create_device_keys(ChirpstackDeviceKeys {
+            devEui: dev_eui.clone(),
+            nwkKey: hex(&**device.nwk_key()),
+            appKey: if lorawan_1_1 { hex(&**device.app_key()) } else { hex(&[0; 16]) },
+        })
+        .await?;
+        if let Some(session) = device.session() {
+            let network = session.network_context();
+            self.activate_device(DeviceActivation {
+                devEui: dev_eui,
+                devAddr: hex(network.dev_addr()),
+                appSKey: hex(&**session.application_context().app_s_key()),
+                nwkSEncKey: hex(&**network.nwk_s_enc_key()),
+                sNwkSIntKey: hex(&**network.s_nwk_s_int_key()),
+                fNwkSIntKey: hex(&**network.f_nwk_s_int_key()),
+                fCntUp: network.f_cnt_up(),
+                nFCntDown: network.nf_cnt_dwn(),
+                aFCntDown: session.application_context().af_cnt_dwn(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the devices of an application created by `provision_device`, returns how many.
+    pub async fn remove_simulated_devices(&self, application_id: &str) -> Result<usize, ChirpstackError> {
+        let simulated = self.list_devices(application_id, None).await?.into_iter().filter(|d| d.description == SIMULATED_DEVICE_DESCRIPTION).collect::<Vec<DeviceAns>>();
+        for d in simulated.iter() {
+            self.delete_device(&d.devEui).await?;
+        }
+        Ok(simulated.len())
+    }
+}
+
+#[tokio::test]
+async fn chirpstack_client_with_mock() {
+    use mockito::Matcher;
+
+    let mut server = mockito::Server::new_async().await;
+    let client = ChirpstackClient::new(Some(format!("{}/", server.url())), Some(String::from("secret"))).unwrap();
+    let page = |from: usize, to: usize| {
+        let devices = (from..to)
+            .map(|i| format!(r#"{{"createdAt":"","description":"simulated device","devEui":"{i:016x}","deviceProfileId":"p","deviceProfileName":"p","name":"sim","updatedAt":null}}"#))
+            .collect::<Vec<String>>();
+        format!(r#"{{"totalCount":150,"result":[{}]}}"#, devices.join(","))
+    };
+    let offset = |o: &str| Matcher::AllOf(vec![Matcher::UrlEncoded("applicationId".into(), "app".into()), Matcher::UrlEncoded("offset".into(), o.into())]);
+    let first = server.mock("GET", "/api/devices").match_query(offset("0")).match_header("Grpc-Metadata-Authorization", "Bearer secret").with_body(page(0, 100)).create_async().await;
+    let second = server.mock("GET", "/api/devices").match_query(offset("100")).with_body(page(100, 150)).create_async().await;
+
+    let devices = client.list_devices("app", None).await.unwrap();
+    assert_eq!(devices.len(), 150);
+    assert_eq!(devices[149].devEui, format!("{:016x}", 149));
+    first.assert_async().await;
+    second.assert_async().await;
+
+    server.mock("GET", "/api/devices/0000000000000001/activation").with_status(404).create_async().await;
+    assert!(client.activation("0000000000000001").await.unwrap().is_none());
+
+    let conflict = server
+        .mock("POST", "/api/devices")
+        .match_body(Matcher::PartialJsonString(r#"{"device":{"devEui":"0000000000000002","applicationId":"app"}}"#.into()))
+        .with_status(409)
+        .with_body(r#"{"code":6,"message":"object already exists"}"#)
+        .create_async()
+        .await;
+    let device = NewDevice {
+        devEui: String::from("0000000000000002"),
+        joinEui: String::from("0000000000000000"),
+        name: String::from("sim"),
+        description: String::from(SIMULATED_DEVICE_DESCRIPTION),
+        applicationId: String::from("app"),
+        deviceProfileId: String::from("p"),
+        skipFcntCheck: false,
+        isDisabled: false,
+    };
+    match client.create_device(device).await {
+        Err(ChirpstackError::Status { status, message, .. }) => assert_eq!((status, message.as_str()), (409, "object already exists")),
+        other => panic!("{other:?}"),
+    }
+    conflict.assert_async().await;
+
+    server.mock("POST", "/api/device-profiles").with_body(r#"{"id":"f3b1"}"#).create_async().await;
+    let profile = NewDeviceProfile {
+        tenantId: String::from("t"),
+        name: String::from("simulated 1.0.4"),
+        region: String::from("EU868"),
+        macVersion: String::from("LORAWAN_1_0_4"),
+        regParamsRevision: String::from("RP002_1_0_3"),
+        adrAlgorithmId: String::from("default"),
+        supportsOtaa: true,
+        uplinkInterval: 3600,
+    };
+    assert_eq!(client.create_device_profile(profile).await.unwrap(), "f3b1");
+
+    let deleted = server.mock("DELETE", Matcher::Regex(r"^/api/devices/[0-9a-f]{16}$".into())).expect(150).create_async().await;
+    assert_eq!(client.remove_simulated_devices("app").await.unwrap(), 150);
+    deleted.assert_async().await;
+}
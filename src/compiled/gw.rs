@@ -1,3 +1,5 @@
+//Generated by prost-build from ChirpStack's gw.proto. Hand edits to carry over when regenerating:
+//- TxAckStatus::DutyCycleOverflow = 11, as in upstream gw.proto, used by gateway_state.rs to reject downlinks over the duty cycle
 #![allow(clippy::enum_variant_names)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Modulation {
@@ -745,4 +747,7 @@ pub enum TxAckStatus {
     QueueFull = 9,
     /// Internal error.
     InternalError = 10,
+    /// Rejected because the sub-band of the requested frequency used up its duty cycle.
+    /// Added by hand, see the file header.
+    DutyCycleOverflow = 11,
 }
@@ -0,0 +1,208 @@
+//Device identities and root keys from a seed, so that the simulator and the network servers provision the same fleet
+
+use std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+};
+
+use lorawan::{
+    device::{Device, DeviceClass, LoRaWANVersion},
+    regional_parameters::region::{Region, RegionalParameters},
+    utils::eui::EUI64,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    chirpstack::{ChirpstackDeviceImport, ChirpstackDeviceKeys, NewDevice},
+    constants::SIMULATED_DEVICE_DESCRIPTION,
+};
+
+#[derive(Debug)]
+pub enum CredentialError {
+    Io(io::Error),
+    InvalidPrefix(String),
+    TooMany { requested: usize, available: u128 }, //more devices than DevEUIs left by the prefix
+}
+
+impl Display for CredentialError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::Io(e) => write!(f, "{e}"),
+            CredentialError::InvalidPrefix(prefix) => write!(f, "{prefix:?} is not an EUI prefix of at most 8 hex bytes"),
+            CredentialError::TooMany { requested, available } => write!(f, "{requested} devices requested, the DevEUI prefix leaves {available}"),
+        }
+    }
+}
+
+impl std::error::Error for CredentialError {}
+
+impl From<io::Error> for CredentialError {
+    fn from(e: io::Error) -> Self {
+        CredentialError::Io(e)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_prefix(prefix: &str) -> Result<Vec<u8>, CredentialError> {
+    let invalid = || CredentialError::InvalidPrefix(prefix.to_string());
+    if prefix.len() > 16 || prefix.len() % 2 == 1 {
+        return Err(invalid());
+    }
+    (0..prefix.len()).step_by(2).map(|i| u8::from_str_radix(prefix.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())).collect()
+}
+
+/// Identity and root key of a device, the key being both its NwkKey and its AppKey.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub dev_eui: [u8; 8],
+    pub join_eui: [u8; 8],
+    pub key: [u8; 16],
+    pub version: LoRaWANVersion,
+}
+
+impl Credentials {
+    pub fn dev_eui_hex(&self) -> String {
+        hex(&self.dev_eui)
+    }
+
+    /// Not activated: the activation split of the scenario decides between joining and ABP.
+    pub fn device(&self) -> Device {
+        Device::new(
+            DeviceClass::A,
+            Some(RegionalParameters::new(Region::EU863_870)),
+            EUI64::from_hex(&hex(&self.dev_eui)).unwrap(),
+            EUI64::from_hex(&hex(&self.join_eui)).unwrap(),
+            self.key.into(),
+            self.key.into(),
+            self.version,
+        )
+    }
+}
+
+/// The same seed and settings always give the same credentials, in the same order.
+#[derive(Debug, Clone)]
+pub struct CredentialGenerator {
+    rng: StdRng,
+    dev_eui_prefix: Vec<u8>,
+    join_eui_prefix: Vec<u8>,
+    lorawan_1_1_share: f64,
+    issued: HashSet<[u8; 8]>,
+}
+
+impl CredentialGenerator {
+    pub fn new(seed: u64) -> Self {
+        CredentialGenerator {
+            rng: StdRng::seed_from_u64(seed),
+            dev_eui_prefix: Vec::new(),
+            join_eui_prefix: Vec::new(),
+            lorawan_1_1_share: 0.0,
+            issued: HashSet::new(),
+        }
+    }
+
+    /// Leading bytes of every DevEUI, like an OUI, as hex.
+    pub fn with_dev_eui_prefix(mut self, prefix: &str) -> Result<Self, CredentialError> {
+        self.dev_eui_prefix = parse_prefix(prefix)?;
+        Ok(self)
+    }
+
+    /// Leading bytes of every JoinEUI as hex, all 8 of them for a single join server.
+    pub fn with_join_eui_prefix(mut self, prefix: &str) -> Result<Self, CredentialError> {
+        self.join_eui_prefix = parse_prefix(prefix)?;
+        Ok(self)
+    }
+
+    pub fn with_lorawan_1_1_share(mut self, share: f64) -> Self {
+        self.lorawan_1_1_share = share;
+        self
+    }
+
+    fn eui(rng: &mut StdRng, prefix: &[u8]) -> [u8; 8] {
+        let mut eui = rng.gen::<[u8; 8]>();
+        eui[..prefix.len()].copy_from_slice(prefix);
+        eui
+    }
+
+    /// `count` more devices, with DevEUIs never issued before by this generator.
+    pub fn generate(&mut self, count: usize) -> Result<Vec<Credentials>, CredentialError> {
+        let available = 1u128 << (8 * (8 - self.dev_eui_prefix.len()));
+        if (self.issued.len() + count) as u128 > available {
+            return Err(CredentialError::TooMany { requested: count, available: available - self.issued.len() as u128 });
+        }
+        let mut credentials = Vec::with_capacity(count);
+        while credentials.len() < count {
+            let dev_eui = Self::eui(&mut self.rng, &self.dev_eui_prefix);
+            let join_eui = Self::eui(&mut self.rng, &self.join_eui_prefix);
+            let key = self.rng.gen::<[u8; 16]>();
+            let version = if self.rng.gen_range(0.0..1.0) < self.lorawan_1_1_share { LoRaWANVersion::V1_1 } else { LoRaWANVersion::V1_0_4 };
+            if self.issued.insert(dev_eui) {
+                credentials.push(Credentials { dev_eui, join_eui, key, version });
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+/// DevEUI,JoinEUI,AppKey rows, the layout of devices_augmented.csv and of the csv device source.
+pub fn write_csv<P: AsRef<Path>>(credentials: &[Credentials], path: P) -> io::Result<()> {
+    let content = credentials.iter().map(|c| format!("{},{},{}\n", hex(&c.dev_eui), hex(&c.join_eui), hex(&c.key))).collect::<String>();
+    std::fs::write(path, content)
+}
+
+/// One serialized `Device` per line, for the network controllers and the sessions_file device source.
+pub fn write_deloran<P: AsRef<Path>>(credentials: &[Credentials], path: P) -> io::Result<()> {
+    let mut content = String::new();
+    for c in credentials {
+        content.push_str(&serde_json::to_string(&c.device()).map_err(io::Error::other)?);
+        content.push('\n');
+    }
+    std::fs::write(path, content)
+}
+
+/// One device-create and keys payload per line. The device profile of each device has to match its version.
+pub fn write_chirpstack<P: AsRef<Path>>(credentials: &[Credentials], path: P, application_id: &str, profile_1_0: &str, profile_1_1: &str) -> io::Result<()> {
+    let mut content = String::new();
+    for c in credentials {
+        let lorawan_1_1 = c.version == LoRaWANVersion::V1_1;
+        let import = ChirpstackDeviceImport {
+            device: NewDevice {
+                devEui: hex(&c.dev_eui),
+                joinEui: hex(&c.join_eui),
+                name: format!("sim-{}", hex(&c.dev_eui)),
+                description: String::from(SIMULATED_DEVICE_DESCRIPTION),
+                applicationId: application_id.to_string(),
+                deviceProfileId: String::from(if lorawan_1_1 { profile_1_1 } else { profile_1_0 }),
+                skipFcntCheck: false,
+                isDisabled: false,
+            },
+            deviceKeys: ChirpstackDeviceKeys {
+                devEui: hex(&c.dev_eui),
+                nwkKey: hex(&c.key),
+                appKey: if lorawan_1_1 { hex(&c.key) } else { hex(&[0; 16]) },
+            },
+        };
+        content.push_str(&serde_json::to_string(&import).map_err(io::Error::other)?);
+        content.push('\n');
+    }
+    std::fs::write(path, content)
+}
+
+#[test]
+fn seeded_credentials() {
+    let generate = |seed| CredentialGenerator::new(seed).with_dev_eui_prefix("70b3d5").unwrap().with_join_eui_prefix("0000000000000001").unwrap().generate(100).unwrap();
+    let fleet = generate(7);
+    assert_eq!(fleet, generate(7));
+    assert_ne!(fleet, generate(8));
+    assert!(fleet.iter().all(|c| c.dev_eui_hex().starts_with("70b3d5") && hex(&c.join_eui) == "0000000000000001"));
+    assert_eq!(fleet.iter().map(|c| c.dev_eui).collect::<HashSet<_>>().len(), 100);
+
+    let mut narrow = CredentialGenerator::new(7).with_dev_eui_prefix("70b3d5000000ff").unwrap();
+    assert_eq!(narrow.generate(256).unwrap().len(), 256);
+    assert!(matches!(narrow.generate(1), Err(CredentialError::TooMany { requested: 1, available: 0 })));
+    assert!(matches!(CredentialGenerator::new(7).with_dev_eui_prefix("70b3d"), Err(CredentialError::InvalidPrefix(_))));
+}
@@ -0,0 +1,320 @@
+//Where the simulated fleet comes from: files, a ChirpStack instance or a generator
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io,
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use lorawan::{
+    device::{
+        session_context::{ApplicationSessionContext, NetworkSessionContext, SessionContext},
+        Device, DeviceClass, LoRaWANVersion,
+    },
+    encryption::key::Key,
+    regional_parameters::region::{Region, RegionalParameters},
+    utils::eui::EUI64,
+};
+use rand::{rngs::StdRng, Rng};
+
+use crate::{
+    chirpstack::{ChirpstackClient, ChirpstackError, DeviceAns},
+    credentials::{CredentialError, CredentialGenerator, Credentials},
+};
+
+#[derive(Debug)]
+pub enum DeviceSourceError {
+    Io(io::Error),
+    Malformed { origin: String, line: usize, message: String }, //line numbers start at 1
+    Chirpstack(ChirpstackError),
+    Exhausted { origin: String, requested: usize, available: usize },
+    Credentials(CredentialError),
+}
+
+impl Display for DeviceSourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSourceError::Io(e) => write!(f, "{e}"),
+            DeviceSourceError::Malformed { origin, line, message } => write!(f, "{origin}:{line}: {message}"),
+            DeviceSourceError::Chirpstack(e) => write!(f, "{e}"),
+            DeviceSourceError::Exhausted { origin, requested, available } => write!(f, "{origin} has {available} devices, {requested} requested"),
+            DeviceSourceError::Credentials(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceSourceError {}
+
+impl From<io::Error> for DeviceSourceError {
+    fn from(e: io::Error) -> Self {
+        DeviceSourceError::Io(e)
+    }
+}
+
+impl From<CredentialError> for DeviceSourceError {
+    fn from(e: CredentialError) -> Self {
+        DeviceSourceError::Credentials(e)
+    }
+}
+
+impl From<ChirpstackError> for DeviceSourceError {
+    fn from(e: ChirpstackError) -> Self {
+        DeviceSourceError::Chirpstack(e)
+    }
+}
+
+/// Devices are returned as loaded: with the session of the source, if any, for ABP with given keys.
+#[async_trait]
+pub trait DeviceSource: Send {
+    fn describe(&self) -> String;
+
+    /// Exactly `count` devices, or `Exhausted` when the source has fewer.
+    async fn load(&mut self, count: usize, rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError>;
+}
+
+fn exhausted(origin: String, requested: usize, available: usize) -> Result<Vec<Device>, DeviceSourceError> {
+    Err(DeviceSourceError::Exhausted { origin, requested, available })
+}
+
+fn lorawan_version<R: Rng + ?Sized>(lorawan_1_1_share: f64, rng: &mut R) -> LoRaWANVersion {
+    if rng.gen_range(0.0..1.0) < lorawan_1_1_share {
+        LoRaWANVersion::V1_1
+    } else {
+        LoRaWANVersion::V1_0_4
+    }
+}
+
+/// Device with a random DevEUI and a random root key, used as both NwkKey and AppKey, without a session.
+pub fn generate_device<R: Rng + ?Sized>(lorawan_1_1_share: f64, rng: &mut R) -> Device {
+    let dev_eui = rng.gen::<[u8; 8]>().iter().map(|b| format!("{b:02x}")).collect::<String>();
+    let key = rng.gen::<[u8; 16]>();
+    Device::new(
+        DeviceClass::A,
+        Some(RegionalParameters::new(Region::EU863_870)),
+        EUI64::from_hex(&dev_eui).unwrap(),
+        EUI64::default(),
+        key.into(),
+        key.into(),
+        lorawan_version(lorawan_1_1_share, rng),
+    )
+}
+
+pub struct GeneratedSource {
+    pub lorawan_1_1_share: f64,
+}
+
+#[async_trait]
+impl DeviceSource for GeneratedSource {
+    fn describe(&self) -> String {
+        String::from("generated devices")
+    }
+
+    async fn load(&mut self, count: usize, rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+        Ok((0..count).map(|_| generate_device(self.lorawan_1_1_share, rng)).collect())
+    }
+}
+
+#[async_trait]
+impl DeviceSource for CredentialGenerator {
+    fn describe(&self) -> String {
+        String::from("seeded devices")
+    }
+
+    async fn load(&mut self, count: usize, _rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+        Ok(self.generate(count)?.iter().map(Credentials::device).collect())
+    }
+}
+
+//the first `count` non empty lines of a file, with their line number
+fn lines(path: &PathBuf, count: usize) -> Result<Vec<(usize, String)>, DeviceSourceError> {
+    let content = std::fs::read_to_string(path)?;
+    let lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .take(count)
+        .map(|(i, l)| (i + 1, l.trim().to_string()))
+        .collect::<Vec<(usize, String)>>();
+    Ok(lines)
+}
+
+/// Serialized devices, one per line, like node_sessions_loed.txt.
+pub struct JsonLinesSource {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl DeviceSource for JsonLinesSource {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn load(&mut self, count: usize, _rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+        let lines = lines(&self.path, count)?;
+        if lines.len() < count {
+            return exhausted(self.describe(), count, lines.len());
+        }
+        lines
+            .into_iter()
+            .map(|(line, content)| {
+                serde_json::from_str::<Device>(&content).map_err(|e| DeviceSourceError::Malformed { origin: self.describe(), line, message: e.to_string() })
+            })
+            .collect()
+    }
+}
+
+/// DevEUI,JoinEUI,AppKey rows, like devices_augmented.csv. The key is the root key of 1.0 and 1.1 devices alike.
+pub struct CsvSource {
+    pub path: PathBuf,
+    pub lorawan_1_1_share: f64,
+}
+
+#[async_trait]
+impl DeviceSource for CsvSource {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    async fn load(&mut self, count: usize, rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+        let lines = lines(&self.path, count)?;
+        if lines.len() < count {
+            return exhausted(self.describe(), count, lines.len());
+        }
+        let mut devices = Vec::with_capacity(count);
+        for (line, content) in lines {
+            let malformed = |message: &str| DeviceSourceError::Malformed { origin: self.describe(), line, message: message.to_string() };
+            let fields = content.split(',').map(str::trim).collect::<Vec<&str>>();
+            let [dev_eui, join_eui, key] = fields[..] else {
+                return Err(malformed("expected DevEUI,JoinEUI,AppKey"));
+            };
+            let dev_eui = EUI64::from_hex(dev_eui).map_err(|_| malformed("invalid DevEUI"))?;
+            let join_eui = EUI64::from_hex(join_eui).map_err(|_| malformed("invalid JoinEUI"))?;
+            let key = Key::from_hex(key).map_err(|_| malformed("invalid AppKey"))?;
+            devices.push(Device::new(
+                DeviceClass::A,
+                Some(RegionalParameters::new(Region::EU863_870)),
+                dev_eui,
+                join_eui,
+                key,
+                key,
+                lorawan_version(self.lorawan_1_1_share, rng),
+            ));
+        }
+        Ok(devices)
+    }
+}
+
+/// The devices of a ChirpStack application, with the MAC version of their device profile.
+pub struct ChirpstackSource {
+    pub client: ChirpstackClient,
+    pub application_id: String,
+    pub fetch_sessions: bool, //the sessions of activated devices, for ABP with given keys
+}
+
+impl ChirpstackSource {
+    //the version has to match the one ChirpStack expects, it is the MAC version of the device profile
+    async fn profile_version(&self, profile_id: &str) -> Result<LoRaWANVersion, ChirpstackError> {
+        Ok(match self.client.device_profile(profile_id).await?.macVersion.as_str() {
+            "LORAWAN_1_0_0" => LoRaWANVersion::V1_0,
+            "LORAWAN_1_0_1" => LoRaWANVersion::V1_0_1,
+            "LORAWAN_1_0_2" => LoRaWANVersion::V1_0_2,
+            "LORAWAN_1_0_3" => LoRaWANVersion::V1_0_3,
+            "LORAWAN_1_1_0" => LoRaWANVersion::V1_1,
+            _ => LoRaWANVersion::V1_0_4,
+        })
+    }
+
+    async fn device(&self, d: &DeviceAns, version: LoRaWANVersion) -> Result<Device, ChirpstackError> {
+        let invalid = |what: &str| ChirpstackError::Decode { path: format!("devices/{}", d.devEui), message: format!("invalid {what}") };
+        let keys = self.client.device_keys(&d.devEui).await?;
+        let mut device = Device::new(
+            DeviceClass::A,
+            Some(RegionalParameters::new(Region::EU863_870)),
+            EUI64::from_hex(&d.devEui).map_err(|_| invalid("DevEUI"))?,
+            EUI64::default(),
+            Key::from_hex(&keys.nwkKey).map_err(|_| invalid("nwkKey"))?,
+            Key::from_hex(&keys.appKey).map_err(|_| invalid("appKey"))?,
+            version,
+        );
+        if !self.fetch_sessions {
+            return Ok(device);
+        }
+
+        let Some(a) = self.client.activation(&d.devEui).await? else {
+            return Ok(device);
+        };
+        let key = |hex: &str, what: &str| Key::from_hex(hex).map_err(|_| invalid(what));
+        let dev_addr = u32::from_str_radix(&a.devAddr, 16).map_err(|_| invalid("devAddr"))?.to_be_bytes();
+        let home_net_id = [1, 2, 3];
+        let network_session = NetworkSessionContext::new(
+            key(&a.fNwkSIntKey, "fNwkSIntKey")?,
+            key(&a.sNwkSIntKey, "sNwkSIntKey")?,
+            key(&a.nwkSEncKey, "nwkSEncKey")?,
+            home_net_id,
+            dev_addr,
+            a.fCntUp,
+            a.nFCntDown,
+            0,
+        );
+        let application_session = ApplicationSessionContext::new(key(&a.appSKey, "appSKey")?, a.aFCntDown);
+        device.set_activation_abp(SessionContext::new(application_session, network_session));
+        Ok(device)
+    }
+}
+
+#[async_trait]
+impl DeviceSource for ChirpstackSource {
+    fn describe(&self) -> String {
+        format!("ChirpStack application {} at {}", self.application_id, self.client.url())
+    }
+
+    async fn load(&mut self, count: usize, _rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+        let listed = self.client.list_devices(&self.application_id, Some(count)).await?;
+        if listed.len() < count {
+            return exhausted(self.describe(), count, listed.len());
+        }
+
+        let mut profile_versions = HashMap::new();
+        let mut devices = Vec::with_capacity(count);
+        for d in listed.iter() {
+            if !profile_versions.contains_key(&d.deviceProfileId) {
+                let version = self.profile_version(&d.deviceProfileId).await?;
+                profile_versions.insert(d.deviceProfileId.clone(), version);
+            }
+            devices.push(self.device(d, profile_versions[&d.deviceProfileId]).await?);
+        }
+        Ok(devices)
+    }
+}
+
+/// Devices of several sources, in order, like 5000 from a CSV file and 20000 generated ones.
+pub async fn load_fleet(sources: &mut [(Box<dyn DeviceSource>, usize)], rng: &mut StdRng) -> Result<Vec<Device>, DeviceSourceError> {
+    let mut fleet = Vec::new();
+    for (source, count) in sources.iter_mut() {
+        fleet.extend(source.load(*count, rng).await?);
+    }
+    Ok(fleet)
+}
+
+#[tokio::test]
+async fn csv_and_generated_fleet() {
+    let mut rng = <StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let path = std::env::temp_dir().join("deloran_csv_and_generated_fleet.csv");
+    std::fs::write(&path, "e52eb029a0176c53,0ad4624a3793d65e,4aef773324aadf3e24bf31cbcda377a0\n\n855336e849aa7dd6,173bb90613c43154,95b781983f7c5e5394b7cd01619fb288\n").unwrap();
+    let mut sources: Vec<(Box<dyn DeviceSource>, usize)> = vec![
+        (Box::new(CsvSource { path: path.clone(), lorawan_1_1_share: 0.0 }), 2),
+        (Box::new(GeneratedSource { lorawan_1_1_share: 1.0 }), 3),
+    ];
+    let fleet = load_fleet(&mut sources, &mut rng).await.unwrap();
+    assert_eq!(fleet.len(), 5);
+    assert_eq!(*fleet[1].dev_eui(), EUI64::from_hex("855336e849aa7dd6").unwrap());
+    assert!(fleet[2..].iter().all(|d| *d.version() == LoRaWANVersion::V1_1));
+
+    let mut csv = CsvSource { path: path.clone(), lorawan_1_1_share: 0.0 };
+    assert!(matches!(csv.load(3, &mut rng).await, Err(DeviceSourceError::Exhausted { requested: 3, available: 2, .. })));
+    std::fs::write(&path, "e52eb029a0176c53,0ad4624a3793d65e\n").unwrap();
+    assert!(matches!(csv.load(1, &mut rng).await, Err(DeviceSourceError::Malformed { line: 1, .. })));
+    std::fs::remove_file(&path).unwrap();
+}
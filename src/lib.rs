@@ -1,7 +1,11 @@
+pub mod analysis;
 pub mod chirpstack;
 pub mod compiled;
+pub mod credentials;
+pub mod device_source;
 pub mod logger;
 pub mod physical_simulator;
+pub mod scenario;
 pub mod traffic_models;
 
 pub mod constants {
@@ -12,13 +16,34 @@ pub mod constants {
     pub const RANDOM_JOIN_DELAY: u64 = 180;
     pub const FIXED_PACKET_DELAY: u64 = 60;
     pub const RANDOM_PACKET_DELAY: u64 = 180;
-    pub const _CONFIRMED_AVERAGE_SEND: u8 = 10;
+    pub const CONFIRMED_SHARE: f64 = 1.0; //uplinks sent as confirmed by the default payload profile
+    pub const MIN_UPLINK_INTERVAL: f64 = 30.0; //seconds, the LOED tables are cut below it
+    pub const LOED_REGULAR_TRAFFIC_PATH: &str = "loed_regular_traffic_distribution.csv"; //periods of the LOED nodes sending regularly
+    pub const LOED_UNREGULAR_TRAFFIC_PATH: &str = "loed_unregular_traffic_distribution.csv"; //intervals of the other LOED nodes
     pub const STARTING_DEV_NONCE: u32 = 0;
-    pub const STARTING_FCNT_UP: u32 = 730;
+    pub const LORAWAN_1_1_SHARE: f64 = 0.0; //devices built from keys alone that speak LoRaWAN 1.1 instead of 1.0.4
+    //shares of OTAA devices and of ABP devices with generated keys, the others use ABP with the keys they are loaded with
+    pub const OTAA_SHARE: f64 = 0.0;
+    pub const ABP_GENERATED_KEYS_SHARE: f64 = 0.0;
+    pub const COLD_START: bool = false; //join every device, ignoring the sessions they were loaded with
+    pub const LINK_CHECK_REQ_EVERY: u32 = 50;
+    pub const DEVICE_TIME_REQ_EVERY: u32 = 200;
+    pub const BATTERY_DRAIN_PER_UPLINK: f32 = 0.00002;
+
+    pub const GATEWAY_MAX_TX_POWER_DBM: i32 = 27; //downlinks asking for more are refused with TX_POWER
+    pub const DOWNLINK_MIN_LEAD_MS: u128 = 30; //a downlink due sooner than this is TOO_LATE, the concentrator could not load it
+    pub const DOWNLINK_MAX_LEAD_MS: u128 = 60_000; //and one due later than this TOO_EARLY
+    pub const GATEWAY_STATS_INTERVAL: u64 = 30; //seconds between two stats messages of a ChirpStack or Semtech UDP gateway
+    pub const GWMP_PULL_INTERVAL: u64 = 10; //seconds between two PULL_DATA keepalives of a Semtech UDP gateway
+    pub const CHIRPSTACK_PAGE_SIZE: usize = 100; //devices and profiles per request when listing them
+    pub const SIMULATED_DEVICE_DESCRIPTION: &str = "simulated device"; //of the devices the simulator provisions into ChirpStack
 
     pub const ACTIVE_LOGGER: bool = true;
     pub const LOGGER_PRINTLN: bool = true;
 
     pub const RTT_LOG_PATH: &str = "rtt_times.csv";
     pub const PRINT_LOG_PATH: &str = "log.txt";
+    pub const FUOTA_REPORT_PATH: &str = "fuota_report.csv";
+    pub const DEV_NONCES_PATH: &str = "dev_nonces.csv";
+    pub const SESSION_STORE_PATH: &str = "session_store.jsonl";
 }
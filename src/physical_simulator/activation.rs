@@ -0,0 +1,151 @@
+//Activation of the simulated devices: OTAA ones join during the run, ABP ones start with a session
+//given by the device source or generated here
+
+use lazy_static::lazy_static;
+use lorawan::{
+    device::{
+        session_context::{ApplicationSessionContext, NetworkSessionContext, SessionContext},
+        Device, DeviceClass, LoRaWANVersion,
+    },
+    encryption::key::Key,
+    regional_parameters::region::{Region, RegionalParameters},
+    utils::eui::EUI64,
+};
+use rand::Rng;
+
+use crate::logger::Logger;
+
+lazy_static! {
+    //generated sessions are unknown to the network server until they are provisioned from this file
+    static ref GENERATED_SESSIONS: Logger = Logger::new("./generated_abp_sessions.csv", true, false);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActivationMode {
+    Otaa,
+    AbpGivenKeys,     //the session provided by the device source
+    AbpGeneratedKeys, //a random DevAddr and random session keys
+}
+
+/// Shares of the population activated by OTAA and by ABP with generated keys,
+/// the remaining devices use ABP with the keys they are loaded with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ActivationSplit {
+    pub otaa: f64,
+    pub abp_generated_keys: f64,
+}
+
+impl ActivationSplit {
+    pub fn pick<R: Rng + ?Sized>(&self, rng: &mut R) -> ActivationMode {
+        let x = rng.gen_range(0.0..1.0);
+        if x < self.otaa {
+            ActivationMode::Otaa
+        } else if x < self.otaa + self.abp_generated_keys {
+            ActivationMode::AbpGeneratedKeys
+        } else {
+            ActivationMode::AbpGivenKeys
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Random ABP session with a DevAddr of NetID 000000 (the ChirpStack default) and counters at 0.
+/// A 1.0 session has a single NwkSKey, stored in all three network keys.
+pub fn generate_abp_session<R: Rng + ?Sized>(dev_eui: &EUI64, lorawan_1_1: bool, rng: &mut R) -> SessionContext {
+    let mut dev_addr = rng.gen::<[u8; 4]>();
+    dev_addr[0] &= 0x01; //NwkID 0 in the 7 most significant bits
+    let f_nwk_s_int_key = rng.gen::<[u8; 16]>();
+    let (s_nwk_s_int_key, nwk_s_enc_key) = if lorawan_1_1 { (rng.gen(), rng.gen()) } else { (f_nwk_s_int_key, f_nwk_s_int_key) };
+    let app_s_key = rng.gen::<[u8; 16]>();
+
+    let network_session = NetworkSessionContext::new(
+        f_nwk_s_int_key.into(),
+        s_nwk_s_int_key.into(),
+        nwk_s_enc_key.into(),
+        [0; 3],
+        dev_addr,
+        0,
+        0,
+        0,
+    );
+    let session = SessionContext::new(ApplicationSessionContext::new(app_s_key.into(), 0), network_session);
+    GENERATED_SESSIONS.write(&format!(
+        "{},{},{},{},{},{}",
+        dev_eui,
+        hex(&dev_addr),
+        hex(&f_nwk_s_int_key),
+        hex(&s_nwk_s_int_key),
+        hex(&nwk_s_enc_key),
+        hex(&app_s_key)
+    ));
+    session
+}
+
+/// Brings a device loaded from a source to the state its activation mode starts from: without any
+/// session for OTAA, so that it joins, or with `given` (or the session it was loaded with) or a
+/// generated session for ABP.
+pub fn provision<R: Rng + ?Sized>(mut device: Device, mode: ActivationMode, given: Option<SessionContext>, rng: &mut R) -> Device {
+    match mode {
+        ActivationMode::Otaa => {
+            let mut fresh = Device::new(
+                DeviceClass::A,
+                Some(RegionalParameters::new(Region::EU863_870)),
+                *device.dev_eui(),
+                *device.join_eui(),
+                Key::from(**device.nwk_key()),
+                Key::from(**device.app_key()),
+                *device.version(),
+            );
+            fresh.set_dev_nonce(device.dev_nonce());
+            fresh
+        }
+        ActivationMode::AbpGivenKeys => {
+            if let Some(session) = given {
+                device.set_activation_abp(session);
+            }
+            device
+        }
+        ActivationMode::AbpGeneratedKeys => {
+            let session = generate_abp_session(device.dev_eui(), *device.version() == LoRaWANVersion::V1_1, rng);
+            device.set_activation_abp(session);
+            device
+        }
+    }
+}
+
+#[test]
+fn activation_split_and_generated_keys() {
+    use crate::device_source::generate_device;
+
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let split = ActivationSplit { otaa: 0.5, abp_generated_keys: 0.3 };
+    let mut counts = [0i32; 3];
+    for _ in 0..10_000 {
+        match split.pick(&mut rng) {
+            ActivationMode::Otaa => counts[0] += 1,
+            ActivationMode::AbpGeneratedKeys => counts[1] += 1,
+            ActivationMode::AbpGivenKeys => counts[2] += 1,
+        }
+    }
+    assert!((counts[0] - 5000).abs() < 200 && (counts[1] - 3000).abs() < 200 && (counts[2] - 2000).abs() < 200);
+
+    //a 1.0 session has a single network key, a 1.1 one three different ones, both with a DevAddr of NetID 000000
+    for lorawan_1_1 in [false, true] {
+        let device = provision(generate_device(if lorawan_1_1 { 1.0 } else { 0.0 }, &mut rng), ActivationMode::AbpGeneratedKeys, None, &mut rng);
+        let network = device.session().unwrap().network_context();
+        assert_eq!(network.dev_addr()[0] >> 1, 0);
+        assert_eq!(network.f_cnt_up(), 0);
+        assert_eq!(network.f_nwk_s_int_key() != network.s_nwk_s_int_key(), lorawan_1_1);
+        assert_eq!(network.f_nwk_s_int_key() != network.nwk_s_enc_key(), lorawan_1_1);
+
+        //OTAA drops the session and keeps the DevNonce, to join with the next one
+        let mut device = device;
+        device.set_dev_nonce(9);
+        let device = provision(device, ActivationMode::Otaa, None, &mut rng);
+        assert!(device.session().is_none());
+        assert_eq!(device.dev_nonce(), 9);
+    }
+}
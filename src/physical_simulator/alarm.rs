@@ -0,0 +1,76 @@
+//Correlated traffic: a physical event (fire, flood, earthquake) sensed by every node of a zone,
+//which all send an alarm uplink within seconds of each other
+
+use std::time::Duration;
+
+use lorawan_device::communicator::Position;
+use rand::Rng;
+
+use super::payload::PayloadProfile;
+
+/// "At `at`, something happens at `centre` and spreads at `propagation_speed` up to `radius`".
+#[derive(Clone, Debug)]
+pub struct AlarmEvent {
+    pub at: Duration, //since the start of the run
+    pub centre: Position,
+    pub radius: f32,            //meters
+    pub propagation_speed: f32, //meters per second, infinite for events sensed everywhere at once
+    pub reaction_delay: Duration, //from sensing the event to the alarm uplink
+    pub reaction_jitter: Duration, //random extra delay, up to this
+    pub payload: Option<PayloadProfile>, //of the alarm uplinks, the payload profile of each node otherwise
+}
+
+impl AlarmEvent {
+    pub fn new(at: Duration, centre: Position, radius: f32, propagation_speed: f32) -> Self {
+        AlarmEvent {
+            at,
+            centre,
+            radius,
+            propagation_speed,
+            reaction_delay: Duration::from_secs(1),
+            reaction_jitter: Duration::from_secs(2),
+            payload: None,
+        }
+    }
+
+    pub fn with_reaction(mut self, delay: Duration, jitter: Duration) -> Self {
+        self.reaction_delay = delay;
+        self.reaction_jitter = jitter;
+        self
+    }
+
+    pub fn with_payload(mut self, payload: PayloadProfile) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// When the node at `position` sends its alarm, since the start of the run, or None when it is out of reach.
+    pub fn alarm_time<R: Rng + ?Sized>(&self, position: &Position, rng: &mut R) -> Option<Duration> {
+        let distance = position.distance(&self.centre);
+        if distance > self.radius {
+            return None;
+        }
+        let propagation = Duration::from_secs_f32(distance / self.propagation_speed);
+        Some(self.at + propagation + self.reaction_delay + self.reaction_jitter.mul_f64(rng.gen_range(0.0..1.0)))
+    }
+}
+
+#[test]
+fn alarm_reach_and_delay() {
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let at = Duration::from_secs(3600);
+    let event = AlarmEvent::new(at, Position { x: 100.0, y: 100.0, z: 0.0 }, 300.0, 5.0).with_reaction(Duration::from_secs(1), Duration::ZERO);
+
+    //sensed when the event has covered the distance, then reported after the reaction delay
+    assert_eq!(event.alarm_time(&Position { x: 100.0, y: 100.0, z: 0.0 }, &mut rng), Some(at + Duration::from_secs(1)));
+    assert_eq!(event.alarm_time(&Position { x: 400.0, y: 100.0, z: 0.0 }, &mut rng), Some(at + Duration::from_secs(61)));
+    assert_eq!(event.alarm_time(&Position { x: 100.0, y: -150.0, z: 0.0 }, &mut rng), Some(at + Duration::from_secs(51)));
+    //out of the radius
+    assert_eq!(event.alarm_time(&Position { x: 400.0, y: 400.0, z: 0.0 }, &mut rng), None);
+
+    let event = event.with_reaction(Duration::from_secs(1), Duration::from_secs(2));
+    for _ in 0..100 {
+        let t = event.alarm_time(&Position { x: 100.0, y: 150.0, z: 0.0 }, &mut rng).unwrap();
+        assert!(at + Duration::from_secs(11) <= t && t <= at + Duration::from_secs(13));
+    }
+}
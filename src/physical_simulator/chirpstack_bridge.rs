@@ -1,19 +1,28 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use lorawan::physical_parameters::{CodeRate, LoRaBandwidth, SpreadingFactor};
 use lorawan_device::communicator::{Position, ReceivedTransmission, Transmission};
-use paho_mqtt::AsyncClient;
 use prost::Message;
 use tokio::sync::mpsc::{Receiver, Sender};
-use crate::compiled::gw::{modulation::Parameters, DownlinkFrame, LoraModulationInfo, Modulation, UplinkFrame, UplinkRxInfo, UplinkTxInfo};
+use crate::compiled::common::{Location, LocationSource};
+use crate::compiled::gw::{
+    conn_state::State, modulation::Parameters, timing, CodeRate as GwCodeRate, ConnState, CrcStatus, DownlinkFrame, DownlinkFrameItem, DownlinkTxAck, DownlinkTxAckItem, GatewayStats, LoraModulationInfo,
+    Modulation, PerModulationCount, TxAckStatus, UplinkFrame, UplinkRxInfo, UplinkTxInfo,
+};
+use crate::constants::GATEWAY_STATS_INTERVAL;
 
-use super::{node::NodeConfig, utils::get_sensitivity, world::World};
+use super::{gateway_state::{concentrator_channel, parse_code_rate, tx_ack_name, DownlinkRequest, GatewayCounters, GatewayState, LoRaModulation}, mqtt::{MqttConfig, SharedMqttClient}, node::NodeConfig, utils::{concentrator_counter, counter_to_unix_ms, get_sensitivity, gps_epoch_to_unix_ms, unix_to_gps_epoch_ms, GeoPosition}, world::World};
 
 
 #[derive(Clone, Debug)]
 pub struct ChirpstackBridgeConfig {
-    pub gwid: &'static str,
+    pub gwid: String,
     pub node_config: NodeConfig,
+    pub mqtt: MqttConfig, //gateways of the same broker share one connection
+    pub location: Option<GeoPosition>, //reported to ChirpStack, none when the scenario has no origin
 }
 
 impl ChirpstackBridgeConfig {
@@ -27,8 +36,10 @@ impl ChirpstackBridgeConfig {
 #[derive(Debug)]
 pub struct ChirpstackBridge {
     id: u32,
-    gwid: &'static str,
+    gwid: String,
     node_config: NodeConfig,
+    mqtt: MqttConfig,
+    location: Option<Location>,
     sender: Sender<Transmission>,
     receiver: Receiver<ReceivedTransmission>,
 }
@@ -39,6 +50,14 @@ impl ChirpstackBridge {
             id,
             gwid: config.gwid,
             node_config: config.node_config,
+            mqtt: config.mqtt,
+            location: config.location.map(|l| Location {
+                latitude: l.latitude,
+                longitude: l.longitude,
+                altitude: l.altitude,
+                source: LocationSource::Config as i32,
+                accuracy: 0.0,
+            }),
             sender,
             receiver,
         }
@@ -52,6 +71,10 @@ impl ChirpstackBridge {
         self.node_config.receiver_sensitivity
     }
 
+    pub fn mqtt_config(&self) -> &MqttConfig {
+        &self.mqtt
+    }
+
     pub fn get_position(&self) -> Position {
         self.node_config.position
     }
@@ -62,133 +85,347 @@ impl ChirpstackBridge {
         t.arrival_stats.rssi > get_sensitivity(&t.transmission)        //signal strength is greater than receiver sensitivity
     }
 
-    fn round_fq_u32(fq: f64) -> u32 {
-        let f: [u32; 8] = [868_100_000, 868_300_000, 868_500_000, 867_100_000, 867_300_000, 867_500_000, 867_700_000, 867_900_000];
-        for ff in f {
-            if (fq - ff as f64).abs() < 1000.0 {
-                return ff;
-            }
+    fn duration_ms(d: &prost_types::Duration) -> i128 {
+        d.seconds as i128 * 1000 + d.nanos as i128 / 1_000_000
+    }
+
+    //as the 5 of 4/5
+    fn code_rate(lora: &LoraModulationInfo) -> Option<u8> {
+        match GwCodeRate::try_from(lora.code_rate) {
+            Ok(GwCodeRate::Cr45) => Some(5),
+            Ok(GwCodeRate::Cr46) => Some(6),
+            Ok(GwCodeRate::Cr47) => Some(7),
+            Ok(GwCodeRate::Cr48) => Some(8),
+            Ok(GwCodeRate::CrUndefined) if lora.code_rate_legacy.is_empty() => Some(5),
+            Ok(GwCodeRate::CrUndefined) => parse_code_rate(&lora.code_rate_legacy),
+            _ => None,
         }
-        f[0]
     }
 
-    fn round_fq_f64(fq: u32) -> f64 {
-        let f: [f64; 8] = [868_100_000.0, 868_300_000.0, 868_500_000.0, 867_100_000.0, 867_300_000.0, 867_500_000.0, 867_700_000.0, 867_900_000.0];
-        for ff in f {
-            if (fq as f64 - ff).abs() < 1000.0 {
-                return ff;
+    /// The transmission asked for by a downlink item, starting when its timing says, or why the gateway cannot send it.
+    fn plan_downlink(item: &DownlinkFrameItem, position: Position, now: u128) -> Result<Transmission, TxAckStatus> {
+        let info = item.tx_info.as_ref().ok_or(TxAckStatus::InternalError)?;
+        //the simulated radio only speaks LoRa, FSK and LR-FHSS items are refused like an unsupported modulation
+        let lora = match info.modulation.as_ref().and_then(|m| m.parameters.as_ref()) {
+            Some(Parameters::Lora(l)) => l,
+            _ => return Err(TxAckStatus::InternalError),
+        };
+        if !matches!(lora.bandwidth, 125_000 | 250_000 | 500_000) || !(7..=12).contains(&lora.spreading_factor) {
+            return Err(TxAckStatus::InternalError);
+        }
+        let code_rate = Self::code_rate(lora).ok_or(TxAckStatus::InternalError)?;
+
+        let start_time = match info.timing.as_ref().and_then(|t| t.parameters.as_ref()) {
+            Some(timing::Parameters::Immediately(_)) => None,
+            Some(timing::Parameters::Delay(d)) => {
+                let context: [u8; 4] = info.context.as_slice().try_into().map_err(|_| TxAckStatus::InternalError)?;
+                let delay_us = d.delay.as_ref().map_or(0, Self::duration_ms) * 1000;
+                Some(counter_to_unix_ms(u32::from_be_bytes(context).wrapping_add(delay_us as u32), now))
+            }
+            Some(timing::Parameters::GpsEpoch(g)) => {
+                let gps_ms = g.time_since_gps_epoch.as_ref().map_or(0, Self::duration_ms);
+                Some(gps_epoch_to_unix_ms(gps_ms.max(0) as u128))
             }
+            None => return Err(TxAckStatus::InternalError),
+        };
+
+        DownlinkRequest {
+            modulation: LoRaModulation { bandwidth: lora.bandwidth, spreading_factor: lora.spreading_factor.min(u8::MAX as u32) as u8, code_rate },
+            frequency: info.frequency,
+            power: info.power,
+            start_time,
+            payload: item.phy_payload.clone(),
+        }
+        .plan(position, now)
+    }
+
+    fn modulation(m: &LoRaModulation) -> Modulation {
+        let code_rate = match m.code_rate {
+            6 => GwCodeRate::Cr46,
+            7 => GwCodeRate::Cr47,
+            8 => GwCodeRate::Cr48,
+            _ => GwCodeRate::Cr45,
+        };
+        Modulation {
+            parameters: Some(Parameters::Lora(LoraModulationInfo {
+                bandwidth: m.bandwidth,
+                spreading_factor: m.spreading_factor as u32,
+                code_rate_legacy: String::new(),
+                code_rate: code_rate as i32,
+                polarization_inversion: false,
+            })),
+        }
+    }
+
+    fn create_stats(gwid: &str, location: Option<Location>, c: GatewayCounters) -> GatewayStats {
+        let per_modulation = |counts: HashMap<LoRaModulation, u32>| counts.iter().map(|(m, count)| PerModulationCount { modulation: Some(Self::modulation(m)), count: *count }).collect();
+        GatewayStats {
+            gateway_id_legacy: Vec::new(),
+            gateway_id: gwid.to_string(),
+            time: Some(prost_types::Timestamp::from(std::time::SystemTime::now())),
+            location,
+            config_version: String::new(),
+            rx_packets_received: c.rx_received,
+            rx_packets_received_ok: c.rx_received_ok,
+            tx_packets_received: c.tx_received,
+            tx_packets_emitted: c.tx_emitted,
+            metadata: HashMap::new(),
+            tx_packets_per_frequency: c.tx_per_frequency,
+            rx_packets_per_frequency: c.rx_per_frequency,
+            tx_packets_per_modulation: per_modulation(c.tx_per_modulation),
+            rx_packets_per_modulation: per_modulation(c.rx_per_modulation),
+            tx_packets_per_status: c.tx_per_status.iter().map(|(status, count)| (tx_ack_name(*status).to_string(), *count)).collect(),
         }
-        f[0]
     }
 
-    fn create_uplink(gwid: &str, t: &ReceivedTransmission) -> UplinkFrame {
-        UplinkFrame {
+    fn conn_state(gwid: &str, state: State) -> Vec<u8> {
+        ConnState { gateway_id_legacy: Vec::new(), gateway_id: gwid.to_string(), state: state as i32 }.encode_to_vec()
+    }
+
+    /// None when the uplink is outside of the channel plan, the concentrator does not hear it.
+    fn create_uplink(gwid: &str, location: Option<Location>, t: &ReceivedTransmission) -> Option<UplinkFrame> {
+        let modulation = LoRaModulation::of(&t.transmission);
+        let (channel, frequency, rf_chain) = concentrator_channel(t.transmission.frequency, modulation.bandwidth)?;
+        //timestamps of the end of the uplink, RX delays count from there
+        let end_time = t.transmission.start_time + t.transmission.time_on_air();
+        let gps_ms = unix_to_gps_epoch_ms(end_time);
+        Some(UplinkFrame {
             phy_payload: t.transmission.payload.clone(),
             tx_info_legacy: None,
             rx_info_legacy: None,
             tx_info: Some(UplinkTxInfo {
-                frequency: Self::round_fq_u32(t.transmission.frequency),
-                modulation: Some(Modulation {
-                    parameters: Some(Parameters::Lora(LoraModulationInfo {
-                        bandwidth: t.transmission.bandwidth.hz() as u32,
-                        spreading_factor: t.transmission.spreading_factor.value() as u32,
-                        code_rate_legacy: String::new(),
-                        code_rate: 1, //CR 4/5
-                        polarization_inversion: false,
-                    })),
-                }),
+                frequency,
+                modulation: Some(Self::modulation(&modulation)),
             }),
             rx_info: Some(UplinkRxInfo {
                 gateway_id: gwid.to_string(),
                 uplink_id: rand::random(),
-                time: None,
-                time_since_gps_epoch: None,
+                time: Some(prost_types::Timestamp { seconds: (end_time / 1000) as i64, nanos: (end_time % 1000) as i32 * 1_000_000 }),
+                time_since_gps_epoch: Some(prost_types::Duration { seconds: (gps_ms / 1000) as i64, nanos: (gps_ms % 1000) as i32 * 1_000_000 }),
                 fine_time_since_gps_epoch: None,
                 rssi: t.arrival_stats.rssi as i32,
                 snr: t.arrival_stats.snr,
-                channel: 1,
-                rf_chain: 1,
-                board: 1,
-                antenna: 1,
-                context: vec![1,2,3,4],
+                channel,
+                rf_chain,
+                board: 0,
+                antenna: 0,
+                context: concentrator_counter(end_time).to_be_bytes().to_vec(),
                 metadata: HashMap::new(),
-                crc_status: 0,
-                location: None,
+                crc_status: CrcStatus::CrcOk as i32, //the world only delivers frames that survived collisions
+                location,
             }),
-        }
+        })
     }
 
-    pub async fn start(mut self) {
-        let mut client = AsyncClient::new("tcp://169.254.189.196:1883").unwrap();
-        let down_topic = format!("eu868/gateway/{}/command/down", self.gwid);
-        let up_topic = format!("eu868/gateway/{}/event/up", self.gwid);
+    /// `client` must be connected after every gateway of its broker is started, with their topics subscribed.
+    pub fn start(mut self, client: &SharedMqttClient) -> impl std::future::Future<Output = ()> {
+        let up_topic = self.mqtt.up_topic(&self.gwid);
+        let ack_topic = self.mqtt.ack_topic(&self.gwid);
+        let stats_topic = self.mqtt.stats_topic(&self.gwid);
+        let qos = self.mqtt.qos;
+        let mut downlinks = client.subscribe(self.mqtt.down_topic(&self.gwid), qos);
+        client.register_conn_state(self.mqtt.conn_topic(&self.gwid), Self::conn_state(&self.gwid, State::Online), Self::conn_state(&self.gwid, State::Offline), qos);
+        let client = client.clone();
+
+        async move {
+            println!("ChirpstackBridge {} started", self.id);
+
+            let id = self.id;
+            let gwid = self.gwid.clone();
+            let state = Arc::new(Mutex::new(GatewayState::default()));
+
+            let stats_client = client.clone();
+            let stats_state = state.clone();
+            let stats_gwid = gwid.clone();
+            let stats_location = self.location.clone();
+            let stats = tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(GATEWAY_STATS_INTERVAL));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let counters = stats_state.lock().unwrap().take_counters();
+                    if !stats_client.publish(&stats_topic, Self::create_stats(&stats_gwid, stats_location.clone(), counters).encode_to_vec(), qos) {
+                        eprintln!("[NC{id}] MQTT broker unreachable, gateway stats dropped");
+                    }
+                }
+            });
+
+            let uplink_client = client.clone();
+            let uplink_state = state.clone();
+            let t1 = tokio::spawn(async move {
+                while let Some(received_transmission) = self.receiver.recv().await {
+                    println!("[NC{}] Received uplink transmission with rssi {}", self.id, received_transmission.arrival_stats.rssi);
+                    let Some(content) = Self::create_uplink(&self.gwid, self.location.clone(), &received_transmission) else {
+                        eprintln!("[NC{}] Uplink on {} Hz outside of the channel plan ignored", self.id, received_transmission.transmission.frequency);
+                        continue;
+                    };
+                    uplink_state.lock().unwrap().uplink_received(&received_transmission.transmission, true);
+                    if !uplink_client.publish(&up_topic, content.encode_to_vec(), qos) {
+                        eprintln!("[NC{}] MQTT broker unreachable, uplink dropped", self.id);
+                    }
+                }
+                eprintln!("ChirpstackBridge {} stopped forwarding uplinks, world channel closed", self.id);
+            });
 
-        let receiver = client.get_stream(1024);
+            let sender = self.sender;
+            let position = self.node_config.position;
+            let t2 = tokio::spawn(async move {
+                while let Some(msg) = downlinks.recv().await {
+                    let dwn = match DownlinkFrame::decode(msg.payload()) {
+                        Ok(dwn) => dwn,
+                        Err(e) => {
+                            eprintln!("[NC{id}] Malformed downlink for gateway {gwid}: {e}");
+                            continue;
+                        }
+                    };
+                    let (statuses, planned) = Self::schedule_downlink(&dwn, position, World::now(), &mut state.lock().unwrap());
+                    let ack = DownlinkTxAck {
+                        gateway_id_legacy: Vec::new(),
+                        gateway_id: gwid.clone(),
+                        downlink_id: dwn.downlink_id,
+                        downlink_id_legacy: Vec::new(),
+                        items: statuses.iter().map(|s| DownlinkTxAckItem { status: *s as i32 }).collect(),
+                    };
+                    if !client.publish(&ack_topic, ack.encode_to_vec(), qos) {
+                        eprintln!("[NC{id}] MQTT broker unreachable, ack of downlink {} dropped", dwn.downlink_id);
+                    }
 
-        client.connect(None).await.unwrap();
-        client.subscribe(&down_topic, paho_mqtt::QOS_2).await.unwrap();
+                    let Some(transmission) = planned else {
+                        eprintln!("[NC{id}] Downlink {} not sent: {statuses:?}", dwn.downlink_id);
+                        continue;
+                    };
+                    let sender = sender.clone();
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let wait = transmission.start_time.saturating_sub(World::now());
+                        tokio::time::sleep(Duration::from_millis(wait as u64)).await;
+                        let transmission = Transmission { start_time: World::now(), ..transmission };
+                        state.lock().unwrap().downlink_emitted(&transmission);
+                        if let Err(e) = sender.send(transmission).await {
+                            eprintln!("Error sending message to world: {:?}", e);
+                        }
+                        println!("Sent downlink transmission to world");
+                    });
+                }
+                eprintln!("ChirpstackBridge {id} stopped forwarding downlinks");
+            });
 
-        println!("ChirpstackBridge {} started", self.id);
-        
-        let t1 = tokio::spawn(async move {
-            while let Some(received_transmission) = self.receiver.recv().await {
-                //let received_transmission = self.receiver.recv().await.ok_or(CommunicatorError::Radio("Receiver channel closed unexpectedly".to_string())).unwrap();
-                println!("[NC{}] Received uplink transmission with rssi {}", self.id, received_transmission.arrival_stats.rssi);
-                let content = Self::create_uplink(self.gwid, &received_transmission);
-                let v = content.encode_to_vec();
-                
-                client
-                    .publish(paho_mqtt::Message::new(&up_topic, v, paho_mqtt::QOS_2));
+            let (_r1, _r2) = tokio::join!(t1, t2);
+            stats.abort();
+        }
+    }
+
+    /// The ack status of every item of a downlink, and the transmission of the first one the gateway can send, usually RX1 with RX2 as fallback.
+    fn schedule_downlink(dwn: &DownlinkFrame, position: Position, now: u128, state: &mut GatewayState) -> (Vec<TxAckStatus>, Option<Transmission>) {
+        state.downlink_requested();
+        let mut statuses = Vec::with_capacity(dwn.items.len());
+        let mut planned = None;
+        for item in dwn.items.iter() {
+            if planned.is_some() {
+                statuses.push(TxAckStatus::Ignored);
+                continue;
             }
-            panic!("ChirpstackBridge t1 {} stopped", self.id); 
-        });
-        
-
-        let t2 = tokio::spawn(async move {
-            //let mut buffer = [0u8; 1024];
-            while let Ok(Some(msg)) = receiver.recv().await {
-                let dwn = DownlinkFrame::decode(msg.payload()).unwrap();
-                let t = &dwn.items[0];
-                
-                //let mut transmission = serde_json::from_slice::<Transmission>(transmission_bytes).map_err(|e| CommunicatorError::Radio(e.to_string())).unwrap();
-                
-                //println!("[NC{}] Received downlink transmission", self.id);
-
-                let info = t.tx_info.as_ref().unwrap();
-                let lora_modulation = match info.modulation.clone().unwrap().parameters.unwrap() {
-                    Parameters::Lora(l) => l,
-                    Parameters::Fsk(_) => todo!(),
-                    Parameters::LrFhss(_) => todo!(),
-                };
-
-                let l = LoRaBandwidth::from(lora_modulation.bandwidth as f32);
-                let transmission = Transmission {
-                    start_position: self.node_config.position,
-                    start_time: World::now(),
-                    frequency: Self::round_fq_f64(info.frequency),
-                    bandwidth: l,
-                    spreading_factor: SpreadingFactor::new(lora_modulation.spreading_factor as u8),
-                    code_rate: CodeRate::default(),
-                    starting_power: self.node_config.transmission_power_dbm,
-                    uplink: false,
-                    payload: t.phy_payload.clone(),
-                };
-
-                //transmission.start_position = self.node_config.position;
-                //transmission.start_time = World::now();
-                //transmission.starting_power = self.node_config.transmission_power_dbm;
-    
-                if let Err(e) = self.sender.send(transmission).await {
-                    eprintln!("Error sending message to world: {:?}", e);
+            match Self::plan_downlink(item, position, now).and_then(|t| state.schedule(&t, now).map(|_| t)) {
+                Ok(t) => {
+                    planned = Some(t);
+                    statuses.push(TxAckStatus::Ok);
                 }
+                Err(status) => statuses.push(status),
+            }
+        }
+        for status in statuses.iter().filter(|s| **s != TxAckStatus::Ignored) {
+            state.downlink_acked(*status);
+        }
+        (statuses, planned)
+    }
+}
+
+#[test]
+fn downlink_timing_and_fallback() {
+    use crate::compiled::gw::{DelayTimingInfo, DownlinkTxInfo, Timing};
+    use lorawan::physical_parameters::CodeRate;
+
+    let item = |delay_s: i64, frequency: u32, power: i32, context: u32| DownlinkFrameItem {
+        phy_payload: vec![0x60; 20],
+        tx_info_legacy: None,
+        tx_info: Some(DownlinkTxInfo {
+            frequency,
+            power,
+            modulation: Some(Modulation {
+                parameters: Some(Parameters::Lora(LoraModulationInfo { bandwidth: 125_000, spreading_factor: 9, code_rate_legacy: String::new(), code_rate: GwCodeRate::Cr47 as i32, polarization_inversion: true })),
+            }),
+            board: 0,
+            antenna: 0,
+            timing: Some(Timing { parameters: Some(timing::Parameters::Delay(DelayTimingInfo { delay: Some(prost_types::Duration { seconds: delay_s, nanos: 0 }) })) }),
+            context: context.to_be_bytes().to_vec(),
+        }),
+    };
+    let position = Position { x: 0.0, y: 0.0, z: 0.0 };
+    let now = 1_700_000_000_000u128;
+    let uplink_end = now - 1_500;
+    let context = concentrator_counter(uplink_end);
+
+    //RX1 a second after the uplink is already gone, RX2 two seconds after is still ahead
+    assert_eq!(ChirpstackBridge::plan_downlink(&item(1, 868_100_000, 14, context), position, now).err(), Some(TxAckStatus::TooLate));
+    let rx2 = ChirpstackBridge::plan_downlink(&item(2, 869_525_000, 27, context), position, now).unwrap();
+    assert_eq!(rx2.start_time, uplink_end + 2_000);
+    assert_eq!(rx2.frequency, 869_525_000.0);
+    assert_eq!(rx2.starting_power, 27.0);
+    assert_eq!(rx2.code_rate, CodeRate::CR4_7);
 
-                println!("Sent downlink transmission to world");
-            }       
-            panic!("ChirpstackBridge t2 {} stopped", self.id); 
-        });
+    assert_eq!(ChirpstackBridge::plan_downlink(&item(2, 869_525_000, 30, context), position, now).err(), Some(TxAckStatus::TxPower));
+    assert_eq!(ChirpstackBridge::plan_downlink(&item(2, 915_000_000, 14, context), position, now).err(), Some(TxAckStatus::TxFreq));
+    assert_eq!(ChirpstackBridge::plan_downlink(&item(120, 869_525_000, 14, context), position, now).err(), Some(TxAckStatus::TooEarly));
 
-        let (_r1,_r2) = tokio::join!(t1, t2);
-        panic!("ChirpstackBridge {} stopped", self.id);
+    //RX1 is late, RX2 goes out and is acked, a second frame in the same slot collides
+    let frame = DownlinkFrame { downlink_id: 7, items: vec![item(1, 868_100_000, 14, context), item(2, 869_525_000, 27, context)], ..Default::default() };
+    let mut state = GatewayState::default();
+    let (statuses, planned) = ChirpstackBridge::schedule_downlink(&frame, position, now, &mut state);
+    assert_eq!(statuses, vec![TxAckStatus::TooLate, TxAckStatus::Ok]);
+    assert_eq!(planned.unwrap().start_time, uplink_end + 2_000);
+    let frame = DownlinkFrame { items: vec![item(2, 869_525_000, 14, context)], ..frame };
+    assert_eq!(ChirpstackBridge::schedule_downlink(&frame, position, now, &mut state).0, vec![TxAckStatus::CollisionPacket]);
+    let counters = state.take_counters();
+    assert_eq!(counters.tx_received, 2);
+    assert_eq!(counters.tx_per_status, HashMap::from([(TxAckStatus::TooLate, 1), (TxAckStatus::Ok, 1), (TxAckStatus::CollisionPacket, 1)]));
+}
+
+#[test]
+fn uplink_metadata() {
+    use lorawan::physical_parameters::{CodeRate, LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::ArrivalStats;
+
+    let uplink = |frequency, bandwidth| ReceivedTransmission {
+        transmission: Transmission {
+            start_position: Position { x: 0.0, y: 0.0, z: 0.0 },
+            start_time: 1_700_000_000_000,
+            frequency,
+            bandwidth,
+            spreading_factor: SpreadingFactor::SF7,
+            code_rate: CodeRate::CR4_6,
+            starting_power: 14.0,
+            uplink: true,
+            payload: vec![0x40; 20],
+        },
+        arrival_stats: ArrivalStats { time: 1_700_000_000_100, rssi: -100.0, snr: 5.0 },
+    };
+    let location = GeoPosition { latitude: 45.0, longitude: 7.0, altitude: 100.0 }.offset(Position { x: 1000.0, y: 1000.0, z: 30.0 });
+    assert!((location.latitude - 45.008993).abs() < 1e-5 && (location.longitude - 7.012718).abs() < 1e-5 && location.altitude == 130.0);
+
+    let received = uplink(867_500_000.0, LoRaBandwidth::BW125);
+    let frame = ChirpstackBridge::create_uplink("0016c001f153a14c", None, &received).unwrap();
+    let rx_info = frame.rx_info.unwrap();
+    let end_time = received.transmission.start_time + received.transmission.time_on_air();
+    assert_eq!((rx_info.channel, rx_info.rf_chain), (5, 0));
+    assert_eq!(rx_info.context, concentrator_counter(end_time).to_be_bytes().to_vec());
+    assert_eq!(rx_info.time.unwrap().seconds as u128, end_time / 1000);
+    assert_eq!(rx_info.crc_status, CrcStatus::CrcOk as i32);
+    match frame.tx_info.unwrap().modulation.unwrap().parameters {
+        Some(Parameters::Lora(l)) => assert_eq!(l.code_rate, GwCodeRate::Cr46 as i32),
+        _ => panic!("LoRa modulation expected"),
     }
-}
\ No newline at end of file
+
+    let frame = ChirpstackBridge::create_uplink("0016c001f153a14c", None, &uplink(868_300_000.0, LoRaBandwidth::BW250)).unwrap();
+    assert_eq!(frame.rx_info.unwrap().channel, 8);
+    assert!(ChirpstackBridge::create_uplink("0016c001f153a14c", None, &uplink(869_525_000.0, LoRaBandwidth::BW125)).is_none());
+}
@@ -0,0 +1,75 @@
+//Fleet-wide events hitting many nodes at once, like the power outage of a whole area
+
+use std::{collections::HashSet, time::Duration};
+
+use rand::Rng;
+use serde::Deserialize;
+
+/// What the firmware of a node keeps in non volatile memory across a reboot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FirmwareProfile {
+    pub keeps_session: bool,   //session keys and frame counters, otherwise the node joins again
+    pub keeps_dev_nonce: bool, //firmwares that do not restart from STARTING_DEV_NONCE, as LoRaWAN 1.0.4 requires
+    pub boot_time: Duration,   //from the power coming back to the first frame
+    pub boot_jitter: Duration, //random extra delay, spreading the nodes of a storm
+}
+
+impl Default for FirmwareProfile {
+    fn default() -> Self {
+        FirmwareProfile {
+            keeps_session: false,
+            keeps_dev_nonce: true,
+            boot_time: Duration::from_secs(5),
+            boot_jitter: Duration::from_secs(60),
+        }
+    }
+}
+
+impl FirmwareProfile {
+    pub fn first_frame_delay<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        self.boot_time + self.boot_jitter.mul_f64(rng.gen_range(0.0..1.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FleetEventKind {
+    Reboot, //power loss, the firmware profile decides what survives
+    Rejoin, //every affected node drops its session and joins again, whatever the profile
+}
+
+/// "At `at`, `fraction` of the nodes go through `kind` and stay off for `outage`".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FleetEvent {
+    pub at: Duration, //since the start of the run
+    pub fraction: f64,
+    pub outage: Duration,
+    pub kind: FleetEventKind,
+}
+
+impl FleetEvent {
+    pub fn reboot(at: Duration, fraction: f64, outage: Duration) -> Self {
+        FleetEvent { at, fraction, outage, kind: FleetEventKind::Reboot }
+    }
+
+    pub fn rejoin(at: Duration, fraction: f64) -> Self {
+        FleetEvent { at, fraction, outage: Duration::ZERO, kind: FleetEventKind::Rejoin }
+    }
+
+    /// Indices of the nodes hit by the event, exactly `fraction` of the `n` nodes of the fleet.
+    pub fn affected<R: Rng + ?Sized>(&self, n: usize, rng: &mut R) -> HashSet<usize> {
+        let count = ((self.fraction * n as f64).round() as usize).min(n);
+        rand::seq::index::sample(rng, n, count).into_iter().collect()
+    }
+}
+
+#[test]
+fn affected_share_of_the_fleet() {
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let event = FleetEvent::reboot(Duration::from_secs(60), 0.4, Duration::from_secs(30));
+    let affected = event.affected(1001, &mut rng);
+    assert_eq!(affected.len(), 400);
+    assert!(affected.iter().all(|i| *i < 1001));
+    assert_eq!(FleetEvent::rejoin(Duration::ZERO, 1.0).affected(7, &mut rng).len(), 7);
+    assert!(FleetEvent::rejoin(Duration::ZERO, 0.0).affected(7, &mut rng).is_empty());
+}
@@ -0,0 +1,212 @@
+//Minimal codec for LoRaWAN data frames (PHYPayload with MType 010..101)
+
+use super::crypto::{data_frame_mic, data_frame_mic_1_1, MicBlock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest,
+    JoinAccept,
+    UnconfirmedDataUp,
+    UnconfirmedDataDown,
+    ConfirmedDataUp,
+    ConfirmedDataDown,
+    RejoinRequest,
+    Proprietary,
+}
+
+impl MType {
+    pub fn from_mhdr(mhdr: u8) -> MType {
+        match mhdr >> 5 {
+            0 => MType::JoinRequest,
+            1 => MType::JoinAccept,
+            2 => MType::UnconfirmedDataUp,
+            3 => MType::UnconfirmedDataDown,
+            4 => MType::ConfirmedDataUp,
+            5 => MType::ConfirmedDataDown,
+            6 => MType::RejoinRequest,
+            _ => MType::Proprietary,
+        }
+    }
+
+    pub fn is_data(&self) -> bool {
+        matches!(self, MType::UnconfirmedDataUp | MType::UnconfirmedDataDown | MType::ConfirmedDataUp | MType::ConfirmedDataDown)
+    }
+
+    pub fn is_uplink(&self) -> bool {
+        matches!(self, MType::JoinRequest | MType::UnconfirmedDataUp | MType::ConfirmedDataUp | MType::RejoinRequest)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort(usize),
+    NotADataFrame(MType),
+    NotAJoinAccept(MType),
+    InvalidMic,
+}
+
+pub const FCTRL_ADR: u8 = 0x80;
+pub const FCTRL_ADR_ACK_REQ: u8 = 0x40;
+pub const FCTRL_ACK: u8 = 0x20;
+pub const FCTRL_F_PENDING: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataFrame {
+    pub mhdr: u8,
+    pub dev_addr: [u8; 4], //MSB first, like the session context
+    pub f_ctrl: u8,        //without the FOptsLen nibble
+    pub f_cnt: u16,
+    pub f_opts: Vec<u8>,
+    pub f_port: Option<u8>,
+    pub frm_payload: Vec<u8>,
+    pub mic: [u8; 4],
+}
+
+impl DataFrame {
+    pub fn parse(bytes: &[u8]) -> Result<DataFrame, FrameError> {
+        if bytes.len() < 12 {
+            return Err(FrameError::TooShort(bytes.len()));
+        }
+        let mtype = MType::from_mhdr(bytes[0]);
+        if !mtype.is_data() {
+            return Err(FrameError::NotADataFrame(mtype));
+        }
+
+        let dev_addr = [bytes[4], bytes[3], bytes[2], bytes[1]];
+        let f_ctrl = bytes[5] & 0xf0;
+        let f_opts_len = (bytes[5] & 0x0f) as usize;
+        let f_cnt = u16::from_le_bytes([bytes[6], bytes[7]]);
+
+        let mac_payload_end = bytes.len() - 4;
+        if 8 + f_opts_len > mac_payload_end {
+            return Err(FrameError::TooShort(bytes.len()));
+        }
+        let f_opts = bytes[8..8 + f_opts_len].to_vec();
+        let (f_port, frm_payload) = if 8 + f_opts_len < mac_payload_end {
+            (Some(bytes[8 + f_opts_len]), bytes[9 + f_opts_len..mac_payload_end].to_vec())
+        } else {
+            (None, Vec::new())
+        };
+
+        let mut mic = [0u8; 4];
+        mic.copy_from_slice(&bytes[mac_payload_end..]);
+
+        Ok(DataFrame { mhdr: bytes[0], dev_addr, f_ctrl, f_cnt, f_opts, f_port, frm_payload, mic })
+    }
+
+    pub fn mtype(&self) -> MType {
+        MType::from_mhdr(self.mhdr)
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.f_ctrl & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.f_ctrl |= flag;
+        } else {
+            self.f_ctrl &= !flag;
+        }
+    }
+
+    //MHDR | FHDR | FPort | FRMPayload, the part covered by the MIC
+    fn msg_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(9 + self.f_opts.len() + self.frm_payload.len());
+        v.push(self.mhdr);
+        v.extend(self.dev_addr.iter().rev());
+        v.push(self.f_ctrl | (self.f_opts.len() as u8 & 0x0f));
+        v.extend_from_slice(&self.f_cnt.to_le_bytes());
+        v.extend_from_slice(&self.f_opts);
+        if let Some(port) = self.f_port {
+            v.push(port);
+            v.extend_from_slice(&self.frm_payload);
+        }
+        v
+    }
+
+    /// Rebuilds the 32 bit counter from the 16 bits on air, given the last counter seen.
+    pub fn full_f_cnt(&self, last_f_cnt: u32) -> u32 {
+        let candidate = (last_f_cnt & 0xffff_0000) | self.f_cnt as u32;
+        if candidate < last_f_cnt {
+            candidate.wrapping_add(0x1_0000)
+        } else {
+            candidate
+        }
+    }
+
+    pub fn compute_mic(&self, key: &[u8; 16], full_f_cnt: u32) -> [u8; 4] {
+        data_frame_mic(key, &self.dev_addr, full_f_cnt, self.mtype().is_uplink(), &self.msg_bytes())
+    }
+
+    /// MIC of a LoRaWAN 1.1 frame, `tx` is the (data rate, channel index) an uplink is sent with.
+    pub fn compute_mic_1_1(&self, f_nwk_s_int_key: &[u8; 16], s_nwk_s_int_key: &[u8; 16], full_f_cnt: u32, conf_f_cnt: u16, tx: (u8, u8)) -> [u8; 4] {
+        let block = MicBlock {
+            dev_addr: &self.dev_addr,
+            f_cnt: full_f_cnt,
+            uplink: self.mtype().is_uplink(),
+            conf_f_cnt,
+            tx_dr: tx.0,
+            tx_ch: tx.1,
+        };
+        data_frame_mic_1_1(f_nwk_s_int_key, s_nwk_s_int_key, &block, &self.msg_bytes())
+    }
+
+    pub fn verify_mic(&self, key: &[u8; 16], full_f_cnt: u32) -> Result<(), FrameError> {
+        if self.compute_mic(key, full_f_cnt) == self.mic {
+            Ok(())
+        } else {
+            Err(FrameError::InvalidMic)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = self.msg_bytes();
+        v.extend_from_slice(&self.mic);
+        v
+    }
+
+    /// Serializes the frame with a freshly computed MIC, to be used after patching any field.
+    pub fn to_bytes_with_mic(&mut self, key: &[u8; 16], full_f_cnt: u32) -> Vec<u8> {
+        self.mic = self.compute_mic(key, full_f_cnt);
+        self.to_bytes()
+    }
+}
+
+#[test]
+fn data_frame_roundtrip() {
+    let key = [0x2b; 16];
+    let mut frame = DataFrame {
+        mhdr: 0x80,
+        dev_addr: [0x26, 0x01, 0x1b, 0xda],
+        f_ctrl: FCTRL_ADR,
+        f_cnt: 42,
+        f_opts: vec![0x02],
+        f_port: Some(1),
+        frm_payload: vec![1, 2, 3, 4],
+        mic: [0; 4],
+    };
+    let bytes = frame.to_bytes_with_mic(&key, 42);
+    assert_eq!(&bytes[1..5], &[0xda, 0x1b, 0x01, 0x26]);
+
+    let parsed = DataFrame::parse(&bytes).unwrap();
+    assert_eq!(parsed, frame);
+    assert_eq!(parsed.mtype(), MType::ConfirmedDataUp);
+    assert!(parsed.verify_mic(&key, 42).is_ok());
+    assert!(parsed.verify_mic(&key, 42 + 0x1_0000).is_err());
+    assert_eq!(parsed.full_f_cnt(0x1_fff0), 0x2_002a);
+}
+
+#[test]
+fn known_uplink_vector() {
+    use super::crypto::crypt_frm_payload;
+
+    let bytes = [0x40, 0xf1, 0x7d, 0xbe, 0x49, 0x00, 0x02, 0x00, 0x01, 0x95, 0x43, 0x78, 0x76, 0x2b, 0x11, 0xff, 0x0d];
+    let nwk_s_key = [0x44, 0x02, 0x42, 0x41, 0xed, 0x4c, 0xe9, 0xa6, 0x8c, 0x6a, 0x8b, 0xc0, 0x55, 0x23, 0x3f, 0xd3];
+    let app_s_key = [0xec, 0x92, 0x58, 0x02, 0xae, 0x43, 0x0c, 0xa7, 0x7f, 0xd3, 0xdd, 0x73, 0xcb, 0x2c, 0xc5, 0x88];
+
+    let frame = DataFrame::parse(&bytes).unwrap();
+    assert_eq!(frame.dev_addr, [0x49, 0xbe, 0x7d, 0xf1]);
+    assert!(frame.verify_mic(&nwk_s_key, 2).is_ok());
+    assert_eq!(crypt_frm_payload(&app_s_key, &frame.dev_addr, 2, true, &frame.frm_payload), b"test");
+}
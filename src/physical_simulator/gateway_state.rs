@@ -0,0 +1,246 @@
+//What a simulated gateway remembers between packets: the downlinks it has scheduled, the airtime it spent and its traffic counters
+
+use std::collections::HashMap;
+
+use lorawan::physical_parameters::{CodeRate, LoRaBandwidth, SpreadingFactor};
+use lorawan_device::communicator::{Position, Transmission};
+
+use crate::compiled::gw::TxAckStatus;
+use crate::constants::{DOWNLINK_MAX_LEAD_MS, DOWNLINK_MIN_LEAD_MS, GATEWAY_MAX_TX_POWER_DBM};
+
+const DUTY_CYCLE_WINDOW_MS: u128 = 3_600_000; //the regulations count the airtime of the last hour
+
+//EU863-870 sub-bands of ETSI EN 300 220 with their duty cycle, outside of them a gateway may not transmit
+const SUB_BANDS: [(u32, u32, f64); 6] = [
+    (863_000_000, 865_000_000, 0.001),
+    (865_000_000, 868_000_000, 0.01),
+    (868_000_000, 868_600_000, 0.01),
+    (868_700_000, 869_200_000, 0.001),
+    (869_400_000, 869_650_000, 0.1),
+    (869_700_000, 870_000_000, 0.01),
+];
+
+//EU868 concentrator of the ChirpStack and Semtech configurations: eight multi-SF channels of 125 kHz, then the 250 kHz single-SF one
+const MULTI_SF_CHANNELS: [u32; 8] = [868_100_000, 868_300_000, 868_500_000, 867_100_000, 867_300_000, 867_500_000, 867_700_000, 867_900_000];
+const LORA_STD_CHANNEL: (u32, u32) = (868_300_000, 250_000);
+const RADIO_1_MIN_FREQUENCY: u32 = 868_000_000; //radio 0 is centred on 867.5 MHz, radio 1 on 868.5 MHz
+
+/// Index of the concentrator channel demodulating an uplink, its exact frequency and its RF chain.
+/// None when the uplink is outside of the channel plan, the concentrator does not hear it.
+pub fn concentrator_channel(frequency: f64, bandwidth: u32) -> Option<(u32, u32, u32)> {
+    let close = |f: u32| (frequency - f as f64).abs() < 1000.0;
+    let (channel, frequency) = if bandwidth == LORA_STD_CHANNEL.1 {
+        close(LORA_STD_CHANNEL.0).then_some((MULTI_SF_CHANNELS.len() as u32, LORA_STD_CHANNEL.0))?
+    } else {
+        MULTI_SF_CHANNELS.iter().position(|f| bandwidth == 125_000 && close(*f)).map(|i| (i as u32, MULTI_SF_CHANNELS[i]))?
+    };
+    Some((channel, frequency, (frequency >= RADIO_1_MIN_FREQUENCY) as u32))
+}
+
+//"4/5" as 5
+pub fn parse_code_rate(code_rate: &str) -> Option<u8> {
+    match code_rate {
+        "4/5" => Some(5),
+        "4/6" => Some(6),
+        "4/7" => Some(7),
+        "4/8" => Some(8),
+        _ => None,
+    }
+}
+
+//names of the statuses in the gateway bridge and packet forwarder protocols
+pub fn tx_ack_name(status: TxAckStatus) -> &'static str {
+    match status {
+        TxAckStatus::Ignored => "IGNORED",
+        TxAckStatus::Ok => "OK",
+        TxAckStatus::TooLate => "TOO_LATE",
+        TxAckStatus::TooEarly => "TOO_EARLY",
+        TxAckStatus::CollisionPacket => "COLLISION_PACKET",
+        TxAckStatus::CollisionBeacon => "COLLISION_BEACON",
+        TxAckStatus::TxFreq => "TX_FREQ",
+        TxAckStatus::TxPower => "TX_POWER",
+        TxAckStatus::GpsUnlocked => "GPS_UNLOCKED",
+        TxAckStatus::QueueFull => "QUEUE_FULL",
+        TxAckStatus::InternalError => "INTERNAL_ERROR",
+        TxAckStatus::DutyCycleOverflow => "DUTY_CYCLE_OVERFLOW",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoRaModulation {
+    pub bandwidth: u32,
+    pub spreading_factor: u8,
+    pub code_rate: u8, //the 5 of 4/5
+}
+
+impl LoRaModulation {
+    pub fn of(t: &Transmission) -> Self {
+        let code_rate = match t.code_rate {
+            CodeRate::CR4_5 => 5,
+            CodeRate::CR4_6 => 6,
+            CodeRate::CR4_7 => 7,
+            CodeRate::CR4_8 => 8,
+        };
+        LoRaModulation { bandwidth: t.bandwidth.hz() as u32, spreading_factor: t.spreading_factor.value(), code_rate }
+    }
+}
+
+/// A downlink as the network server asks for it, whatever the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownlinkRequest {
+    pub modulation: LoRaModulation,
+    pub frequency: u32,
+    pub power: i32,
+    pub start_time: Option<u128>, //none to send it immediately
+    pub payload: Vec<u8>,
+}
+
+impl DownlinkRequest {
+    /// The transmission the concentrator makes of it, or why it refuses it.
+    pub fn plan(self, position: Position, now: u128) -> Result<Transmission, TxAckStatus> {
+        let m = self.modulation;
+        let code_rate = match m.code_rate {
+            5 => CodeRate::CR4_5,
+            6 => CodeRate::CR4_6,
+            7 => CodeRate::CR4_7,
+            8 => CodeRate::CR4_8,
+            _ => return Err(TxAckStatus::InternalError),
+        };
+        if !matches!(m.bandwidth, 125_000 | 250_000 | 500_000) || !(7..=12).contains(&m.spreading_factor) {
+            return Err(TxAckStatus::InternalError);
+        }
+        if !(863_000_000..=870_000_000).contains(&self.frequency) {
+            return Err(TxAckStatus::TxFreq);
+        }
+        if self.power > GATEWAY_MAX_TX_POWER_DBM {
+            return Err(TxAckStatus::TxPower);
+        }
+        let start_time = match self.start_time {
+            None => now,
+            Some(t) if t < now + DOWNLINK_MIN_LEAD_MS => return Err(TxAckStatus::TooLate),
+            Some(t) if t > now + DOWNLINK_MAX_LEAD_MS => return Err(TxAckStatus::TooEarly),
+            Some(t) => t,
+        };
+        Ok(Transmission {
+            start_position: position,
+            start_time,
+            frequency: self.frequency as f64,
+            bandwidth: LoRaBandwidth::from(m.bandwidth as f32),
+            spreading_factor: SpreadingFactor::new(m.spreading_factor),
+            code_rate,
+            starting_power: self.power as f32,
+            uplink: false,
+            payload: self.payload,
+        })
+    }
+}
+
+/// Traffic of a gateway since the counters were last taken.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GatewayCounters {
+    pub rx_received: u32,
+    pub rx_received_ok: u32,
+    pub tx_received: u32, //downlink requests, whether they were sent or not
+    pub tx_emitted: u32,
+    pub rx_per_frequency: HashMap<u32, u32>,
+    pub tx_per_frequency: HashMap<u32, u32>,
+    pub rx_per_modulation: HashMap<LoRaModulation, u32>,
+    pub tx_per_modulation: HashMap<LoRaModulation, u32>,
+    pub tx_per_status: HashMap<TxAckStatus, u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct GatewayState {
+    scheduled: Vec<(u128, u128)>, //start and end of the downlinks not sent yet
+    airtime: Vec<(usize, u128, u128)>, //sub-band, start and time on air of the downlinks of the last hour
+    counters: GatewayCounters,
+}
+
+impl GatewayState {
+    pub fn uplink_received(&mut self, t: &Transmission, crc_ok: bool) {
+        let c = &mut self.counters;
+        c.rx_received += 1;
+        if crc_ok {
+            c.rx_received_ok += 1;
+        }
+        *c.rx_per_frequency.entry(t.frequency as u32).or_default() += 1;
+        *c.rx_per_modulation.entry(LoRaModulation::of(t)).or_default() += 1;
+    }
+
+    pub fn downlink_requested(&mut self) {
+        self.counters.tx_received += 1;
+    }
+
+    pub fn downlink_acked(&mut self, status: TxAckStatus) {
+        *self.counters.tx_per_status.entry(status).or_default() += 1;
+    }
+
+    /// Books the air for a downlink. It is refused when it overlaps another downlink, or when its sub-band has used up its duty cycle.
+    pub fn schedule(&mut self, t: &Transmission, now: u128) -> Result<(), TxAckStatus> {
+        self.scheduled.retain(|(_, end)| *end > now);
+        self.airtime.retain(|(_, start, _)| start + DUTY_CYCLE_WINDOW_MS > t.start_time);
+
+        let frequency = t.frequency as u32;
+        let sub_band = SUB_BANDS.iter().position(|(low, high, _)| (*low..*high).contains(&frequency)).ok_or(TxAckStatus::TxFreq)?;
+        let time_on_air = t.time_on_air();
+        let end = t.start_time + time_on_air;
+        if self.scheduled.iter().any(|(s, e)| t.start_time < *e && *s < end) {
+            return Err(TxAckStatus::CollisionPacket);
+        }
+        let used = self.airtime.iter().filter(|(b, _, _)| *b == sub_band).map(|(_, _, toa)| toa).sum::<u128>();
+        if (used + time_on_air) as f64 > DUTY_CYCLE_WINDOW_MS as f64 * SUB_BANDS[sub_band].2 {
+            return Err(TxAckStatus::DutyCycleOverflow);
+        }
+
+        self.scheduled.push((t.start_time, end));
+        self.airtime.push((sub_band, t.start_time, time_on_air));
+        Ok(())
+    }
+
+    pub fn downlink_emitted(&mut self, t: &Transmission) {
+        let c = &mut self.counters;
+        c.tx_emitted += 1;
+        *c.tx_per_frequency.entry(t.frequency as u32).or_default() += 1;
+        *c.tx_per_modulation.entry(LoRaModulation::of(t)).or_default() += 1;
+    }
+
+    pub fn take_counters(&mut self) -> GatewayCounters {
+        std::mem::take(&mut self.counters)
+    }
+}
+
+#[test]
+fn downlink_duty_cycle() {
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::Position;
+
+    let now = 1_700_000_000_000u128;
+    let downlink = |start_time, frequency| Transmission {
+        start_position: Position { x: 0.0, y: 0.0, z: 0.0 },
+        start_time,
+        frequency,
+        bandwidth: LoRaBandwidth::BW125,
+        spreading_factor: SpreadingFactor::SF12,
+        code_rate: CodeRate::CR4_5,
+        starting_power: 14.0,
+        uplink: false,
+        payload: vec![0; 33],
+    };
+
+    //back to back downlinks on 868.1 MHz until the 1% of the hour is spent
+    let mut state = GatewayState::default();
+    let mut start = now;
+    let status = loop {
+        let t = downlink(start, 868_100_000.0);
+        if let Err(status) = state.schedule(&t, now) {
+            break status;
+        }
+        start += t.time_on_air();
+    };
+    assert_eq!(status, TxAckStatus::DutyCycleOverflow);
+    assert!(start - now <= 36_000);
+    //the 10% sub-band of RX2 is still free, the gap between the sub-bands is not allowed
+    assert_eq!(state.schedule(&downlink(start, 869_525_000.0), now), Ok(()));
+    assert_eq!(state.schedule(&downlink(start + 60_000, 868_650_000.0), now), Err(TxAckStatus::TxFreq));
+    assert_eq!(state.schedule(&downlink(now, 869_525_000.0), now), Err(TxAckStatus::CollisionPacket));
+}
@@ -0,0 +1,285 @@
+//OTAA join procedure of LoRaWAN 1.0.4 and 1.1 as run by the multi-node engine: JoinRequest/JoinAccept frames,
+//session key derivation, join backoff and the DevNonce store surviving across runs
+
+use std::{collections::HashMap, fs, time::Duration};
+
+use lorawan::utils::eui::EUI64;
+use rand::Rng;
+
+use super::{
+    crypto::{aes128_cmac, aes128_decrypt, aes128_encrypt},
+    frame::{FrameError, MType},
+    mac::{data_rate_to_radio, DEFAULT_CHANNELS, DEFAULT_RX2, RETRANSMIT_TIMEOUT_MS},
+    node::{NodeConfig, RadioParams, TxParams},
+};
+
+//JOIN_ACCEPT_DELAY2 plus the time needed to receive the JoinAccept in RX2
+pub const JOIN_RX_TIMEOUT: Duration = Duration::from_secs(7);
+
+fn mic(key: &[u8; 16], msg: &[u8]) -> [u8; 4] {
+    let cmac = aes128_cmac(key, msg);
+    [cmac[0], cmac[1], cmac[2], cmac[3]]
+}
+
+fn reversed<const N: usize>(mut v: [u8; N]) -> [u8; N] {
+    v.reverse();
+    v
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    pub join_eui: EUI64,
+    pub dev_eui: EUI64,
+    pub dev_nonce: u16,
+}
+
+impl JoinRequest {
+    /// MHDR | JoinEUI | DevEUI | DevNonce | MIC, signed with the root key (NwkKey, AppKey for 1.0 devices).
+    pub fn to_bytes(&self, key: &[u8; 16]) -> Vec<u8> {
+        let mut v = Vec::with_capacity(23);
+        v.push(0x00);
+        v.extend_from_slice(&reversed(*self.join_eui));
+        v.extend_from_slice(&reversed(*self.dev_eui));
+        v.extend_from_slice(&self.dev_nonce.to_le_bytes());
+        let mic = mic(key, &v);
+        v.extend_from_slice(&mic);
+        v
+    }
+}
+
+//root keys are derived with the EUI as in LoRaWAN 1.1 section 6.1.1.3, JSIntKey has prefix 0x06
+fn derive_js_key(nwk_key: &[u8; 16], prefix: u8, dev_eui: &EUI64) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0] = prefix;
+    block[1..9].copy_from_slice(&reversed(**dev_eui));
+    aes128_encrypt(nwk_key, &block)
+}
+
+/// Keys of the session opened by a JoinAccept. A 1.0 session has a single NwkSKey,
+/// stored in all three network keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DerivedKeys {
+    pub f_nwk_s_int_key: [u8; 16],
+    pub s_nwk_s_int_key: [u8; 16],
+    pub nwk_s_enc_key: [u8; 16],
+    pub app_s_key: [u8; 16],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinAccept {
+    pub join_nonce: u32,
+    pub net_id: [u8; 3],
+    pub dev_addr: [u8; 4], //MSB first, like the session context
+    pub opt_neg: bool,     //the network speaks LoRaWAN 1.1, only 1.0 devices ignore it
+    pub rx1_dr_offset: u8,
+    pub rx2_data_rate: u8,
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+}
+
+impl JoinAccept {
+    //JoinNonce | NetID | DevAddr | DLSettings | RxDelay | CFList
+    fn plain_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(28);
+        v.extend_from_slice(&self.join_nonce.to_le_bytes()[..3]);
+        v.extend_from_slice(&reversed(self.net_id));
+        v.extend_from_slice(&reversed(self.dev_addr));
+        v.push((self.opt_neg as u8) << 7 | (self.rx1_dr_offset & 0x07) << 4 | (self.rx2_data_rate & 0x0f));
+        v.push(self.rx_delay);
+        if let Some(cf_list) = self.cf_list {
+            v.extend_from_slice(&cf_list);
+        }
+        v
+    }
+
+    //MIC over MHDR | JoinAccept: with the root key, or with JSIntKey for 1.1 where it also covers the JoinRequest
+    fn mic(key: &[u8; 16], request: &JoinRequest, opt_neg: bool, msg: &[u8]) -> [u8; 4] {
+        if !opt_neg {
+            return mic(key, msg);
+        }
+        let mut v = vec![0xff]; //JoinReqType of a JoinRequest
+        v.extend_from_slice(&reversed(*request.join_eui));
+        v.extend_from_slice(&request.dev_nonce.to_le_bytes());
+        v.extend_from_slice(msg);
+        mic(&derive_js_key(key, 0x06, &request.dev_eui), &v)
+    }
+
+    /// Builds the frame as the network sends it in answer to `request`, encrypted with an AES decryption.
+    pub fn to_bytes(&self, key: &[u8; 16], request: &JoinRequest) -> Vec<u8> {
+        let mut plain = self.plain_bytes();
+        let mic = Self::mic(key, request, self.opt_neg, &[&[0x20], &plain[..]].concat());
+        plain.extend_from_slice(&mic);
+
+        let mut v = vec![0x20];
+        for chunk in plain.chunks(16) {
+            v.extend_from_slice(&aes128_decrypt(key, chunk.try_into().unwrap()));
+        }
+        v
+    }
+
+    /// Reads the answer to `request` as a device of the given version does, with its NwkKey (AppKey for 1.0).
+    pub fn decrypt(bytes: &[u8], key: &[u8; 16], request: &JoinRequest, lorawan_1_1: bool) -> Result<JoinAccept, FrameError> {
+        let Some(&mhdr) = bytes.first() else {
+            return Err(FrameError::TooShort(0));
+        };
+        let mtype = MType::from_mhdr(mhdr);
+        if mtype != MType::JoinAccept {
+            return Err(FrameError::NotAJoinAccept(mtype));
+        }
+        if bytes.len() != 17 && bytes.len() != 33 {
+            return Err(FrameError::TooShort(bytes.len()));
+        }
+
+        let mut plain = Vec::with_capacity(bytes.len() - 1);
+        for chunk in bytes[1..].chunks(16) {
+            plain.extend_from_slice(&aes128_encrypt(key, chunk.try_into().unwrap()));
+        }
+        let (msg, received_mic) = plain.split_at(plain.len() - 4);
+        let opt_neg = lorawan_1_1 && msg[10] & 0x80 != 0;
+        if Self::mic(key, request, opt_neg, &[&[bytes[0]], msg].concat()) != received_mic {
+            return Err(FrameError::InvalidMic);
+        }
+
+        Ok(JoinAccept {
+            join_nonce: u32::from_le_bytes([msg[0], msg[1], msg[2], 0]),
+            net_id: [msg[5], msg[4], msg[3]],
+            dev_addr: [msg[9], msg[8], msg[7], msg[6]],
+            opt_neg,
+            rx1_dr_offset: (msg[10] >> 4) & 0x07,
+            rx2_data_rate: msg[10] & 0x0f,
+            rx_delay: msg[11],
+            cf_list: msg.get(12..28).map(|c| c.try_into().unwrap()),
+        })
+    }
+
+    /// Session keys of LoRaWAN 1.1 section 6.2.2 when OptNeg is set, otherwise the NwkSKey and AppSKey
+    /// of LoRaWAN 1.0.4 section 6.2.5, both derived from `nwk_key`.
+    pub fn session_keys(&self, nwk_key: &[u8; 16], app_key: &[u8; 16], request: &JoinRequest) -> DerivedKeys {
+        let derive = |key: &[u8; 16], prefix: u8| {
+            let mut block = [0u8; 16];
+            block[0] = prefix;
+            block[1..4].copy_from_slice(&self.join_nonce.to_le_bytes()[..3]);
+            if self.opt_neg {
+                block[4..12].copy_from_slice(&reversed(*request.join_eui));
+                block[12..14].copy_from_slice(&request.dev_nonce.to_le_bytes());
+            } else {
+                block[4..7].copy_from_slice(&reversed(self.net_id));
+                block[7..9].copy_from_slice(&request.dev_nonce.to_le_bytes());
+            }
+            aes128_encrypt(key, &block)
+        };
+        if self.opt_neg {
+            DerivedKeys {
+                f_nwk_s_int_key: derive(nwk_key, 0x01),
+                s_nwk_s_int_key: derive(nwk_key, 0x03),
+                nwk_s_enc_key: derive(nwk_key, 0x04),
+                app_s_key: derive(app_key, 0x02),
+            }
+        } else {
+            let nwk_s_key = derive(nwk_key, 0x01);
+            DerivedKeys { f_nwk_s_int_key: nwk_s_key, s_nwk_s_int_key: nwk_s_key, nwk_s_enc_key: nwk_s_key, app_s_key: derive(nwk_key, 0x02) }
+        }
+    }
+
+    /// Frequencies of channels 3 to 7 carried by a CFList of type 0.
+    pub fn cf_list_frequencies(&self) -> Vec<f64> {
+        match self.cf_list {
+            Some(cf_list) if cf_list[15] == 0 => cf_list[..15]
+                .chunks(3)
+                .map(|f| u32::from_le_bytes([f[0], f[1], f[2], 0]) as f64 * 100.0)
+                .filter(|f| *f != 0.0)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// JoinRequests go out on a random default channel with the configured data rate.
+pub fn join_tx_params<R: Rng + ?Sized>(config: &NodeConfig, rng: &mut R) -> TxParams {
+    let frequency = DEFAULT_CHANNELS[rng.gen_range(0..DEFAULT_CHANNELS.len())];
+    TxParams {
+        radio: RadioParams { frequency, ..RadioParams::from_radio_config(&config.radio_config) },
+        power_dbm: config.transmission_power_dbm,
+    }
+}
+
+pub fn join_rx_windows(tx_params: &TxParams) -> Vec<RadioParams> {
+    let (spreading_factor, bandwidth) = data_rate_to_radio(DEFAULT_RX2.1).unwrap();
+    vec![tx_params.radio, RadioParams { frequency: DEFAULT_RX2.0, spreading_factor, bandwidth }]
+}
+
+/// Join backoff of LoRaWAN 1.0.4 section 7: the aggregated time on air of JoinRequests is kept under
+/// 36 s in the first hour, 36 s every 10 hours up to hour 11 and 8.7 s every 24 hours afterwards.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JoinBackoff {
+    first_attempt: Option<u128>,
+    pub attempts: u32,
+}
+
+impl JoinBackoff {
+    fn duty_cycle(elapsed_ms: u128) -> f64 {
+        match elapsed_ms / 3_600_000 {
+            0 => 36.0 / 3600.0,
+            1..=10 => 36.0 / 36000.0,
+            _ => 8.7 / 86400.0,
+        }
+    }
+
+    /// Start time of the next JoinRequest after one of `time_on_air` ms went unanswered.
+    pub fn next_attempt<R: Rng + ?Sized>(&mut self, now: u128, time_on_air: u128, rng: &mut R) -> u128 {
+        let first_attempt = *self.first_attempt.get_or_insert(now);
+        self.attempts += 1;
+        let off_time = (time_on_air as f64 / Self::duty_cycle(now - first_attempt)) as u128 - time_on_air;
+        now + off_time.max(rng.gen_range(RETRANSMIT_TIMEOUT_MS.0..=RETRANSMIT_TIMEOUT_MS.1) as u128)
+    }
+}
+
+/// Last DevNonce used by each device, read from the `dev_eui,dev_nonce` lines appended at every JoinRequest.
+pub fn load_dev_nonces(path: &str) -> HashMap<String, u32> {
+    let mut dev_nonces = HashMap::new();
+    for line in fs::read_to_string(path).unwrap_or_default().lines() {
+        if let Some((dev_eui, dev_nonce)) = line.split_once(',') {
+            if let Ok(dev_nonce) = dev_nonce.trim().parse::<u32>() {
+                let entry = dev_nonces.entry(dev_eui.to_owned()).or_insert(dev_nonce);
+                *entry = (*entry).max(dev_nonce);
+            }
+        }
+    }
+    dev_nonces
+}
+
+#[test]
+fn join_accept_roundtrip() {
+    let key = [0x11; 16];
+    let request = JoinRequest { join_eui: EUI64::from_hex("0101010101010101").unwrap(), dev_eui: EUI64::from_hex("0202020202020202").unwrap(), dev_nonce: 7 };
+    let mut accept = JoinAccept {
+        join_nonce: 0x0a0b0c,
+        net_id: [0x00, 0x00, 0x13],
+        dev_addr: [0x26, 0x01, 0x1b, 0xda],
+        opt_neg: false,
+        rx1_dr_offset: 1,
+        rx2_data_rate: 3,
+        rx_delay: 1,
+        cf_list: Some([0x18, 0x4f, 0x84, 0xe8, 0x56, 0x84, 0xb8, 0x5e, 0x84, 0x88, 0x66, 0x84, 0x58, 0x6e, 0x84, 0x00]),
+    };
+    let bytes = accept.to_bytes(&key, &request);
+    assert_eq!(bytes.len(), 33);
+    assert_eq!(JoinAccept::decrypt(&bytes, &key, &request, false), Ok(accept.clone()));
+    assert_eq!(JoinAccept::decrypt(&bytes, &[0x12; 16], &request, false), Err(FrameError::InvalidMic));
+    //an empty downlink, as a gateway may forward, is not read past its end
+    assert_eq!(JoinAccept::decrypt(&[], &key, &request, false), Err(FrameError::TooShort(0)));
+    assert_eq!(accept.cf_list_frequencies(), vec![867_100_000.0, 867_300_000.0, 867_500_000.0, 867_700_000.0, 867_900_000.0]);
+
+    let keys = accept.session_keys(&key, &[0x22; 16], &request);
+    assert_ne!(keys.f_nwk_s_int_key, keys.app_s_key);
+    assert_eq!(keys.f_nwk_s_int_key, keys.s_nwk_s_int_key);
+
+    //a 1.1 network answers with OptNeg set and a MIC bound to the JoinRequest, 1.0 devices can not check it
+    accept.opt_neg = true;
+    let bytes = accept.to_bytes(&key, &request);
+    assert_eq!(JoinAccept::decrypt(&bytes, &key, &request, true), Ok(accept.clone()));
+    assert_eq!(JoinAccept::decrypt(&bytes, &key, &JoinRequest { dev_nonce: 8, ..request.clone() }, true), Err(FrameError::InvalidMic));
+    assert_eq!(JoinAccept::decrypt(&bytes, &key, &request, false), Err(FrameError::InvalidMic));
+    let keys = accept.session_keys(&key, &[0x22; 16], &request);
+    assert_ne!(keys.f_nwk_s_int_key, keys.s_nwk_s_int_key);
+}
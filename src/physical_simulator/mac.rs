@@ -0,0 +1,779 @@
+//Device side MAC layer of the simulated nodes: keeps the radio parameters the network asked for
+//and the answers that have to be piggybacked on the next uplink.
+
+use std::time::Duration;
+
+use lorawan::{
+    device::{Device, LoRaWANVersion},
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
+};
+use lorawan_device::{communicator::ReceivedTransmission, configs::RadioDeviceConfig};
+use rand::Rng;
+
+use crate::constants::{BATTERY_DRAIN_PER_UPLINK, DEVICE_TIME_REQ_EVERY, LINK_CHECK_REQ_EVERY};
+
+use super::{
+    crypto::{crypt_f_opts, crypt_frm_payload},
+    frame::{DataFrame, FrameError, MType, FCTRL_ACK, FCTRL_ADR, FCTRL_ADR_ACK_REQ},
+    join::JoinAccept,
+    node::{RadioParams, TxParams},
+    utils::{get_sensitivity, gps_epoch_to_unix_ms},
+};
+
+pub const ADR_ACK_LIMIT: u32 = 64;
+pub const ADR_ACK_DELAY: u32 = 32;
+//RETRANSMIT_TIMEOUT of LoRaWAN 1.0.4, waited after the end of RX2 before a retransmission
+pub const RETRANSMIT_TIMEOUT_MS: (u64, u64) = (1000, 3000);
+
+//EU863-870 regional parameters
+const MAX_EIRP_DBM: f32 = 16.0;
+const MAX_TX_POWER_INDEX: u8 = 7;
+const MAX_RX1_DR_OFFSET: u8 = 5;
+pub const DEFAULT_CHANNELS: [f64; 3] = [868_100_000.0, 868_300_000.0, 868_500_000.0];
+pub const DEFAULT_RX2: (f64, u8) = (869_525_000.0, 0);
+const MAX_CHANNELS: usize = 16;
+
+fn valid_frequency(frequency: f64) -> bool {
+    (863_000_000.0..=870_000_000.0).contains(&frequency)
+}
+
+pub fn data_rate_to_radio(dr: u8) -> Option<(SpreadingFactor, LoRaBandwidth)> {
+    match dr {
+        0 => Some((SpreadingFactor::SF12, LoRaBandwidth::BW125)),
+        1 => Some((SpreadingFactor::SF11, LoRaBandwidth::BW125)),
+        2 => Some((SpreadingFactor::SF10, LoRaBandwidth::BW125)),
+        3 => Some((SpreadingFactor::SF9, LoRaBandwidth::BW125)),
+        4 => Some((SpreadingFactor::SF8, LoRaBandwidth::BW125)),
+        5 => Some((SpreadingFactor::SF7, LoRaBandwidth::BW125)),
+        6 => Some((SpreadingFactor::SF7, LoRaBandwidth::BW250)),
+        _ => None,
+    }
+}
+
+pub fn radio_to_data_rate(sf: SpreadingFactor, bw: LoRaBandwidth) -> Option<u8> {
+    (0..=6).find(|dr| data_rate_to_radio(*dr) == Some((sf, bw)))
+}
+
+fn tx_power_dbm(index: u8) -> f32 {
+    MAX_EIRP_DBM - 2.0 * index as f32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channel {
+    pub frequency: f64,
+    pub min_dr: u8,
+    pub max_dr: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownlinkMacCommand {
+    ResetConf { minor: u8 },
+    LinkCheckAns { margin: u8, gw_cnt: u8 },
+    LinkADRReq { data_rate: u8, tx_power: u8, ch_mask: u16, ch_mask_cntl: u8, nb_trans: u8 },
+    DutyCycleReq { max_duty_cycle: u8 },
+    RXParamSetupReq { rx1_dr_offset: u8, rx2_data_rate: u8, frequency: u32 },
+    DevStatusReq,
+    NewChannelReq { ch_index: u8, frequency: u32, min_dr: u8, max_dr: u8 },
+    RXTimingSetupReq { delay: u8 },
+    TxParamSetupReq { eirp_dwell_time: u8 },
+    DlChannelReq { ch_index: u8, frequency: u32 },
+    RekeyConf { minor: u8 },
+    ADRParamSetupReq { limit_exp: u8, delay_exp: u8 },
+    DeviceTimeAns { seconds: u32, fractional: u8 },
+    ForceRejoinReq { rejoin_type: u8, data_rate: u8 },
+    RejoinParamSetupReq { max_time_n: u8, max_count_n: u8 },
+    Unknown { cid: u8 },
+}
+
+const RESET: u8 = 0x01;
+const LINK_CHECK: u8 = 0x02;
+const LINK_ADR: u8 = 0x03;
+const DUTY_CYCLE: u8 = 0x04;
+const RX_PARAM_SETUP: u8 = 0x05;
+const DEV_STATUS: u8 = 0x06;
+const NEW_CHANNEL: u8 = 0x07;
+const RX_TIMING_SETUP: u8 = 0x08;
+const DL_CHANNEL: u8 = 0x0a;
+const REKEY: u8 = 0x0b;
+const ADR_PARAM_SETUP: u8 = 0x0c;
+const DEVICE_TIME: u8 = 0x0d;
+const REJOIN_PARAM_SETUP: u8 = 0x0f;
+//LoRaWAN minor version sent in ResetInd and RekeyInd
+const LORAWAN_1_1_MINOR: u8 = 1;
+
+fn frequency_from_bytes(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], 0]) * 100
+}
+
+impl DownlinkMacCommand {
+    /// Parses a sequence of network to device commands, stopping at the first unknown CID
+    /// because its length (and so the start of the next command) is not known.
+    pub fn parse_all(bytes: &[u8]) -> Vec<DownlinkMacCommand> {
+        let mut commands = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let cid = bytes[i];
+            let len = match cid {
+                0x01 => 1,
+                0x02 => 2,
+                0x03 => 4,
+                0x04 => 1,
+                0x05 => 4,
+                0x06 => 0,
+                0x07 => 5,
+                0x08 => 1,
+                0x09 => 1,
+                0x0a => 4,
+                0x0b => 1,
+                0x0c => 1,
+                0x0d => 5,
+                0x0e => 2,
+                0x0f => 1,
+                _ => {
+                    commands.push(DownlinkMacCommand::Unknown { cid });
+                    break;
+                }
+            };
+            let Some(p) = bytes.get(i + 1..i + 1 + len) else { break };
+            commands.push(match cid {
+                0x01 => DownlinkMacCommand::ResetConf { minor: p[0] & 0x0f },
+                0x02 => DownlinkMacCommand::LinkCheckAns { margin: p[0], gw_cnt: p[1] },
+                0x03 => DownlinkMacCommand::LinkADRReq {
+                    data_rate: p[0] >> 4,
+                    tx_power: p[0] & 0x0f,
+                    ch_mask: u16::from_le_bytes([p[1], p[2]]),
+                    ch_mask_cntl: (p[3] >> 4) & 0x07,
+                    nb_trans: p[3] & 0x0f,
+                },
+                0x04 => DownlinkMacCommand::DutyCycleReq { max_duty_cycle: p[0] & 0x0f },
+                0x05 => DownlinkMacCommand::RXParamSetupReq {
+                    rx1_dr_offset: (p[0] >> 4) & 0x07,
+                    rx2_data_rate: p[0] & 0x0f,
+                    frequency: frequency_from_bytes(&p[1..4]),
+                },
+                0x06 => DownlinkMacCommand::DevStatusReq,
+                0x07 => DownlinkMacCommand::NewChannelReq {
+                    ch_index: p[0],
+                    frequency: frequency_from_bytes(&p[1..4]),
+                    min_dr: p[4] & 0x0f,
+                    max_dr: p[4] >> 4,
+                },
+                0x08 => DownlinkMacCommand::RXTimingSetupReq { delay: p[0] & 0x0f },
+                0x09 => DownlinkMacCommand::TxParamSetupReq { eirp_dwell_time: p[0] },
+                0x0a => DownlinkMacCommand::DlChannelReq { ch_index: p[0], frequency: frequency_from_bytes(&p[1..4]) },
+                0x0b => DownlinkMacCommand::RekeyConf { minor: p[0] & 0x0f },
+                0x0c => DownlinkMacCommand::ADRParamSetupReq { limit_exp: p[0] >> 4, delay_exp: p[0] & 0x0f },
+                0x0d => DownlinkMacCommand::DeviceTimeAns {
+                    seconds: u32::from_le_bytes([p[0], p[1], p[2], p[3]]),
+                    fractional: p[4],
+                },
+                0x0e => DownlinkMacCommand::ForceRejoinReq { rejoin_type: (p[0] >> 4) & 0x07, data_rate: p[0] & 0x0f },
+                _ => DownlinkMacCommand::RejoinParamSetupReq { max_time_n: p[0] >> 4, max_count_n: p[0] & 0x0f },
+            });
+            i += 1 + len;
+        }
+        commands
+    }
+}
+
+/// Session keys needed to inspect and patch frames outside of the lorawan crate.
+/// A 1.0 session has a single NwkSKey, stored in all three network keys.
+#[derive(Clone, Debug)]
+pub struct SessionKeys {
+    pub dev_addr: [u8; 4],
+    pub f_nwk_s_int_key: [u8; 16],
+    pub s_nwk_s_int_key: [u8; 16],
+    pub nwk_s_enc_key: [u8; 16],
+    pub lorawan_1_1: bool,
+}
+
+impl SessionKeys {
+    pub fn from_device(device: &Device) -> Option<SessionKeys> {
+        let network_context = device.session()?.network_context();
+        Some(SessionKeys {
+            dev_addr: *network_context.dev_addr(),
+            f_nwk_s_int_key: **network_context.f_nwk_s_int_key(),
+            s_nwk_s_int_key: **network_context.s_nwk_s_int_key(),
+            nwk_s_enc_key: **network_context.nwk_s_enc_key(),
+            lorawan_1_1: *device.version() == LoRaWANVersion::V1_1,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DownlinkInfo {
+    pub ack: bool,
+    pub f_port: Option<u8>,
+    pub commands: Vec<DownlinkMacCommand>,
+}
+
+#[derive(Debug)]
+pub struct MacState {
+    keys: SessionKeys,
+    f_cnt_up: u32,
+    n_f_cnt_down: Option<u32>,
+    a_f_cnt_down: Option<u32>, //1.1 only, 1.0 sessions count every downlink in n_f_cnt_down
+    //counters of the last confirmed frames, acknowledged through the ConfFCnt of 1.1 MICs
+    conf_f_cnt_up: u32,
+    conf_f_cnt_down: u32,
+
+    adr: bool,
+    adr_ack_cnt: u32,
+    adr_ack_limit: u32,
+    adr_ack_delay: u32,
+    data_rate: u8,
+    tx_power: u8,
+    nb_trans: u8,
+    channels: [Option<Channel>; MAX_CHANNELS],
+    dl_frequencies: [Option<f64>; MAX_CHANNELS],
+    ch_mask: u16,
+    last_channel: usize,
+
+    rx1_dr_offset: u8,
+    rx2: (f64, u8),
+    rx1_delay: u8,
+    max_duty_cycle: u8,
+    off_until: u128,
+
+    battery: f32,
+    uplinks: u32,
+    link_check: Option<(u8, u8)>,
+    clock_offset_ms: Option<i128>,
+    uplink_end: u128,
+
+    pending_answers: Vec<u8>,
+    //answers repeated in every uplink until a downlink is received
+    sticky_answers: Vec<u8>,
+    //RekeyInd or ResetInd, repeated in every uplink until the network confirms it
+    indication: Option<u8>,
+}
+
+impl MacState {
+    /// State of a node booting with a session, as ABP devices do: 1.1 ones announce it with ResetInd.
+    pub fn new(device: &Device, radio_config: &RadioDeviceConfig, transmission_power_dbm: f32) -> Option<MacState> {
+        let keys = SessionKeys::from_device(device)?;
+        let f_cnt_up = device.session()?.network_context().f_cnt_up();
+        let mut mac = Self::from_keys(keys, f_cnt_up, radio_config, transmission_power_dbm);
+        if mac.keys.lorawan_1_1 {
+            mac.indication = Some(RESET);
+        }
+        Some(mac)
+    }
+
+    pub fn from_keys(keys: SessionKeys, f_cnt_up: u32, radio_config: &RadioDeviceConfig, transmission_power_dbm: f32) -> MacState {
+        let mut channels = [None; MAX_CHANNELS];
+        for (i, frequency) in DEFAULT_CHANNELS.iter().enumerate() {
+            channels[i] = Some(Channel { frequency: *frequency, min_dr: 0, max_dr: 5 });
+        }
+        //nodes configured outside of the default channels get their frequency as the first extra channel
+        let configured = DEFAULT_CHANNELS.iter().position(|f| *f == radio_config.freq).unwrap_or_else(|| {
+            channels[DEFAULT_CHANNELS.len()] = Some(Channel { frequency: radio_config.freq, min_dr: 0, max_dr: 5 });
+            DEFAULT_CHANNELS.len()
+        });
+
+        let tx_power = (((MAX_EIRP_DBM - transmission_power_dbm) / 2.0).round().max(0.0) as u8).min(MAX_TX_POWER_INDEX);
+
+        MacState {
+            keys,
+            f_cnt_up,
+            n_f_cnt_down: None,
+            a_f_cnt_down: None,
+            conf_f_cnt_up: 0,
+            conf_f_cnt_down: 0,
+            adr: true,
+            adr_ack_cnt: 0,
+            adr_ack_limit: ADR_ACK_LIMIT,
+            adr_ack_delay: ADR_ACK_DELAY,
+            data_rate: radio_to_data_rate(radio_config.spreading_factor, radio_config.bandwidth).unwrap_or(5),
+            tx_power,
+            nb_trans: 1,
+            channels,
+            dl_frequencies: [None; MAX_CHANNELS],
+            ch_mask: 1 << configured,
+            last_channel: configured,
+            rx1_dr_offset: 0,
+            rx2: DEFAULT_RX2,
+            rx1_delay: 1,
+            max_duty_cycle: 0,
+            off_until: 0,
+            battery: 1.0,
+            uplinks: 0,
+            link_check: None,
+            clock_offset_ms: None,
+            uplink_end: 0,
+            pending_answers: Vec::new(),
+            sticky_answers: Vec::new(),
+            indication: None,
+        }
+    }
+
+    pub fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    pub fn tx_power_dbm(&self) -> f32 {
+        tx_power_dbm(self.tx_power)
+    }
+
+    pub fn nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
+
+    pub fn ch_mask(&self) -> u16 {
+        self.ch_mask
+    }
+
+    pub fn adr_ack_cnt(&self) -> u32 {
+        self.adr_ack_cnt
+    }
+
+    pub fn n_f_cnt_down(&self) -> Option<u32> {
+        self.n_f_cnt_down
+    }
+
+    pub fn lorawan_1_1(&self) -> bool {
+        self.keys.lorawan_1_1
+    }
+
+    /// Receive windows settings and extra channels of a JoinAccept, for a state built from its session keys.
+    pub fn apply_join_accept(&mut self, accept: &JoinAccept) {
+        if accept.rx1_dr_offset <= MAX_RX1_DR_OFFSET {
+            self.rx1_dr_offset = accept.rx1_dr_offset;
+        }
+        if data_rate_to_radio(accept.rx2_data_rate).is_some() {
+            self.rx2.1 = accept.rx2_data_rate;
+        }
+        self.rx1_delay = accept.rx_delay.max(1);
+        for (i, frequency) in accept.cf_list_frequencies().into_iter().enumerate().filter(|(_, f)| valid_frequency(*f)) {
+            self.channels[DEFAULT_CHANNELS.len() + i] = Some(Channel { frequency, min_dr: 0, max_dr: 5 });
+            self.ch_mask |= 1 << (DEFAULT_CHANNELS.len() + i);
+        }
+        //a 1.1 session is only confirmed by the network once RekeyConf is received
+        if self.keys.lorawan_1_1 {
+            self.indication = Some(REKEY);
+        }
+    }
+
+    /// Battery level as reported in DevStatusAns, 1 (empty) to 254 (full).
+    pub fn battery_level(&self) -> u8 {
+        1 + (self.battery.clamp(0.0, 1.0) * 253.0).round() as u8
+    }
+
+    /// Last (margin, gateway count) received in a LinkCheckAns.
+    pub fn link_check(&self) -> Option<(u8, u8)> {
+        self.link_check
+    }
+
+    /// Difference between the network time received in DeviceTimeAns and the local clock.
+    pub fn clock_offset_ms(&self) -> Option<i128> {
+        self.clock_offset_ms
+    }
+
+    /// Uplinks must not start before this time (ms since the unix epoch) to respect DutyCycleReq.
+    pub fn off_until(&self) -> u128 {
+        self.off_until
+    }
+
+    pub fn request_link_check(&mut self) {
+        self.pending_answers.push(LINK_CHECK);
+    }
+
+    pub fn request_device_time(&mut self) {
+        self.pending_answers.push(DEVICE_TIME);
+    }
+
+    fn enabled_channels(&self) -> Vec<usize> {
+        (0..MAX_CHANNELS).filter(|i| self.ch_mask & (1 << i) != 0 && self.channels[*i].is_some()).collect()
+    }
+
+    fn tx_params<R: Rng + ?Sized>(&mut self, rng: &mut R) -> TxParams {
+        let mut channels = self.enabled_channels();
+        if channels.is_empty() {
+            //nothing usable is left, the default channels are always defined
+            self.ch_mask |= (1 << DEFAULT_CHANNELS.len()) - 1;
+            channels = self.enabled_channels();
+        }
+        self.last_channel = channels[rng.gen_range(0..channels.len())];
+        let (spreading_factor, bandwidth) = data_rate_to_radio(self.data_rate).unwrap();
+        TxParams {
+            radio: RadioParams { frequency: self.channels[self.last_channel].unwrap().frequency, spreading_factor, bandwidth },
+            power_dbm: self.tx_power_dbm(),
+        }
+    }
+
+    /// RX1 (uplink channel, or the one set by DlChannelReq, with the RX1 data rate offset) and RX2.
+    pub fn rx_windows(&self, tx_params: &TxParams) -> Vec<RadioParams> {
+        let rx1_frequency = self.dl_frequencies[self.last_channel].unwrap_or(tx_params.radio.frequency);
+        let (rx1_sf, rx1_bw) = data_rate_to_radio(self.data_rate.saturating_sub(self.rx1_dr_offset)).unwrap();
+        let (rx2_sf, rx2_bw) = data_rate_to_radio(self.rx2.1).unwrap();
+        vec![
+            RadioParams { frequency: rx1_frequency, spreading_factor: rx1_sf, bandwidth: rx1_bw },
+            RadioParams { frequency: self.rx2.0, spreading_factor: rx2_sf, bandwidth: rx2_bw },
+        ]
+    }
+
+    /// How long to listen after an uplink to cover both receive windows.
+    pub fn rx_timeout(&self) -> Duration {
+        Duration::from_secs(self.rx1_delay as u64 + 1)
+    }
+
+    /// Bookkeeping once the uplink left the antenna: battery, aggregated duty cycle and the clock used by DeviceTimeAns.
+    pub fn on_uplink_sent(&mut self, end_time: u128, time_on_air: u128) {
+        let (sf, _) = data_rate_to_radio(self.data_rate).unwrap();
+        //the energy of an uplink is roughly proportional to its time on air, which doubles at every SF step
+        self.battery -= BATTERY_DRAIN_PER_UPLINK * (1 << (sf.value() - 7)) as f32 * (self.tx_power_dbm() / MAX_EIRP_DBM);
+        self.off_until = end_time + time_on_air * ((1 << self.max_duty_cycle) - 1);
+        self.uplink_end = end_time;
+    }
+
+    /// Radio parameters for a retransmission of the last uplink, which is sent again with the same FCnt.
+    /// Every two failed attempts the data rate is lowered by one step to gain some range.
+    pub fn retransmission_params<R: Rng + ?Sized>(&mut self, attempt: u8, rng: &mut R) -> TxParams {
+        if attempt > 1 && attempt % 2 == 1 && self.data_rate > 0 {
+            self.data_rate -= 1;
+        }
+        self.tx_params(rng)
+    }
+
+    /// Retransmission of `payload`: the MIC of a 1.1 uplink covers its data rate and channel, so it is signed again.
+    pub fn prepare_retransmission<R: Rng + ?Sized>(&mut self, payload: &[u8], attempt: u8, rng: &mut R) -> Result<(Vec<u8>, TxParams), FrameError> {
+        let tx_params = self.retransmission_params(attempt, rng);
+        if !self.keys.lorawan_1_1 {
+            return Ok((payload.to_vec(), tx_params));
+        }
+        let mut frame = DataFrame::parse(payload)?;
+        let f_cnt = frame.full_f_cnt(self.f_cnt_up);
+        frame.f_opts = crypt_f_opts(&self.keys.nwk_s_enc_key, &self.keys.dev_addr, f_cnt, true, false, &frame.f_opts);
+        Ok((self.seal_uplink(frame, f_cnt), tx_params))
+    }
+
+    //encrypts the FOpts of 1.1 frames and computes the MIC, for the data rate and channel of the last tx_params
+    fn seal_uplink(&self, mut frame: DataFrame, f_cnt: u32) -> Vec<u8> {
+        if !self.keys.lorawan_1_1 {
+            return frame.to_bytes_with_mic(&self.keys.f_nwk_s_int_key, f_cnt);
+        }
+        frame.f_opts = crypt_f_opts(&self.keys.nwk_s_enc_key, &self.keys.dev_addr, f_cnt, true, false, &frame.f_opts);
+        let conf_f_cnt = if frame.has_flag(FCTRL_ACK) { self.conf_f_cnt_down as u16 } else { 0 };
+        let tx = (self.data_rate, self.last_channel as u8);
+        frame.mic = frame.compute_mic_1_1(&self.keys.f_nwk_s_int_key, &self.keys.s_nwk_s_int_key, f_cnt, conf_f_cnt, tx);
+        frame.to_bytes()
+    }
+
+    /// Random delay before a retransmission, counted from the end of the receive windows.
+    pub fn retransmission_backoff<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        Duration::from_millis(rng.gen_range(RETRANSMIT_TIMEOUT_MS.0..=RETRANSMIT_TIMEOUT_MS.1))
+    }
+
+    //ADR backoff of LoRaWAN 1.0.4 section 4.3.1.1: max power first, then lower data rates, then default channels
+    fn adr_backoff_step(&mut self) {
+        if self.tx_power > 0 {
+            self.tx_power = 0;
+        } else if self.data_rate > 0 {
+            self.data_rate -= 1;
+        } else {
+            self.ch_mask |= (1 << DEFAULT_CHANNELS.len()) - 1;
+        }
+    }
+
+    fn fully_backed_off(&self) -> bool {
+        let defaults = (1 << DEFAULT_CHANNELS.len()) - 1;
+        self.tx_power == 0 && self.data_rate == 0 && self.ch_mask & defaults == defaults
+    }
+
+    /// Applies the MAC layer state to a frame built by the device: ADR bits, pending answers in FOpts
+    /// and the radio parameters the uplink has to be sent with.
+    pub fn prepare_uplink<R: Rng + ?Sized>(&mut self, payload: &[u8], rng: &mut R) -> Result<(Vec<u8>, TxParams), FrameError> {
+        let mut frame = DataFrame::parse(payload)?;
+        let f_cnt = frame.full_f_cnt(self.f_cnt_up);
+        self.f_cnt_up = f_cnt;
+        if self.keys.lorawan_1_1 {
+            frame.f_opts = crypt_f_opts(&self.keys.nwk_s_enc_key, &self.keys.dev_addr, f_cnt, true, false, &frame.f_opts);
+        }
+        if frame.mtype() == MType::ConfirmedDataUp {
+            self.conf_f_cnt_up = f_cnt;
+        }
+
+        if self.adr && self.adr_ack_cnt >= self.adr_ack_limit + self.adr_ack_delay {
+            self.adr_backoff_step();
+            self.adr_ack_cnt = self.adr_ack_limit;
+        }
+        frame.set_flag(FCTRL_ADR, self.adr);
+        frame.set_flag(FCTRL_ADR_ACK_REQ, self.adr && self.adr_ack_cnt >= self.adr_ack_limit && !self.fully_backed_off());
+        self.adr_ack_cnt += 1;
+
+        self.uplinks += 1;
+        if self.uplinks.is_multiple_of(LINK_CHECK_REQ_EVERY) {
+            self.request_link_check();
+        }
+        if self.uplinks.is_multiple_of(DEVICE_TIME_REQ_EVERY) {
+            self.request_device_time();
+        }
+
+        //FOpts can hold at most 15 bytes, whatever does not fit waits for the next uplink
+        if let Some(cid) = self.indication {
+            if frame.f_opts.len() + 2 <= 15 {
+                frame.f_opts.extend_from_slice(&[cid, LORAWAN_1_1_MINOR]);
+            }
+        }
+        let sticky_len = commands_fitting(&self.sticky_answers, 15 - frame.f_opts.len());
+        frame.f_opts.extend_from_slice(&self.sticky_answers[..sticky_len]);
+        let pending_len = commands_fitting(&self.pending_answers, 15 - frame.f_opts.len());
+        frame.f_opts.extend(self.pending_answers.drain(..pending_len));
+
+        let tx_params = self.tx_params(rng);
+        Ok((self.seal_uplink(frame, f_cnt), tx_params))
+    }
+
+    /// Validates a downlink addressed to this node and processes the MAC commands it carries.
+    pub fn handle_downlink(&mut self, received: &ReceivedTransmission) -> Result<DownlinkInfo, FrameError> {
+        let frame = DataFrame::parse(&received.transmission.payload)?;
+        if frame.mtype().is_uplink() || frame.dev_addr != self.keys.dev_addr {
+            return Err(FrameError::NotADataFrame(frame.mtype()));
+        }
+
+        //1.1 sessions count application downlinks apart, in AFCntDown
+        let application = self.keys.lorawan_1_1 && frame.f_port.is_some_and(|p| p > 0);
+        let last_f_cnt_down = if application { self.a_f_cnt_down } else { self.n_f_cnt_down };
+        let f_cnt = frame.full_f_cnt(last_f_cnt_down.map_or(0, |f| f + 1));
+        if self.keys.lorawan_1_1 {
+            let conf_f_cnt = if frame.has_flag(FCTRL_ACK) { self.conf_f_cnt_up as u16 } else { 0 };
+            if frame.compute_mic_1_1(&self.keys.f_nwk_s_int_key, &self.keys.s_nwk_s_int_key, f_cnt, conf_f_cnt, (0, 0)) != frame.mic {
+                return Err(FrameError::InvalidMic);
+            }
+        } else {
+            frame.verify_mic(&self.keys.f_nwk_s_int_key, f_cnt)?;
+        }
+        if application {
+            self.a_f_cnt_down = Some(f_cnt);
+        } else {
+            self.n_f_cnt_down = Some(f_cnt);
+        }
+        if frame.mtype() == MType::ConfirmedDataDown {
+            self.conf_f_cnt_down = f_cnt;
+        }
+        self.adr_ack_cnt = 0;
+        self.sticky_answers.clear();
+
+        //the simulated radio has no demodulator, the link margin is the distance from the sensitivity
+        let margin = (received.arrival_stats.rssi - get_sensitivity(&received.transmission)).round().clamp(-32.0, 31.0) as i8;
+
+        let f_opts = if self.keys.lorawan_1_1 {
+            crypt_f_opts(&self.keys.nwk_s_enc_key, &self.keys.dev_addr, f_cnt, false, application, &frame.f_opts)
+        } else {
+            frame.f_opts.clone()
+        };
+        let mut commands = DownlinkMacCommand::parse_all(&f_opts);
+        if frame.f_port == Some(0) {
+            let plain = crypt_frm_payload(&self.keys.nwk_s_enc_key, &self.keys.dev_addr, f_cnt, false, &frame.frm_payload);
+            commands.extend(DownlinkMacCommand::parse_all(&plain));
+        }
+        self.process_commands(&commands, margin);
+
+        Ok(DownlinkInfo { ack: frame.has_flag(FCTRL_ACK), f_port: frame.f_port, commands })
+    }
+
+    fn process_commands(&mut self, commands: &[DownlinkMacCommand], margin: i8) {
+        let mut i = 0;
+        while i < commands.len() {
+            match commands[i] {
+                DownlinkMacCommand::LinkADRReq { .. } => {
+                    //contiguous LinkADRReq are a single atomic request (LoRaWAN 1.0.4 section 5.3)
+                    let block_len = commands[i..].iter().take_while(|c| matches!(c, DownlinkMacCommand::LinkADRReq { .. })).count();
+                    self.link_adr_req(&commands[i..i + block_len]);
+                    i += block_len;
+                    continue;
+                }
+                DownlinkMacCommand::LinkCheckAns { margin, gw_cnt } => self.link_check = Some((margin, gw_cnt)),
+                DownlinkMacCommand::DutyCycleReq { max_duty_cycle } => {
+                    self.max_duty_cycle = max_duty_cycle;
+                    self.pending_answers.push(DUTY_CYCLE);
+                }
+                DownlinkMacCommand::RXParamSetupReq { rx1_dr_offset, rx2_data_rate, frequency } => {
+                    let frequency_ack = valid_frequency(frequency as f64);
+                    let rx2_dr_ack = data_rate_to_radio(rx2_data_rate).is_some();
+                    let rx1_dr_offset_ack = rx1_dr_offset <= MAX_RX1_DR_OFFSET;
+                    if frequency_ack && rx2_dr_ack && rx1_dr_offset_ack {
+                        self.rx1_dr_offset = rx1_dr_offset;
+                        self.rx2 = (frequency as f64, rx2_data_rate);
+                    }
+                    let status = (rx1_dr_offset_ack as u8) << 2 | (rx2_dr_ack as u8) << 1 | frequency_ack as u8;
+                    self.sticky_answers.extend_from_slice(&[RX_PARAM_SETUP, status]);
+                }
+                DownlinkMacCommand::DevStatusReq => {
+                    self.pending_answers.extend_from_slice(&[DEV_STATUS, self.battery_level(), margin as u8 & 0x3f]);
+                }
+                DownlinkMacCommand::NewChannelReq { ch_index, frequency, min_dr, max_dr } => {
+                    let ch_index = ch_index as usize;
+                    //default channels can not be modified in EU863-870
+                    let editable = ch_index >= DEFAULT_CHANNELS.len() && ch_index < MAX_CHANNELS;
+                    //removing the last enabled channel would leave the node unable to transmit
+                    let last_channel = frequency == 0 && self.enabled_channels() == [ch_index];
+                    let frequency_ack = editable && !last_channel && (frequency == 0 || valid_frequency(frequency as f64));
+                    let dr_range_ack = editable && min_dr <= max_dr && data_rate_to_radio(max_dr).is_some();
+                    if frequency_ack && dr_range_ack {
+                        if frequency == 0 {
+                            self.channels[ch_index] = None;
+                            self.ch_mask &= !(1 << ch_index);
+                        } else {
+                            self.channels[ch_index] = Some(Channel { frequency: frequency as f64, min_dr, max_dr });
+                            self.ch_mask |= 1 << ch_index;
+                        }
+                        self.dl_frequencies[ch_index] = None;
+                    }
+                    self.pending_answers.extend_from_slice(&[NEW_CHANNEL, (dr_range_ack as u8) << 1 | frequency_ack as u8]);
+                }
+                DownlinkMacCommand::RXTimingSetupReq { delay } => {
+                    self.rx1_delay = delay.max(1);
+                    self.sticky_answers.push(RX_TIMING_SETUP);
+                }
+                DownlinkMacCommand::DlChannelReq { ch_index, frequency } => {
+                    let ch_index = ch_index as usize;
+                    let uplink_frequency_exists = ch_index < MAX_CHANNELS && self.channels[ch_index].is_some();
+                    let frequency_ack = valid_frequency(frequency as f64);
+                    if uplink_frequency_exists && frequency_ack {
+                        self.dl_frequencies[ch_index] = Some(frequency as f64);
+                    }
+                    self.sticky_answers.extend_from_slice(&[DL_CHANNEL, (uplink_frequency_exists as u8) << 1 | frequency_ack as u8]);
+                }
+                DownlinkMacCommand::DeviceTimeAns { seconds, fractional } => {
+                    //the time refers to the end of the uplink carrying DeviceTimeReq
+                    let network_ms = gps_epoch_to_unix_ms(seconds as u128 * 1000 + fractional as u128 * 1000 / 256);
+                    self.clock_offset_ms = Some(network_ms as i128 - self.uplink_end as i128);
+                }
+                DownlinkMacCommand::ResetConf { .. } if self.indication == Some(RESET) => self.indication = None,
+                DownlinkMacCommand::RekeyConf { .. } if self.indication == Some(REKEY) => self.indication = None,
+                DownlinkMacCommand::ADRParamSetupReq { limit_exp, delay_exp } => {
+                    self.adr_ack_limit = 1 << limit_exp;
+                    self.adr_ack_delay = 1 << delay_exp;
+                    self.pending_answers.push(ADR_PARAM_SETUP);
+                }
+                //periodic rejoins are not simulated, the device only says it can not follow the time limit
+                DownlinkMacCommand::RejoinParamSetupReq { .. } => self.pending_answers.extend_from_slice(&[REJOIN_PARAM_SETUP, 0]),
+                //TxParamSetupReq is not implemented in EU863-870 and RejoinRequests are not simulated
+                DownlinkMacCommand::TxParamSetupReq { .. }
+                | DownlinkMacCommand::ResetConf { .. }
+                | DownlinkMacCommand::RekeyConf { .. }
+                | DownlinkMacCommand::ForceRejoinReq { .. }
+                | DownlinkMacCommand::Unknown { .. } => (),
+            }
+            i += 1;
+        }
+    }
+
+    fn link_adr_req(&mut self, block: &[DownlinkMacCommand]) {
+        let mut ch_mask = self.ch_mask;
+        let mut ch_mask_ack = true;
+        for command in block {
+            if let DownlinkMacCommand::LinkADRReq { ch_mask: mask, ch_mask_cntl, .. } = command {
+                match ch_mask_cntl {
+                    0 => ch_mask = *mask,
+                    6 => ch_mask = (0..MAX_CHANNELS).filter(|i| self.channels[*i].is_some()).fold(0, |m, i| m | 1 << i),
+                    _ => ch_mask_ack = false,
+                }
+            }
+        }
+        let enabled = (0..MAX_CHANNELS).filter(|i| ch_mask & (1 << i) != 0).collect::<Vec<_>>();
+        if enabled.is_empty() || enabled.iter().any(|i| self.channels[*i].is_none()) {
+            ch_mask_ack = false;
+        }
+
+        let Some(DownlinkMacCommand::LinkADRReq { data_rate, tx_power, nb_trans, .. }) = block.last() else { return };
+        let data_rate = if *data_rate == 0x0f { self.data_rate } else { *data_rate };
+        let data_rate_ack = data_rate_to_radio(data_rate).is_some()
+            && enabled.iter().filter_map(|i| self.channels[*i]).any(|c| c.min_dr <= data_rate && data_rate <= c.max_dr);
+        let tx_power = if *tx_power == 0x0f { self.tx_power } else { *tx_power };
+        let tx_power_ack = tx_power <= MAX_TX_POWER_INDEX;
+
+        if ch_mask_ack && data_rate_ack && tx_power_ack {
+            self.ch_mask = ch_mask;
+            self.data_rate = data_rate;
+            self.tx_power = tx_power;
+            if *nb_trans != 0 {
+                self.nb_trans = *nb_trans;
+            }
+        }
+
+        let status = (tx_power_ack as u8) << 2 | (data_rate_ack as u8) << 1 | ch_mask_ack as u8;
+        for _ in block {
+            self.pending_answers.extend_from_slice(&[LINK_ADR, status]);
+        }
+    }
+}
+
+//total length, CID included, of the device to network commands
+fn uplink_command_len(cid: u8) -> usize {
+    match cid {
+        RESET | LINK_ADR | RX_PARAM_SETUP | NEW_CHANNEL | DL_CHANNEL | REKEY | REJOIN_PARAM_SETUP => 2,
+        DEV_STATUS => 3,
+        _ => 1,
+    }
+}
+
+//number of bytes of whole commands, taken from the start of `commands`, that fit in `space`
+fn commands_fitting(commands: &[u8], space: usize) -> usize {
+    let mut len = 0;
+    while let Some(cid) = commands.get(len) {
+        let next = len + uplink_command_len(*cid);
+        if next > space || next > commands.len() {
+            break;
+        }
+        len = next;
+    }
+    len
+}
+
+#[test]
+fn mac_commands_and_answers() {
+    use lorawan::{physical_parameters::{CodeRate, DataRate}, regional_parameters::region::Region};
+
+    let radio_config = RadioDeviceConfig {
+        region: Region::EU863_870,
+        spreading_factor: SpreadingFactor::SF7,
+        data_rate: DataRate::DR5,
+        bandwidth: LoRaBandwidth::BW125,
+        freq: 868_100_000.0,
+        sample_rate: 1.0,
+        rx_chan_id: 1,
+        tx_chan_id: 1,
+        code_rate: CodeRate::CR4_5,
+    };
+    let keys = SessionKeys { dev_addr: [0; 4], f_nwk_s_int_key: [0; 16], s_nwk_s_int_key: [0; 16], nwk_s_enc_key: [0; 16], lorawan_1_1: false };
+    let mut mac = MacState::from_keys(keys, 0, &radio_config, 14.0);
+
+    //DR2, power index 3, channels 0-2, NbTrans 2
+    mac.process_commands(&DownlinkMacCommand::parse_all(&[0x03, 0x23, 0x07, 0x00, 0x02]), 0);
+    assert_eq!((mac.data_rate(), mac.tx_power_dbm(), mac.ch_mask(), mac.nb_trans()), (2, 10.0, 0x07, 2));
+    assert_eq!(mac.pending_answers, vec![0x03, 0x07]);
+
+    //enabling an undefined channel is refused as a whole
+    mac.pending_answers.clear();
+    mac.process_commands(&DownlinkMacCommand::parse_all(&[0x03, 0x5f, 0x08, 0x00, 0x01]), 0);
+    assert_eq!((mac.data_rate(), mac.ch_mask()), (2, 0x07));
+    assert_eq!(mac.pending_answers, vec![0x03, 0x04]);
+
+    //DevStatusReq, NewChannelReq on channel 3 (867.1 MHz, DR0-5), RXTimingSetupReq of 3 s
+    mac.pending_answers.clear();
+    mac.process_commands(&DownlinkMacCommand::parse_all(&[0x06, 0x07, 0x03, 0x18, 0x4f, 0x84, 0x50, 0x08, 0x03]), -5);
+    assert_eq!(mac.pending_answers, vec![0x06, 254, 0x3b, 0x07, 0x03]);
+    assert_eq!(mac.sticky_answers, vec![0x08]);
+    assert_eq!(mac.ch_mask(), 0x0f);
+    assert_eq!(mac.rx_timeout(), Duration::from_secs(4));
+    assert_eq!(commands_fitting(&mac.pending_answers, 4), 3);
+
+    //retransmissions step the data rate down every two attempts
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    mac.retransmission_params(2, &mut rng);
+    assert_eq!(mac.data_rate(), 2);
+    assert_eq!(mac.retransmission_params(3, &mut rng).radio.spreading_factor, SpreadingFactor::SF11);
+
+    //only channel 3 enabled, then a NewChannelReq deleting it is refused
+    mac.pending_answers.clear();
+    mac.process_commands(&DownlinkMacCommand::parse_all(&[0x03, 0xff, 0x08, 0x00, 0x00, 0x07, 0x03, 0x00, 0x00, 0x00, 0x50]), 0);
+    assert_eq!(mac.pending_answers, vec![0x03, 0x07, 0x07, 0x02]);
+    assert_eq!(mac.ch_mask(), 0x08);
+    //a node left without channels falls back to the default ones
+    mac.ch_mask = 0;
+    let frequency = mac.tx_params(&mut rng).radio.frequency;
+    assert!(DEFAULT_CHANNELS.contains(&frequency));
+    assert_eq!(mac.ch_mask(), 0x07);
+}
@@ -4,4 +4,17 @@ pub mod node;
 pub mod utils;
 pub mod network_controller_bridge;
 pub mod chirpstack_bridge;
-pub mod multi_node;
\ No newline at end of file
+pub mod semtech_udp_bridge;
+pub mod multi_node;
+pub mod crypto;
+pub mod frame;
+pub mod multicast;
+pub mod mac;
+pub mod join;
+pub mod fleet;
+pub mod session_store;
+pub mod activation;
+pub mod alarm;
+pub mod payload;
+pub mod mqtt;
+pub mod gateway_state;
@@ -0,0 +1,278 @@
+//One MQTT connection shared by the gateways of a broker, that survives the broker going away
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use paho_mqtt::{AsyncClient, ConnectOptionsBuilder, CreateOptionsBuilder, Message, SslOptionsBuilder};
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Gateways whose settings only differ by `topic_prefix` and `qos` share one connection. MQTT gives a connection a single
+/// last will, so the broker publishes the offline state of a gateway that did not disconnect cleanly only when it is alone on
+/// its connection: a `client_id` per gateway gives each one its own connection.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub broker_url: String, //tcp://host:1883, or ssl://host:8883 with tls
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<MqttTls>,
+    pub topic_prefix: String, //the region of the ChirpStack gateway bridge, like eu868
+    pub qos: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttTls {
+    pub ca_cert: PathBuf,
+    pub client_cert: Option<PathBuf>, //with client_key, for brokers asking for client certificates
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            broker_url: String::from("tcp://169.254.189.196:1883"),
+            client_id: None,
+            username: None,
+            password: None,
+            tls: None,
+            topic_prefix: String::from("eu868"),
+            qos: paho_mqtt::QOS_2,
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Whether the gateways of the two settings share a connection.
+    pub fn same_connection(&self, other: &MqttConfig) -> bool {
+        (&self.broker_url, &self.client_id, &self.username, &self.password, &self.tls) == (&other.broker_url, &other.client_id, &other.username, &other.password, &other.tls)
+    }
+
+    pub fn up_topic(&self, gwid: &str) -> String {
+        format!("{}/gateway/{gwid}/event/up", self.topic_prefix)
+    }
+
+    pub fn down_topic(&self, gwid: &str) -> String {
+        format!("{}/gateway/{gwid}/command/down", self.topic_prefix)
+    }
+
+    pub fn ack_topic(&self, gwid: &str) -> String {
+        format!("{}/gateway/{gwid}/event/ack", self.topic_prefix)
+    }
+
+    pub fn stats_topic(&self, gwid: &str) -> String {
+        format!("{}/gateway/{gwid}/event/stats", self.topic_prefix)
+    }
+
+    pub fn conn_topic(&self, gwid: &str) -> String {
+        format!("{}/gateway/{gwid}/state/conn", self.topic_prefix)
+    }
+
+    fn connect_options(&self, will: Option<Message>) -> Result<paho_mqtt::ConnectOptions, paho_mqtt::Error> {
+        let mut options = ConnectOptionsBuilder::new();
+        options.clean_session(true).keep_alive_interval(Duration::from_secs(30)).automatic_reconnect(Duration::from_secs(1), Duration::from_secs(30));
+        if let Some(will) = will {
+            options.will_message(will);
+        }
+        if let Some(username) = &self.username {
+            options.user_name(username);
+        }
+        if let Some(password) = &self.password {
+            options.password(password);
+        }
+        if let Some(tls) = &self.tls {
+            let mut ssl = SslOptionsBuilder::new();
+            ssl.trust_store(&tls.ca_cert)?;
+            if let Some(cert) = &tls.client_cert {
+                ssl.key_store(cert)?;
+            }
+            if let Some(key) = &tls.client_key {
+                ssl.private_key(key)?;
+            }
+            options.ssl_options(ssl.finalize());
+        }
+        Ok(options.finalize())
+    }
+}
+
+//retained connection state of a gateway
+#[derive(Clone, Debug)]
+struct ConnState {
+    topic: String,
+    online: Vec<u8>,
+    offline: Vec<u8>,
+    qos: i32,
+}
+
+//the session is clean, every connection subscribes the topics of the gateways again and publishes their online state
+fn renewal(subscriptions: &[(String, i32)], conn_states: &[ConnState]) -> (Vec<String>, Vec<i32>, Vec<Message>) {
+    let (topics, qos) = subscriptions.iter().cloned().unzip();
+    let online = conn_states.iter().map(|s| Message::new_retained(s.topic.as_str(), s.online.clone(), s.qos)).collect();
+    (topics, qos, online)
+}
+
+//a connection has one last will, it can only stand for a gateway alone on it
+fn last_will(conn_states: &[ConnState]) -> Option<Message> {
+    match conn_states {
+        [s] => Some(Message::new_retained(s.topic.as_str(), s.offline.clone(), s.qos)),
+        _ => None,
+    }
+}
+
+/// Messages of the subscribed topics go to the receiver registered for the topic.
+#[derive(Clone)]
+pub struct SharedMqttClient {
+    client: AsyncClient,
+    config: MqttConfig,
+    routes: Arc<Mutex<HashMap<String, Sender<Message>>>>,
+    subscriptions: Arc<Mutex<Vec<(String, i32)>>>, //topic and QoS of each gateway
+    conn_states: Arc<Mutex<Vec<ConnState>>>,
+}
+
+impl SharedMqttClient {
+    pub fn new(config: &MqttConfig) -> Result<Self, paho_mqtt::Error> {
+        let client_id = config.client_id.clone().unwrap_or_else(|| format!("deloran-simulator-{:08x}", rand::random::<u32>()));
+        let client = AsyncClient::new(CreateOptionsBuilder::new().server_uri(&config.broker_url).client_id(client_id).finalize())?;
+        Ok(SharedMqttClient {
+            client,
+            config: config.clone(),
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            conn_states: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    /// Must be called before `connect`.
+    pub fn subscribe(&self, topic: String, qos: i32) -> Receiver<Message> {
+        let (sender, receiver) = mpsc::channel(1024);
+        self.routes.lock().unwrap().insert(topic.clone(), sender);
+        self.subscriptions.lock().unwrap().push((topic, qos));
+        receiver
+    }
+
+    /// Must be called before `connect`. The online state is published, retained, on every connection and the offline one by `disconnect`.
+    /// A connection serving a single gateway also gets the offline state as last will, for runs that do not end cleanly.
+    pub fn register_conn_state(&self, topic: String, online: Vec<u8>, offline: Vec<u8>, qos: i32) {
+        self.conn_states.lock().unwrap().push(ConnState { topic, online, offline, qos });
+    }
+
+    /// Connects in the background, retrying until the broker answers. Subscriptions are renewed on every reconnection.
+    pub fn connect(&mut self) -> Result<(), paho_mqtt::Error> {
+        let options = self.config.connect_options(last_will(&self.conn_states.lock().unwrap()))?;
+        let stream = self.client.get_stream(1024);
+
+        let subscriptions = self.subscriptions.clone();
+        let conn_states = self.conn_states.clone();
+        let url = self.config.broker_url.clone();
+        self.client.set_connected_callback(move |client| {
+            let (topics, qos, online) = renewal(&subscriptions.lock().unwrap(), &conn_states.lock().unwrap());
+            println!("Connected to the MQTT broker {url}, subscribing to {} topics", topics.len());
+            if !topics.is_empty() {
+                client.subscribe_many(&topics, &qos);
+            }
+            for message in online {
+                client.publish(message);
+            }
+        });
+
+        let client = self.client.clone();
+        let url = self.config.broker_url.clone();
+        tokio::spawn(async move {
+            while let Err(e) = client.connect(options.clone()).await {
+                eprintln!("Could not connect to the MQTT broker {url}: {e}, retrying in 5 seconds");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        let routes = self.routes.clone();
+        let url = self.config.broker_url.clone();
+        tokio::spawn(async move {
+            while let Ok(message) = stream.recv().await {
+                let Some(message) = message else {
+                    eprintln!("Connection to the MQTT broker {url} lost, reconnecting");
+                    continue;
+                };
+                let route = routes.lock().unwrap().get(message.topic()).cloned();
+                match route {
+                    Some(sender) => {
+                        if sender.send(message).await.is_err() {
+                            eprintln!("No gateway listens to MQTT topic anymore");
+                        }
+                    }
+                    None => eprintln!("MQTT message on unexpected topic {}", message.topic()),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Messages published while the broker is unreachable are dropped, like a gateway without backhaul would.
+    pub fn publish(&self, topic: &str, payload: Vec<u8>, qos: i32) -> bool {
+        if !self.client.is_connected() {
+            return false;
+        }
+        self.client.publish(Message::new(topic, payload, qos));
+        true
+    }
+
+    /// Publishes the offline state of every gateway, then closes the connection.
+    pub async fn disconnect(&self) {
+        if !self.client.is_connected() {
+            return;
+        }
+        let conn_states = self.conn_states.lock().unwrap().clone();
+        for state in conn_states {
+            if let Err(e) = self.client.publish(Message::new_retained(state.topic, state.offline, state.qos)).await {
+                eprintln!("Could not publish the offline state to {}: {e}", self.config.broker_url);
+            }
+        }
+        if let Err(e) = self.client.disconnect(None).await {
+            eprintln!("Could not disconnect from the MQTT broker {}: {e}", self.config.broker_url);
+        }
+    }
+}
+
+impl std::fmt::Debug for SharedMqttClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMqttClient").field("broker_url", &self.config.broker_url).finish()
+    }
+}
+
+#[test]
+fn renewed_on_every_connection() {
+    let eu868 = MqttConfig::default();
+    let us915 = MqttConfig { topic_prefix: String::from("us915"), qos: paho_mqtt::QOS_0, ..MqttConfig::default() };
+    //the prefix and the QoS are those of each gateway, the connection is the same
+    assert!(eu868.same_connection(&us915));
+    assert!(!eu868.same_connection(&MqttConfig { client_id: Some(String::from("gateway-2")), ..MqttConfig::default() }));
+
+    let client = SharedMqttClient::new(&eu868).unwrap();
+    let _downlinks = client.subscribe(eu868.down_topic("01"), eu868.qos);
+    client.register_conn_state(eu868.conn_topic("01"), b"online".to_vec(), b"offline".to_vec(), eu868.qos);
+    let will = last_will(&client.conn_states.lock().unwrap()).unwrap();
+    assert_eq!((will.topic(), will.payload(), will.retained()), ("eu868/gateway/01/state/conn", &b"offline"[..], true));
+
+    let _downlinks = client.subscribe(us915.down_topic("02"), us915.qos);
+    client.register_conn_state(us915.conn_topic("02"), b"online".to_vec(), b"offline".to_vec(), us915.qos);
+    assert!(last_will(&client.conn_states.lock().unwrap()).is_none());
+    //the first connection and every reconnection subscribe both gateways again, each with its QoS, and publish their online states
+    for _ in 0..2 {
+        let (topics, qos, online) = renewal(&client.subscriptions.lock().unwrap(), &client.conn_states.lock().unwrap());
+        assert_eq!(topics, ["eu868/gateway/01/command/down", "us915/gateway/02/command/down"]);
+        assert_eq!(qos, [paho_mqtt::QOS_2, paho_mqtt::QOS_0]);
+        assert_eq!(online.iter().map(|m| (m.topic(), m.payload(), m.qos(), m.retained())).collect::<Vec<_>>(), [
+            ("eu868/gateway/01/state/conn", &b"online"[..], paho_mqtt::QOS_2, true),
+            ("us915/gateway/02/state/conn", &b"online"[..], paho_mqtt::QOS_0, true),
+        ]);
+    }
+}
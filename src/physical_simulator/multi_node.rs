@@ -1,178 +1,407 @@
 use std::{
-    cmp::Reverse, collections::{BinaryHeap, HashMap}, mem, ops::DerefMut, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}
+    cmp::Reverse, collections::{BinaryHeap, HashMap}, mem, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}
 };
 
 use lazy_static::lazy_static;
-use lorawan::utils::eui::EUI64;
-use lorawan_device::{
-    communicator::Transmission,
-    split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
+use lorawan::{
+    device::{
+        session_context::{ApplicationSessionContext, NetworkSessionContext, SessionContext},
+        Device, LoRaWANVersion,
+    },
+    utils::eui::EUI64,
 };
+use lorawan_device::split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator};
 use rand::{prelude::Distribution, Rng, SeedableRng};
 use tokio::{
-    sync::Mutex,
+    sync::{Mutex, RwLock},
     time::Instant,
 };
 
 use crate::{
-    logger::Logger, physical_simulator::world::World, traffic_models::UNREGULAR_TRAFFIC_DISTRIBUTION
+    constants::{COLD_START, DEV_NONCES_PATH, FIXED_JOIN_DELAY, NUM_PACKETS, RANDOM_JOIN_DELAY, STARTING_DEV_NONCE},
+    logger::Logger,
+    physical_simulator::world::World,
+    traffic_models::{TrafficModel, TrafficProfile},
 };
 
-use super::node::{Node, NodeReceiver, NodeSender};
+use super::{
+    alarm::AlarmEvent,
+    fleet::{FirmwareProfile, FleetEvent, FleetEventKind},
+    frame::{DataFrame, MType},
+    join::{join_rx_windows, join_tx_params, load_dev_nonces, JoinAccept, JoinBackoff, JoinRequest, JOIN_RX_TIMEOUT},
+    mac::{MacState, SessionKeys},
+    node::{Node, NodeReceiver, NodeSender, TxParams},
+    payload::PayloadProfile,
+    session_store::SessionStore,
+};
 
 lazy_static!(
     static ref ERROR_LOGGER: Logger = Logger::new("./Multinode_log.txt", true, true);
-    static ref SESSIONS: Logger = Logger::new("./node_sessions.txt", true, false);
     static ref RESPONSE_TIMES: Logger = Logger::new("./response_times.csv", true, false);
+    static ref ADR_LOGGER: Logger = Logger::new("./adr_changes.csv", true, false);
+    static ref ATTEMPTS_LOGGER: Logger = Logger::new("./uplink_attempts.csv", true, false);
+    static ref JOINS_LOGGER: Logger = Logger::new("./joins.csv", true, false);
+    static ref DEV_NONCES: Logger = Logger::new(DEV_NONCES_PATH, true, false);
+    static ref FLEET_LOGGER: Logger = Logger::new("./fleet_events.csv", true, false);
+    static ref ALARMS_LOGGER: Logger = Logger::new("./alarms.csv", true, false);
 );
 
+type MacStates = Arc<RwLock<HashMap<EUI64, Arc<Mutex<MacState>>>>>;
+
+#[derive(Debug)]
+enum EventKind {
+    JoinRequest,
+    Uplink {
+        attempt: u8,              //1 for the first transmission
+        payload: Option<Vec<u8>>, //the frame to send again when retransmitting
+        alarm: Option<usize>,     //index of the alarm event reported, outside of the regular traffic
+    },
+    PowerCycle {
+        outage: Duration,
+        rejoin: bool, //the session is dropped whatever the firmware profile
+    },
+}
+
+//a JoinRequest or an uplink waiting in the schedule, uplink frames are only built when sent for the first time
 #[derive(Debug)]
-struct MultiNodeTransmission {
+struct ScheduledEvent {
     dev_eui: EUI64,
-    transmission: Transmission,
+    start_time: u128,
+    generation: u32, //events of a node scheduled before its last power cycle are dropped
+    kind: EventKind,
 }
 
-impl PartialEq for MultiNodeTransmission {
+impl PartialEq for ScheduledEvent {
     fn eq(&self, other: &Self) -> bool {
-        self.transmission == other.transmission
+        self.start_time == other.start_time
     }
 }
 
-impl Eq for MultiNodeTransmission {}
+impl Eq for ScheduledEvent {}
 
-impl PartialOrd for MultiNodeTransmission {
+impl PartialOrd for ScheduledEvent {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for MultiNodeTransmission {
+impl Ord for ScheduledEvent {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.transmission.cmp(&other.transmission)
+        self.start_time.cmp(&other.start_time)
     }
 }
 
+#[derive(Debug)]
+struct NodeTraffic {
+    device: Device,
+    traffic_model: TrafficModel,
+    sent: usize,
+    next_start: u128,
+    join_backoff: JoinBackoff,
+    profile: FirmwareProfile,
+    payload: PayloadProfile,
+    generation: u32,
+    last_join_nonce: Option<u32>, //1.1 devices refuse JoinAccepts that do not increase it
+    busy: bool,                   //waiting for the outcome of a frame, the next one has to wait
+}
+
+//an uplink that just left a node
+struct UplinkAttempt {
+    attempt: u8,
+    payload: Vec<u8>,
+    rx_timeout: Duration,
+    alarm: Option<usize>,
+}
+
+//what a node needs to read the JoinAccept answering its JoinRequest
+struct JoinAttempt {
+    request: JoinRequest,
+    nwk_key: [u8; 16],
+    app_key: [u8; 16],
+    lorawan_1_1: bool,
+    last_join_nonce: Option<u32>,
+    retry_at: u128,
+}
+
+//what the receiving side needs to know about a frame that just left a node
+enum Sent {
+    Uplink { dev_eui: EUI64, generation: u32, uplink: UplinkAttempt },
+    JoinRequest { dev_eui: EUI64, generation: u32, attempt: JoinAttempt },
+}
+
+//sent back by the receiving side once the outcome of a frame is known
+struct Feedback {
+    dev_eui: EUI64,
+    generation: u32,
+    outcome: Outcome,
+}
+
+enum Outcome {
+    Retry(ScheduledEvent),
+    Done { alarm: bool },
+    Joined(Box<SessionContext>, u32, Box<MacState>), //with the JoinNonce and the MAC state of the new session
+    JoinFailed(u128),
+}
+
 #[derive(Debug, Default)]
-pub struct MultiNode {
-    nodes: Vec<(Node, Duration)>,
-    senders_map: HashMap<EUI64, NodeSender>,
-    receivers_map: HashMap<EUI64, Arc<NodeReceiver>>,
-    transmissions: BinaryHeap<Reverse<MultiNodeTransmission>>,
+struct Schedule {
+    traffic: HashMap<EUI64, NodeTraffic>,
+    queue: BinaryHeap<Reverse<ScheduledEvent>>,
+    in_flight: usize, //frames sent and waiting for their outcome
+    session_store: Option<SessionStore>,
+    traffic_profile: Option<TrafficProfile>, //time-of-day modulation of every node's rate
+    alarm_payloads: Vec<Option<PayloadProfile>>, //of each alarm event
 }
 
-impl MultiNode {
-    pub fn add_node(&mut self, node: Node, duration: Duration) {
-        self.nodes.push((node, duration));
+impl Schedule {
+    #[allow(clippy::too_many_arguments)]
+    fn add_node<R: Rng + ?Sized>(&mut self, dev_eui: EUI64, device: Device, traffic_model: TrafficModel, profile: FirmwareProfile, payload: PayloadProfile, join_at: Option<u128>, rng: &mut R) {
+        let next_start = World::now() + Self::next_delay(World::now(), &traffic_model, self.traffic_profile.as_ref(), rng).as_millis();
+        let join_backoff = JoinBackoff::default();
+        self.traffic.insert(dev_eui, NodeTraffic { device, traffic_model, sent: 0, next_start, join_backoff, profile, payload, generation: 0, last_join_nonce: None, busy: false });
+        match join_at {
+            Some(start_time) => self.schedule_join(dev_eui, start_time),
+            None => self.schedule_next(dev_eui, rng),
+        }
     }
 
-    pub async fn prepare(&mut self) {
-        //self.join_devices().await;
-        //println!("Joined all devices!!");
-        self.prepare_transmissions().await;
-        //println!("Prepared all the transmissions!!");
-        let nodes = mem::take(&mut self.nodes);
-        for (node, _) in nodes.into_iter() {
-            let dev_eui = *node.dev_eui();
-            let (sender, receiver) = node.into_device().into_communicator().split_communicator().await.unwrap();
-            self.senders_map.insert(dev_eui, sender);
-            self.receivers_map.insert(dev_eui, Arc::new(receiver));
+    fn schedule_join(&mut self, dev_eui: EUI64, start_time: u128) {
+        let generation = self.traffic[&dev_eui].generation;
+        self.queue.push(Reverse(ScheduledEvent { dev_eui, start_time, generation, kind: EventKind::JoinRequest }));
+    }
+
+    //hands the current device state to the session store, with the downlink counter tracked by the MAC layer
+    fn persist(&mut self, dev_eui: EUI64, n_f_cnt_down: Option<u32>) {
+        let Some(store) = &self.session_store else { return };
+        let device = &mut self.traffic.get_mut(&dev_eui).unwrap().device;
+        if let (Some(n_f_cnt_down), Some(session)) = (n_f_cnt_down, device.session_mut()) {
+            session.network_context_mut().update_nf_cnt_dwn(n_f_cnt_down);
+        }
+        store.update(device);
+    }
+
+    //power cycles are never stale, they are what makes the other events stale
+    fn is_stale(&self, event: &ScheduledEvent) -> bool {
+        !matches!(event.kind, EventKind::PowerCycle { .. }) && event.generation != self.traffic[&event.dev_eui].generation
+    }
+
+    fn next_delay<R: Rng + ?Sized>(from: u128, traffic_model: &TrafficModel, traffic_profile: Option<&TrafficProfile>, rng: &mut R) -> Duration {
+        let mut delay = traffic_model.sample(rng);
+        if let Some(traffic_profile) = traffic_profile {
+            delay = traffic_profile.stretch(from as f64 / 1000.0, delay);
         }
+        Duration::from_secs_f64(delay)
     }
 
-    pub async fn join_devices(&mut self) {
-        let mut nodes = mem::take(&mut self.nodes)
-            .into_iter()
-            .map(|v| Arc::new(Mutex::new(v)))
-            .collect::<Vec<_>>();
-
-        for (i, el) in nodes.iter_mut().enumerate() {
-            let cloned = el.clone();
-            tokio::spawn(async move {
-                tokio::time::sleep(Duration::from_millis(251 * i as u64)).await;
-                let mut mutex_lock = cloned.lock().await;
-                let (node, _duration) = mutex_lock.deref_mut();
-                match  node.join(Some(10), Some(Duration::from_secs(1))).await {
-                    Ok(_) => {
-                        SESSIONS.write(&serde_json::to_string(&*node.device).unwrap());
-                    },
-                    Err(e) => ERROR_LOGGER.write(&format!("Device {i} failed to join: {e:?}")),
+    //a node has a single uplink in the queue at any time, the next one is added when the previous one is over
+    fn schedule_next<R: Rng + ?Sized>(&mut self, dev_eui: EUI64, rng: &mut R) {
+        let traffic = self.traffic.get_mut(&dev_eui).unwrap();
+        if traffic.sent >= NUM_PACKETS {
+            return;
+        }
+        let start_time = traffic.next_start.max(World::now());
+        let kind = EventKind::Uplink { attempt: 1, payload: None, alarm: None };
+        self.queue.push(Reverse(ScheduledEvent { dev_eui, start_time, generation: traffic.generation, kind }));
+        traffic.next_start = start_time + Self::next_delay(start_time, &traffic.traffic_model, self.traffic_profile.as_ref(), rng).as_millis();
+    }
+
+    /// Returns the MAC state a join leaves the node with, to be installed in place of the previous one.
+    fn on_feedback<R: Rng + ?Sized>(&mut self, feedback: Feedback, rng: &mut R) -> Option<MacState> {
+        let dev_eui = feedback.dev_eui;
+        //the node rebooted while the frame was in flight, nothing follows from its outcome
+        if feedback.generation != self.traffic[&dev_eui].generation {
+            self.in_flight -= 1;
+            return None;
+        }
+        //a retransmission keeps the node busy, any other outcome ends the exchange
+        if !matches!(feedback.outcome, Outcome::Retry(_)) {
+            self.traffic.get_mut(&dev_eui).unwrap().busy = false;
+        }
+        match feedback.outcome {
+            Outcome::Retry(event) => self.queue.push(Reverse(event)),
+            Outcome::Done { alarm } => {
+                self.in_flight -= 1;
+                //alarms are extra uplinks, the regular traffic goes on from its own schedule
+                if !alarm {
+                    self.schedule_next(dev_eui, rng);
                 }
-            });
+            }
+            Outcome::Joined(session, join_nonce, mac) => {
+                self.in_flight -= 1;
+                let traffic = self.traffic.get_mut(&dev_eui).unwrap();
+                traffic.device.set_activation_abp(*session);
+                traffic.last_join_nonce = Some(join_nonce);
+                traffic.next_start = World::now() + Self::next_delay(World::now(), &traffic.traffic_model, self.traffic_profile.as_ref(), rng).as_millis();
+                JOINS_LOGGER.write(&format!("{},{},{}", World::now(), dev_eui, traffic.join_backoff.attempts));
+                self.persist(dev_eui, None);
+                self.schedule_next(dev_eui, rng);
+                return Some(*mac);
+            }
+            Outcome::JoinFailed(start_time) => {
+                self.in_flight -= 1;
+                self.schedule_join(dev_eui, start_time);
+            }
+        }
+        None
+    }
 
-            // Limit to 100 concurrent tasks
-            //if (i + 1) % 500 == 0 {
-            //    tokio::time::sleep(Duration::from_secs(30)).await
-            //}
+    //the next uplink of a node, built when it is sent so that it carries the current FCnt and MAC state
+    fn build_uplink<R: Rng + ?Sized>(&mut self, dev_eui: EUI64, mac: &mut MacState, alarm: Option<usize>, rng: &mut R) -> Result<(Vec<u8>, TxParams), String> {
+        let node = self.traffic.get_mut(&dev_eui).unwrap();
+        //alarms are not part of the regular uplinks of a node, they do not count in them
+        let (profile, index) = match alarm {
+            Some(alarm) => (self.alarm_payloads[alarm].as_ref().unwrap_or(&node.payload), node.sent),
+            None => {
+                node.sent += 1;
+                (&node.payload, node.sent - 1)
+            }
+        };
+        let message = profile.generator.payload(index, rng);
+        match node.device.create_uplink(Some(&message), profile.confirmed(rng), Some(profile.f_port), None) {
+            Ok(payload) => mac.prepare_uplink(&payload, rng).map_err(|e| format!("{e:?}")),
+            Err(e) => Err(format!("{e:?}")),
         }
-        //println!("BEFORE 30S SLEEP");
-        // Wait for any remaining tasks
-        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
 
-        //println!("AFTER 30S SLEEP");
-        for node in nodes.into_iter() {
-            while Arc::strong_count(&node) > 1 {
-                tokio::time::sleep(Duration::from_secs(1)).await;
+    //an uplink that could not be built, a retransmission ends undelivered the exchange opened by its first attempt
+    fn uplink_failed<R: Rng + ?Sized>(&mut self, dev_eui: EUI64, generation: u32, attempt: u8, f_cnt: u16, alarm: Option<usize>, rng: &mut R) {
+        if attempt == 1 {
+            if alarm.is_none() {
+                self.schedule_next(dev_eui, rng);
             }
-            self.nodes.push(Arc::into_inner(node).unwrap().into_inner())
+            return;
+        }
+        ATTEMPTS_LOGGER.write(&format!("{},{},{},{},{}", World::now(), dev_eui, f_cnt, attempt - 1, false));
+        if let Some(alarm) = alarm {
+            ALARMS_LOGGER.write(&format!("{},{},{},{},{}", World::now(), dev_eui, alarm, attempt - 1, false));
+        }
+        self.on_feedback(Feedback { dev_eui, generation, outcome: Outcome::Done { alarm: alarm.is_some() } }, rng);
+    }
+
+    /// Turns a node off for `outage` and back on: what it remembers depends on its firmware profile.
+    /// Returns whether it kept its session, otherwise it joins again from a reset join backoff.
+    fn power_cycle<R: Rng + ?Sized>(&mut self, dev_eui: EUI64, outage: Duration, rejoin: bool, rng: &mut R) -> bool {
+        let traffic = self.traffic.get_mut(&dev_eui).unwrap();
+        traffic.generation += 1;
+        traffic.busy = false;
+        traffic.join_backoff = JoinBackoff::default();
+        let profile = traffic.profile;
+        //the last JoinNonce is kept in the same non volatile memory as the DevNonce
+        if !profile.keeps_dev_nonce {
+            traffic.last_join_nonce = None;
+            traffic.device.set_dev_nonce(STARTING_DEV_NONCE);
+            self.persist(dev_eui, None);
+        }
+        let traffic = self.traffic.get_mut(&dev_eui).unwrap();
+
+        let keeps_session = profile.keeps_session && !rejoin && traffic.device.session().is_some();
+        let start_time = World::now() + (outage + profile.first_frame_delay(rng)).as_millis();
+        FLEET_LOGGER.write(&format!("{},{},{},{}", World::now(), dev_eui, if rejoin { "rejoin" } else { "reboot" }, keeps_session));
+        if keeps_session {
+            traffic.next_start = start_time;
+            self.schedule_next(dev_eui, rng);
+        } else {
+            self.schedule_join(dev_eui, start_time);
         }
-        //println!("END OF JOINING PROCESSES")
+        keeps_session
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MultiNode {
+    nodes: Vec<Node>,
+    senders_map: HashMap<EUI64, NodeSender>,
+    receivers_map: HashMap<EUI64, Arc<NodeReceiver>>,
+    mac_states: MacStates,
+    schedule: Schedule,
+    fleet_events: Vec<FleetEvent>,
+    alarm_events: Vec<AlarmEvent>,
+    firmware_profiles: HashMap<EUI64, FirmwareProfile>,
+    default_firmware_profile: FirmwareProfile,
+    payload_profiles: HashMap<EUI64, PayloadProfile>,
+    default_payload_profile: PayloadProfile,
+}
+
+impl MultiNode {
+    pub fn add_node(&mut self, node: Node) {
+        self.nodes.push(node);
+    }
+
+    pub fn add_fleet_event(&mut self, event: FleetEvent) {
+        self.fleet_events.push(event);
+    }
+
+    pub fn add_alarm_event(&mut self, event: AlarmEvent) {
+        self.schedule.alarm_payloads.push(event.payload.clone());
+        self.alarm_events.push(event);
+    }
+
+    pub fn set_firmware_profile(&mut self, dev_eui: EUI64, profile: FirmwareProfile) {
+        self.firmware_profiles.insert(dev_eui, profile);
+    }
+
+    pub fn set_default_firmware_profile(&mut self, profile: FirmwareProfile) {
+        self.default_firmware_profile = profile;
+    }
+
+    pub fn set_payload_profile(&mut self, dev_eui: EUI64, profile: PayloadProfile) {
+        self.payload_profiles.insert(dev_eui, profile);
+    }
+
+    pub fn set_default_payload_profile(&mut self, profile: PayloadProfile) {
+        self.default_payload_profile = profile;
     }
 
-    pub async fn prepare_transmissions(&mut self) {
+    pub fn set_session_store(&mut self, store: SessionStore) {
+        self.schedule.session_store = Some(store);
+    }
+
+    pub fn set_traffic_profile(&mut self, profile: TrafficProfile) {
+        self.schedule.traffic_profile = Some(profile);
+    }
+
+    pub async fn prepare(&mut self) {
         let mut rng = rand::rngs::StdRng::from_entropy();
-        for (node, node_delay) in &mut self.nodes {
-            let trans_power = node.communicator().get_config().transmission_power_dbm;
-            let position = node.communicator().get_config().position;
-            let radio_config = node.communicator().get_config().radio_config;
-
-            //node.session_mut().expect("Session should be there thanks to node_sessions.txt").network_context_mut().update_f_cnt_up(STARTING_FCNT_UP);
-
-            let mut start = SystemTime::now();
-            //Sstart += Duration::from_secs_f64(rng.gen_range(0..600) as f64); // Random start time
-
-            for i in 0..100 {
-                let payload = node
-                    .create_uplink(
-                        Some(format!("###  confirmed {i} message  ###").into_bytes()).as_deref(),
-                        true,
-                        Some(1),
-                        None,
-                    )
-                    .unwrap();
-
-                //let complete_delay = Duration::from_secs(rng.gen_range(FIXED_PACKET_DELAY..RANDOM_PACKET_DELAY));
-                let complete_delay = if node_delay.is_zero() {
-                    let mut delay = Duration::ZERO;
-                    while delay.as_secs() < 30 {
-                        delay = Duration::from_secs_f64(UNREGULAR_TRAFFIC_DISTRIBUTION.sample(&mut rng))
-                    }
-                    delay
-                } else {
-                    *node_delay
-                };
-                let random_delay = Duration::from_secs(rng.gen_range(0..5));
-
-                let transmission = Transmission {
-                    start_position: position,
-                    start_time: (start + complete_delay + random_delay)
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis(),
-                    frequency: radio_config.freq,
-                    bandwidth: radio_config.bandwidth,
-                    spreading_factor: radio_config.spreading_factor,
-                    code_rate: radio_config.code_rate,
-                    starting_power: trans_power,
-                    uplink: true,
-                    payload,
-                };
+        let start = World::now();
+        //DevNonces must never be reused, so they are carried over from the previous runs
+        let dev_nonces = load_dev_nonces(DEV_NONCES_PATH);
+        let nodes = mem::take(&mut self.nodes);
+        let affected = self.fleet_events.iter().map(|e| e.affected(nodes.len(), &mut rng)).collect::<Vec<_>>();
+        for (i, mut node) in nodes.into_iter().enumerate() {
+            let dev_eui = *node.dev_eui();
+            if let Some(dev_nonce) = dev_nonces.get(&dev_eui.to_string()) {
+                let dev_nonce = node.dev_nonce().max(*dev_nonce);
+                node.set_dev_nonce(dev_nonce);
+            }
 
-                start += complete_delay + random_delay;
-                self.transmissions.push(Reverse(MultiNodeTransmission {
-                    dev_eui: *node.dev_eui(),
-                    transmission,
-                }));
+            let config = node.communicator().get_config();
+            let mac = if COLD_START { None } else { MacState::new(&node.device, &config.radio_config, config.transmission_power_dbm) };
+            let join_at = match mac {
+                Some(mac) => {
+                    self.mac_states.write().await.insert(dev_eui, Arc::new(Mutex::new(mac)));
+                    None
+                }
+                //without a session the node joins first, at a random time so that a cold start is not a burst
+                None => Some(World::now() + rng.gen_range(FIXED_JOIN_DELAY * 1000..RANDOM_JOIN_DELAY * 1000) as u128),
+            };
+            let profile = self.firmware_profiles.get(&dev_eui).copied().unwrap_or(self.default_firmware_profile);
+            let payload = self.payload_profiles.get(&dev_eui).unwrap_or(&self.default_payload_profile).clone();
+            self.schedule.add_node(dev_eui, Device::clone(&node.device), node.traffic_model.clone(), profile, payload, join_at, &mut rng);
+            for (event, _) in self.fleet_events.iter().zip(&affected).filter(|(_, affected)| affected.contains(&i)) {
+                let kind = EventKind::PowerCycle { outage: event.outage, rejoin: event.kind == FleetEventKind::Rejoin };
+                let start_time = start + event.at.as_millis();
+                self.schedule.queue.push(Reverse(ScheduledEvent { dev_eui, start_time, generation: 0, kind }));
+            }
+            for (alarm, event) in self.alarm_events.iter().enumerate() {
+                if let Some(at) = event.alarm_time(&config.position, &mut rng) {
+                    let kind = EventKind::Uplink { attempt: 1, payload: None, alarm: Some(alarm) };
+                    self.schedule.queue.push(Reverse(ScheduledEvent { dev_eui, start_time: start + at.as_millis(), generation: 0, kind }));
+                }
             }
+
+            let (sender, receiver) = node.into_device().into_communicator().split_communicator().await.unwrap();
+            self.senders_map.insert(dev_eui, sender);
+            self.receivers_map.insert(dev_eui, Arc::new(receiver));
         }
     }
 
@@ -187,44 +416,192 @@ impl MultiNode {
         unix_epoch + duration_since_epoch
     }
 
-    pub async fn run(mut self) {
+    //the MAC state of a join is only installed if the node did not reboot while joining
+    async fn apply_feedback(schedule: &mut Schedule, mac_states: &MacStates, feedback: Feedback, rng: &mut rand::rngs::StdRng) {
+        let dev_eui = feedback.dev_eui;
+        if let Some(mac) = schedule.on_feedback(feedback, rng) {
+            mac_states.write().await.insert(dev_eui, Arc::new(Mutex::new(mac)));
+        }
+    }
+
+    pub async fn run(self) {
         // println!("MULTIDEVICE IS RUNNING!!");
-        let (sender, mut receiver) = tokio::sync::mpsc::channel::<EUI64>(1000);
+        let MultiNode { senders_map, receivers_map, mac_states, mut schedule, .. } = self;
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Sent>(1000);
+        let (feedback_sender, mut feedback_receiver) = tokio::sync::mpsc::channel::<Feedback>(1000);
+        let send_mac_states = mac_states.clone();
         let t_send = tokio::task::spawn(async move {
+            let mut rng = rand::rngs::StdRng::from_entropy();
             loop {
-                if let Some(transmission) = self.transmissions.pop() {
-                    let transmission = transmission.0;
-                    let lora_sender = self.senders_map.get_mut(&transmission.dev_eui).unwrap();
-                    if World::now() < transmission.transmission.start_time {
-                        tokio::time::sleep_until(Self::instant_from_u128(transmission.transmission.start_time,)).await;
+                while let Ok(feedback) = feedback_receiver.try_recv() {
+                    Self::apply_feedback(&mut schedule, &send_mac_states, feedback, &mut rng).await;
+                }
+                let Some(Reverse(next)) = schedule.queue.peek() else {
+                    if schedule.in_flight == 0 {
+                        break;
+                    }
+                    if let Some(feedback) = feedback_receiver.recv().await {
+                        Self::apply_feedback(&mut schedule, &send_mac_states, feedback, &mut rng).await;
+                    }
+                    continue;
+                };
+                if World::now() < next.start_time {
+                    //a retransmission may have to go out before the next scheduled uplink
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(Self::instant_from_u128(next.start_time)) => (),
+                        Some(feedback) = feedback_receiver.recv() => {
+                            Self::apply_feedback(&mut schedule, &send_mac_states, feedback, &mut rng).await;
+                            continue;
+                        }
+                    }
+                }
+
+                let mut event = schedule.queue.pop().unwrap().0;
+                let dev_eui = event.dev_eui;
+                //alarms come from the environment, whatever happened to the node before
+                if matches!(event.kind, EventKind::Uplink { attempt: 1, alarm: Some(_), .. }) {
+                    event.generation = schedule.traffic[&dev_eui].generation;
+                }
+                let generation = event.generation;
+                let lora_sender = senders_map.get(&dev_eui).unwrap();
+                if schedule.is_stale(&event) {
+                    //retransmissions waiting in the queue were still counted as in flight
+                    if matches!(event.kind, EventKind::Uplink { attempt, .. } if attempt > 1) {
+                        schedule.in_flight -= 1;
+                    }
+                    continue;
+                }
+
+                let (attempt, payload, alarm) = match &mut event.kind {
+                    EventKind::Uplink { attempt, payload, alarm } => (*attempt, payload.take(), *alarm),
+                    EventKind::PowerCycle { outage, rejoin } => {
+                        //the MAC layer restarts from its defaults, or waits for the next JoinAccept
+                        let keeps_session = schedule.power_cycle(dev_eui, *outage, *rejoin, &mut rng);
+                        let mac = keeps_session.then(|| {
+                            let config = lora_sender.config();
+                            MacState::new(&schedule.traffic[&dev_eui].device, &config.radio_config, config.transmission_power_dbm)
+                        });
+                        match mac.flatten() {
+                            Some(mac) => send_mac_states.write().await.insert(dev_eui, Arc::new(Mutex::new(mac))),
+                            None => send_mac_states.write().await.remove(&dev_eui),
+                        };
+                        continue;
                     }
-                    lora_sender
-                        .send(&transmission.transmission.payload, None)
-                        .await
-                        .unwrap();
-                    sender.send(transmission.dev_eui).await.unwrap();
+                    EventKind::JoinRequest => {
+                        let node = schedule.traffic.get_mut(&dev_eui).unwrap();
+                        let dev_nonce = node.device.dev_nonce();
+                        let Ok(dev_nonce) = u16::try_from(dev_nonce) else {
+                            ERROR_LOGGER.write(&format!("Device {dev_eui} ran out of DevNonces and can not join anymore"));
+                            continue;
+                        };
+                        node.device.set_dev_nonce(dev_nonce as u32 + 1);
+                        DEV_NONCES.write(&format!("{},{}", dev_eui, dev_nonce as u32 + 1));
+
+                        let request = JoinRequest { join_eui: *node.device.join_eui(), dev_eui, dev_nonce };
+                        let nwk_key = **node.device.nwk_key();
+                        let payload = request.to_bytes(&nwk_key);
+                        let tx_params = join_tx_params(lora_sender.config(), &mut rng);
+                        lora_sender.config().set_rx_windows(join_rx_windows(&tx_params)).await;
+                        let time_on_air = lora_sender.config().uplink_transmission(&payload, Some(tx_params)).time_on_air();
+                        lora_sender.send(&payload, Some(tx_params)).await.unwrap();
+
+                        let attempt = JoinAttempt {
+                            request,
+                            nwk_key,
+                            app_key: **node.device.app_key(),
+                            lorawan_1_1: *node.device.version() == LoRaWANVersion::V1_1,
+                            last_join_nonce: node.last_join_nonce,
+                            retry_at: node.join_backoff.next_attempt(World::now(), time_on_air, &mut rng),
+                        };
+                        node.busy = true;
+                        schedule.persist(dev_eui, None);
+                        schedule.in_flight += 1;
+                        sender.send(Sent::JoinRequest { dev_eui, generation, attempt }).await.unwrap();
+                        continue;
+                    }
+                };
+
+                //a node sends one frame at a time, an alarm may come while it waits for the outcome of another one
+                if attempt == 1 && schedule.traffic[&dev_eui].busy {
+                    event.start_time = World::now() + 1000;
+                    event.kind = EventKind::Uplink { attempt, payload, alarm };
+                    schedule.queue.push(Reverse(event));
+                    continue;
+                }
+                //regular uplinks are only scheduled with a session, alarms may find a node still joining
+                let Some(mac) = send_mac_states.read().await.get(&dev_eui).cloned() else {
+                    ERROR_LOGGER.write(&format!("Device {dev_eui} has no session, alarm {alarm:?} dropped"));
+                    continue;
+                };
+                let mut mac = mac.lock().await;
+
+                //DutyCycleReq off-time not elapsed yet, the uplink is postponed
+                if World::now() < mac.off_until() {
+                    event.start_time = mac.off_until();
+                    event.kind = EventKind::Uplink { attempt, payload, alarm };
+                    schedule.queue.push(Reverse(event));
+                    continue;
+                }
+
+                let prepared = match &payload {
+                    Some(payload) => mac.prepare_retransmission(payload, attempt, &mut rng).map_err(|e| format!("{e:?}")),
+                    None => schedule.build_uplink(dev_eui, &mut mac, alarm, &mut rng),
+                };
+                let (payload, tx_params) = match prepared {
+                    Ok(prepared) if attempt == 1 => {
+                        schedule.persist(dev_eui, mac.n_f_cnt_down());
+                        prepared
+                    }
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        ERROR_LOGGER.write(&format!("Device {dev_eui} uplink could not be prepared: {e}"));
+                        let f_cnt = payload.as_deref().and_then(|p| DataFrame::parse(p).ok()).map_or(0, |f| f.f_cnt);
+                        schedule.uplink_failed(dev_eui, generation, attempt, f_cnt, alarm, &mut rng);
+                        continue;
+                    }
+                };
+
+                lora_sender.config().set_rx_windows(mac.rx_windows(&tx_params)).await;
+                let time_on_air = lora_sender.config().uplink_transmission(&payload, Some(tx_params)).time_on_air();
+                lora_sender.send(&payload, Some(tx_params)).await.unwrap();
+                mac.on_uplink_sent(World::now() + time_on_air, time_on_air);
+                let rx_timeout = mac.rx_timeout();
+                drop(mac);
+
+                if attempt == 1 {
+                    schedule.in_flight += 1;
+                    schedule.traffic.get_mut(&dev_eui).unwrap().busy = true;
                 }
+                let uplink = UplinkAttempt { attempt, payload, rx_timeout, alarm };
+                sender.send(Sent::Uplink { dev_eui, generation, uplink }).await.unwrap();
             }
         });
 
+        let recv_mac_states = mac_states.clone();
         let t_recv = tokio::task::spawn(async move {
-            while let Some(dev_eui) = receiver.recv().await {
-                let lora_receiver = self.receivers_map.get_mut(&dev_eui).unwrap().clone();
+            while let Some(sent) = receiver.recv().await {
+                let dev_eui = match &sent {
+                    Sent::Uplink { dev_eui, .. } | Sent::JoinRequest { dev_eui, .. } => *dev_eui,
+                };
+                let lora_receiver = receivers_map.get(&dev_eui).unwrap().clone();
+                let mac_states = recv_mac_states.clone();
+                let feedback_sender = feedback_sender.clone();
                 tokio::spawn(async move {
-                    let now = Instant::now();
-                    match lora_receiver.receive(Some(Duration::from_secs(2))).await {
-                        Ok(_received) => {
-                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                            RESPONSE_TIMES.write(&format!("{},{}", timestamp, now.elapsed().as_millis()));
-                            //println!("Device {dev_eui} received {:?}", received);
+                    let feedback = match sent {
+                        Sent::Uplink { dev_eui, generation, uplink } => {
+                            //a node that rebooted without its session has no MAC state until it joins again
+                            let outcome = match mac_states.read().await.get(&dev_eui).cloned() {
+                                Some(mac) => Self::receive_downlink(&lora_receiver, &mac, dev_eui, generation, uplink).await,
+                                None => Outcome::Done { alarm: uplink.alarm.is_some() },
+                            };
+                            Feedback { dev_eui, generation, outcome }
                         }
-                        Err(e) => {
-                            ERROR_LOGGER.write(&format!("Device {dev_eui} didnt receive an answer: {:?} #########################",e));
-                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-                            RESPONSE_TIMES.write(&format!("{},{}", timestamp, now.elapsed().as_millis()));
-                            //eprintln!("########################### Device {dev_eui} didnt receive an answer: {:?} #########################",e);
+                        Sent::JoinRequest { dev_eui, generation, attempt } => {
+                            let outcome = Self::receive_join_accept(&lora_receiver, dev_eui, attempt).await;
+                            Feedback { dev_eui, generation, outcome }
                         }
-                    }
+                    };
+                    feedback_sender.send(feedback).await.unwrap();
                 });
             }
         });
@@ -233,4 +610,272 @@ impl MultiNode {
         r1.unwrap();
         r2.unwrap();
     }
+
+    async fn receive_downlink(lora_receiver: &NodeReceiver, mac: &Mutex<MacState>, dev_eui: EUI64, generation: u32, uplink: UplinkAttempt) -> Outcome {
+        let UplinkAttempt { attempt, payload, rx_timeout, alarm } = uplink;
+        let now = Instant::now();
+        let confirmed = MType::from_mhdr(payload[0]) == MType::ConfirmedDataUp;
+        //unconfirmed uplinks are repeated NbTrans times unless a downlink shows they got through
+        let mut delivered = false;
+        match lora_receiver.receive(Some(rx_timeout)).await {
+            Ok(received) => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                RESPONSE_TIMES.write(&format!("{},{}", timestamp, now.elapsed().as_millis()));
+                //println!("Device {dev_eui} received {:?}", received);
+                let mut mac = mac.lock().await;
+                for r in received.iter() {
+                    let before = (mac.data_rate(), mac.tx_power_dbm(), mac.ch_mask(), mac.nb_trans());
+                    match mac.handle_downlink(r) {
+                        Ok(info) => delivered |= info.ack || !confirmed,
+                        Err(e) => ERROR_LOGGER.write(&format!("Device {dev_eui} discarded a downlink: {e:?}")),
+                    }
+                    let after = (mac.data_rate(), mac.tx_power_dbm(), mac.ch_mask(), mac.nb_trans());
+                    if before != after {
+                        ADR_LOGGER.write(&format!("{},{},{},{},{:04x},{}", timestamp, dev_eui, after.0, after.1, after.2, after.3));
+                    }
+                }
+            }
+            Err(e) => {
+                ERROR_LOGGER.write(&format!("Device {dev_eui} didnt receive an answer: {:?} #########################",e));
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+                RESPONSE_TIMES.write(&format!("{},{}", timestamp, now.elapsed().as_millis()));
+                //eprintln!("########################### Device {dev_eui} didnt receive an answer: {:?} #########################",e);
+            }
+        }
+
+        let (nb_trans, backoff) = {
+            let mac = mac.lock().await;
+            (mac.nb_trans(), mac.retransmission_backoff(&mut rand::thread_rng()))
+        };
+        if !delivered && attempt < nb_trans {
+            Outcome::Retry(ScheduledEvent {
+                dev_eui,
+                start_time: World::now() + backoff.as_millis(),
+                generation,
+                kind: EventKind::Uplink { attempt: attempt + 1, payload: Some(payload), alarm },
+            })
+        } else {
+            let f_cnt = DataFrame::parse(&payload).map(|f| f.f_cnt).unwrap_or_default();
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            ATTEMPTS_LOGGER.write(&format!("{},{},{},{},{}", timestamp, dev_eui, f_cnt, attempt, delivered));
+            if let Some(alarm) = alarm {
+                ALARMS_LOGGER.write(&format!("{},{},{},{},{}", timestamp, dev_eui, alarm, attempt, delivered));
+            }
+            Outcome::Done { alarm: alarm.is_some() }
+        }
+    }
+
+    async fn receive_join_accept(lora_receiver: &NodeReceiver, dev_eui: EUI64, attempt: JoinAttempt) -> Outcome {
+        let JoinAttempt { request, nwk_key, app_key, lorawan_1_1, last_join_nonce, retry_at } = attempt;
+        let received = lora_receiver.receive(Some(JOIN_RX_TIMEOUT)).await.unwrap_or_default();
+        let Some(accept) = received.iter().find_map(|r| JoinAccept::decrypt(&r.transmission.payload, &nwk_key, &request, lorawan_1_1).ok()) else {
+            ERROR_LOGGER.write(&format!("Device {dev_eui} got no JoinAccept for DevNonce {}", request.dev_nonce));
+            return Outcome::JoinFailed(retry_at);
+        };
+        //LoRaWAN 1.1 section 6.2.3, a replayed JoinAccept is discarded
+        if accept.opt_neg && last_join_nonce.is_some_and(|n| accept.join_nonce <= n) {
+            ERROR_LOGGER.write(&format!("Device {dev_eui} discarded a JoinAccept with JoinNonce {} not above {}", accept.join_nonce, last_join_nonce.unwrap()));
+            return Outcome::JoinFailed(retry_at);
+        }
+
+        let derived = accept.session_keys(&nwk_key, &app_key, &request);
+        let keys = SessionKeys {
+            dev_addr: accept.dev_addr,
+            f_nwk_s_int_key: derived.f_nwk_s_int_key,
+            s_nwk_s_int_key: derived.s_nwk_s_int_key,
+            nwk_s_enc_key: derived.nwk_s_enc_key,
+            lorawan_1_1: accept.opt_neg,
+        };
+        let config = lora_receiver.config();
+        let mut mac = MacState::from_keys(keys, 0, &config.radio_config, config.transmission_power_dbm);
+        mac.apply_join_accept(&accept);
+
+        let network_session = NetworkSessionContext::new(
+            derived.f_nwk_s_int_key.into(),
+            derived.s_nwk_s_int_key.into(),
+            derived.nwk_s_enc_key.into(),
+            accept.net_id,
+            accept.dev_addr,
+            0,
+            0,
+            0,
+        );
+        let application_session = ApplicationSessionContext::new(derived.app_s_key.into(), 0);
+        Outcome::Joined(Box::new(SessionContext::new(application_session, network_session)), accept.join_nonce, Box::new(mac))
+    }
+}
+
+#[test]
+fn failed_retransmission_ends_the_exchange() {
+    use crate::device_source::generate_device;
+
+    let mut rng = <rand::rngs::StdRng as SeedableRng>::seed_from_u64(0);
+    let mut schedule = Schedule::default();
+    let device = generate_device(0.0, &mut rng);
+    let dev_eui = *device.dev_eui();
+    schedule.add_node(dev_eui, device, TrafficModel::Periodic(60.0), FirmwareProfile::default(), PayloadProfile::default(), None, &mut rng);
+
+    //the first attempt went out and is waiting for its outcome, then its retransmission can not be built
+    let first = schedule.queue.pop().unwrap().0;
+    schedule.in_flight += 1;
+    schedule.traffic.get_mut(&dev_eui).unwrap().busy = true;
+    schedule.uplink_failed(dev_eui, first.generation, 2, 0, None, &mut rng);
+    assert_eq!(schedule.in_flight, 0);
+    assert!(!schedule.traffic[&dev_eui].busy);
+    let next = schedule.queue.pop().unwrap().0;
+    assert!(matches!(next.kind, EventKind::Uplink { attempt: 1, payload: None, alarm: None }));
+    assert!(next.start_time > first.start_time);
+
+    //a first attempt opened nothing, the node just moves on to its next uplink
+    schedule.uplink_failed(dev_eui, next.generation, 1, 0, None, &mut rng);
+    assert_eq!((schedule.in_flight, schedule.queue.len()), (0, 1));
+}
+
+#[test]
+fn uplinks_built_when_sent() {
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::Position;
+
+    use super::activation::{provision, ActivationMode};
+    use crate::{device_source::generate_device, scenario::device_config};
+
+    let mut rng = <rand::rngs::StdRng as SeedableRng>::seed_from_u64(0);
+    let device = provision(generate_device(0.0, &mut rng), ActivationMode::AbpGeneratedKeys, None, &mut rng);
+    let dev_eui = *device.dev_eui();
+    let radio_config = device_config(Position { x: 0.0, y: 0.0, z: 0.0 }, SpreadingFactor::SF7, 868_100_000.0, LoRaBandwidth::BW125, 14.0).radio_config;
+    let mut mac = MacState::new(&device, &radio_config, 14.0).unwrap();
+    let mut schedule = Schedule::default();
+    //shorter than the 30 s LOED minimum, which only applies to the LOED model
+    schedule.add_node(dev_eui, device, TrafficModel::Periodic(10.0), FirmwareProfile::default(), PayloadProfile::default(), None, &mut rng);
+
+    let mut last_start = None;
+    for f_cnt in 0..NUM_PACKETS {
+        //a single event per node waits in the queue, whatever the length of the run
+        assert_eq!(schedule.queue.len(), 1);
+        let event = schedule.queue.pop().unwrap().0;
+        if let Some(last_start) = last_start {
+            assert_eq!(event.start_time - last_start, 10_000);
+        }
+        last_start = Some(event.start_time);
+        let (payload, _) = schedule.build_uplink(dev_eui, &mut mac, None, &mut rng).unwrap();
+        assert_eq!(DataFrame::parse(&payload).unwrap().f_cnt as usize, f_cnt);
+        schedule.in_flight += 1;
+        schedule.on_feedback(Feedback { dev_eui, generation: event.generation, outcome: Outcome::Done { alarm: false } }, &mut rng);
+    }
+    //NUM_PACKETS uplinks per node, then nothing is scheduled anymore
+    assert!(schedule.queue.is_empty());
+}
+
+#[test]
+fn alarm_payloads() {
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::Position;
+
+    use super::{
+        activation::{provision, ActivationMode},
+        payload::PayloadGenerator,
+    };
+    use crate::{device_source::generate_device, scenario::device_config};
+
+    let mut rng = <rand::rngs::StdRng as SeedableRng>::seed_from_u64(0);
+    let device = provision(generate_device(0.0, &mut rng), ActivationMode::AbpGeneratedKeys, None, &mut rng);
+    let dev_eui = *device.dev_eui();
+    let radio_config = device_config(Position { x: 0.0, y: 0.0, z: 0.0 }, SpreadingFactor::SF7, 868_100_000.0, LoRaBandwidth::BW125, 14.0).radio_config;
+    let mut mac = MacState::new(&device, &radio_config, 14.0).unwrap();
+    let alarm_payload = PayloadProfile { generator: PayloadGenerator::Fixed(3), f_port: 20, confirmed_share: 0.0 };
+    let mut schedule = Schedule { alarm_payloads: vec![None, Some(alarm_payload)], ..Default::default() };
+    let profile = PayloadProfile { generator: PayloadGenerator::Fixed(11), f_port: 2, confirmed_share: 1.0 };
+    schedule.add_node(dev_eui, device, TrafficModel::Periodic(60.0), FirmwareProfile::default(), profile, None, &mut rng);
+    let mut build = |alarm| DataFrame::parse(&schedule.build_uplink(dev_eui, &mut mac, alarm, &mut rng).unwrap().0).unwrap();
+
+    //without a payload of its own, an alarm carries what the node always sends
+    let frame = build(Some(0));
+    assert_eq!((frame.f_port, frame.frm_payload.len(), frame.f_cnt), (Some(2), 11, 0));
+    assert_eq!(MType::from_mhdr(frame.mhdr), MType::ConfirmedDataUp);
+    let frame = build(Some(1));
+    assert_eq!((frame.f_port, frame.frm_payload.len(), frame.f_cnt), (Some(20), 3, 1));
+    assert_eq!(MType::from_mhdr(frame.mhdr), MType::UnconfirmedDataUp);
+    build(None);
+    assert_eq!(schedule.traffic[&dev_eui].sent, 1);
+}
+
+#[test]
+fn join_outcome_after_a_reboot() {
+    use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::Position;
+
+    use crate::{device_source::generate_device, scenario::device_config};
+
+    let mut rng = <rand::rngs::StdRng as SeedableRng>::seed_from_u64(0);
+    let device = generate_device(0.0, &mut rng);
+    let dev_eui = *device.dev_eui();
+    let mut schedule = Schedule::default();
+    schedule.add_node(dev_eui, device, TrafficModel::Periodic(60.0), FirmwareProfile::default(), PayloadProfile::default(), Some(World::now()), &mut rng);
+
+    let radio_config = device_config(Position { x: 0.0, y: 0.0, z: 0.0 }, SpreadingFactor::SF7, 868_100_000.0, LoRaBandwidth::BW125, 14.0).radio_config;
+    let joined = || {
+        let keys = SessionKeys { dev_addr: [1; 4], f_nwk_s_int_key: [2; 16], s_nwk_s_int_key: [2; 16], nwk_s_enc_key: [2; 16], lorawan_1_1: false };
+        let network_session = NetworkSessionContext::new([2; 16].into(), [2; 16].into(), [2; 16].into(), [0; 3], [1; 4], 0, 0, 0);
+        let session = SessionContext::new(ApplicationSessionContext::new([3; 16].into(), 0), network_session);
+        Outcome::Joined(Box::new(session), 1, Box::new(MacState::from_keys(keys, 0, &radio_config, 14.0)))
+    };
+
+    //the JoinRequest is sent, then the node reboots before the JoinAccept arrives
+    let join = schedule.queue.pop().unwrap().0;
+    schedule.in_flight += 1;
+    schedule.power_cycle(dev_eui, Duration::from_secs(10), false, &mut rng);
+    assert!(schedule.on_feedback(Feedback { dev_eui, generation: join.generation, outcome: joined() }, &mut rng).is_none());
+    assert_eq!(schedule.in_flight, 0);
+    assert!(schedule.traffic[&dev_eui].device.session().is_none());
+
+    //the join of the new generation installs its session and MAC state
+    let join = schedule.queue.pop().unwrap().0;
+    assert!(matches!(join.kind, EventKind::JoinRequest));
+    schedule.in_flight += 1;
+    assert!(schedule.on_feedback(Feedback { dev_eui, generation: join.generation, outcome: joined() }, &mut rng).is_some());
+    assert!(schedule.traffic[&dev_eui].device.session().is_some());
+    assert_eq!(schedule.in_flight, 0);
+}
+
+#[test]
+fn reboot_and_rejoin() {
+    use super::activation::{provision, ActivationMode};
+    use crate::device_source::generate_device;
+
+    let mut rng = <rand::rngs::StdRng as SeedableRng>::seed_from_u64(0);
+    let mut schedule = Schedule::default();
+    let profiles = [
+        FirmwareProfile { keeps_session: true, ..FirmwareProfile::default() },
+        FirmwareProfile { keeps_session: false, keeps_dev_nonce: false, ..FirmwareProfile::default() },
+    ];
+    let mut dev_euis = Vec::new();
+    for profile in profiles {
+        let mut device = provision(generate_device(0.0, &mut rng), ActivationMode::AbpGeneratedKeys, None, &mut rng);
+        device.set_dev_nonce(42);
+        dev_euis.push(*device.dev_eui());
+        schedule.add_node(*device.dev_eui(), device, TrafficModel::Periodic(600.0), profile, PayloadProfile::default(), None, &mut rng);
+    }
+    schedule.queue.clear();
+    let (keeper, forgetter) = (dev_euis[0], dev_euis[1]);
+    let outage = Duration::from_secs(30);
+
+    //a reboot keeps the session of the first firmware, it comes back with an uplink after the outage and its boot time
+    let before = World::now();
+    assert!(schedule.power_cycle(keeper, outage, false, &mut rng));
+    let event = schedule.queue.pop().unwrap().0;
+    assert!(matches!(event.kind, EventKind::Uplink { attempt: 1, .. }));
+    assert!(event.start_time >= before + 35_000 && event.start_time <= World::now() + 95_000);
+    assert_eq!(event.generation, 1);
+
+    //the second firmware forgets its session and its DevNonces, it joins again from the first one
+    assert!(!schedule.power_cycle(forgetter, outage, false, &mut rng));
+    assert!(matches!(schedule.queue.pop().unwrap().0.kind, EventKind::JoinRequest));
+    assert_eq!(schedule.traffic[&forgetter].device.dev_nonce(), STARTING_DEV_NONCE);
+
+    //a mass rejoin drops the session whatever the firmware, the DevNonce is kept where the firmware keeps it
+    assert!(!schedule.power_cycle(keeper, Duration::ZERO, true, &mut rng));
+    assert!(matches!(schedule.queue.pop().unwrap().0.kind, EventKind::JoinRequest));
+    assert_eq!(schedule.traffic[&keeper].device.dev_nonce(), 42);
+    //events scheduled before the last power cycle are stale
+    assert!(schedule.is_stale(&event));
 }
@@ -0,0 +1,471 @@
+//Multicast groups (LoRaWAN TS005 Remote Multicast Setup) and fragmented data block transport
+//(LoRaWAN TS004 Fragmented Data Block Transport) as seen from the simulated nodes.
+
+use std::time::Duration;
+
+use lorawan::{
+    encryption::key::Key,
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
+    utils::eui::EUI64,
+};
+use lorawan_device::communicator::{Position, Transmission};
+
+use super::{
+    crypto::{aes128_encrypt, crypt_frm_payload},
+    frame::{DataFrame, MType},
+};
+
+pub const FRAG_PORT: u8 = 201;
+const FRAG_DATA_BLOCK_CID: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum McClass {
+    //beacon synchronisation is not simulated, class B members listen for the whole session like class C ones
+    B { ping_slot_periodicity: u8 },
+    C,
+}
+
+#[derive(Clone, Debug)]
+pub struct McGroupSetup {
+    pub mc_group_id: u8,
+    pub mc_addr: [u8; 4],
+    pub mc_key: Key,
+    pub min_mc_f_cnt: u32,
+    pub max_mc_f_cnt: u32,
+}
+
+impl McGroupSetup {
+    fn derive_key(&self, prefix: u8) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[0] = prefix;
+        block[1..5].copy_from_slice(&[self.mc_addr[3], self.mc_addr[2], self.mc_addr[1], self.mc_addr[0]]);
+        aes128_encrypt(&self.mc_key, &block)
+    }
+
+    pub fn mc_app_s_key(&self) -> [u8; 16] {
+        self.derive_key(0x01)
+    }
+
+    pub fn mc_nwk_s_key(&self) -> [u8; 16] {
+        self.derive_key(0x02)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct McSession {
+    pub class: McClass,
+    pub frequency: f64,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: LoRaBandwidth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FragSessionSetup {
+    pub frag_index: u8,
+    pub nb_frag: u16,
+    pub frag_size: u8,
+    pub padding: u8,
+}
+
+impl FragSessionSetup {
+    pub fn for_image(frag_index: u8, image_len: usize, frag_size: u8) -> FragSessionSetup {
+        let nb_frag = image_len.div_ceil(frag_size as usize);
+        FragSessionSetup {
+            frag_index,
+            nb_frag: nb_frag as u16,
+            frag_size,
+            padding: (nb_frag * frag_size as usize - image_len) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MulticastGroupConfig {
+    pub setup: McGroupSetup,
+    pub session: McSession,
+    pub fragmentation: Option<FragSessionSetup>,
+}
+
+impl MulticastGroupConfig {
+    /// Builds an encrypted unconfirmed downlink addressed to the group.
+    pub fn build_downlink(&self, f_cnt: u32, f_port: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = DataFrame {
+            mhdr: 0x60,
+            dev_addr: self.setup.mc_addr,
+            f_ctrl: 0,
+            f_cnt: f_cnt as u16,
+            f_opts: Vec::new(),
+            f_port: Some(f_port),
+            frm_payload: crypt_frm_payload(&self.setup.mc_app_s_key(), &self.setup.mc_addr, f_cnt, false, payload),
+            mic: [0; 4],
+        };
+        frame.to_bytes_with_mic(&self.setup.mc_nwk_s_key(), f_cnt)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct McFrame {
+    pub f_cnt: u32,
+    pub f_port: u8,
+    pub payload: Vec<u8>,
+}
+
+//coefficient vectors of the fragment equations, packed 64 fragments per word
+type BitRow = Vec<u64>;
+
+fn bit_get(row: &BitRow, i: usize) -> bool {
+    row[i / 64] & (1 << (i % 64)) != 0
+}
+
+fn bit_set(row: &mut BitRow, i: usize) {
+    row[i / 64] |= 1 << (i % 64);
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+fn prbs23(x: u32) -> u32 {
+    let b0 = x & 1;
+    let b1 = (x & 0x20) >> 5;
+    (x >> 1) + ((b0 ^ b1) << 22)
+}
+
+/// Row of the TS004 parity matrix telling which uncoded fragments are xored in fragment `n` (1 based).
+pub fn matrix_line(n: u16, nb_frag: u16) -> BitRow {
+    let m = nb_frag as usize;
+    let mut line = vec![0u64; m.div_ceil(64)];
+    if n <= nb_frag {
+        bit_set(&mut line, n as usize - 1);
+        return line;
+    }
+
+    let pow2 = if m.is_power_of_two() { 1 } else { 0 };
+    let mut x = 1 + 1001 * (n - nb_frag) as u32;
+    let mut nb_coeff = 0;
+    while nb_coeff < m / 2 {
+        let mut r = 1 << 16;
+        while r >= m {
+            x = prbs23(x);
+            r = x as usize % (m + pow2);
+        }
+        bit_set(&mut line, r);
+        nb_coeff += 1;
+    }
+    line
+}
+
+/// Splits `image` into `nb_frag` uncoded fragments followed by `redundancy` coded ones.
+pub fn encode_fragments(image: &[u8], frag_size: u8, redundancy: u16) -> Vec<Vec<u8>> {
+    let setup = FragSessionSetup::for_image(0, image.len(), frag_size);
+    let mut padded = image.to_vec();
+    padded.resize(setup.nb_frag as usize * frag_size as usize, 0);
+    let uncoded = padded.chunks(frag_size as usize).map(|c| c.to_vec()).collect::<Vec<_>>();
+
+    let mut fragments = uncoded.clone();
+    for n in setup.nb_frag + 1..=setup.nb_frag + redundancy {
+        let line = matrix_line(n, setup.nb_frag);
+        let mut coded = vec![0u8; frag_size as usize];
+        for (i, fragment) in uncoded.iter().enumerate() {
+            if bit_get(&line, i) {
+                xor_into(&mut coded, fragment);
+            }
+        }
+        fragments.push(coded);
+    }
+    fragments
+}
+
+/// Incremental GF(2) decoder for uncoded and coded fragments.
+#[derive(Debug)]
+pub struct FragmentReassembler {
+    setup: FragSessionSetup,
+    //rows[p] holds an equation whose lowest coefficient is p
+    rows: Vec<Option<(BitRow, Vec<u8>)>>,
+    rank: usize,
+    fragments_received: u32,
+    last_n: u16,
+}
+
+impl FragmentReassembler {
+    pub fn new(setup: FragSessionSetup) -> Self {
+        Self {
+            setup,
+            rows: vec![None; setup.nb_frag as usize],
+            rank: 0,
+            fragments_received: 0,
+            last_n: 0,
+        }
+    }
+
+    pub fn setup(&self) -> &FragSessionSetup {
+        &self.setup
+    }
+
+    pub fn fragments_received(&self) -> u32 {
+        self.fragments_received
+    }
+
+    /// Number of uncoded fragments that are already recoverable from what was received.
+    pub fn rank(&self) -> usize {
+        self.rank
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.rank == self.setup.nb_frag as usize
+    }
+
+    /// Number of fragments lost between the first and the last one received.
+    pub fn fragments_lost(&self) -> u32 {
+        self.last_n as u32 - self.fragments_received
+    }
+
+    pub fn add_fragment(&mut self, n: u16, data: &[u8]) {
+        if n == 0 || data.len() != self.setup.frag_size as usize || self.is_complete() {
+            return;
+        }
+        self.fragments_received += 1;
+        self.last_n = self.last_n.max(n);
+
+        let mut coefficients = matrix_line(n, self.setup.nb_frag);
+        let mut data = data.to_vec();
+        for p in 0..self.rows.len() {
+            if !bit_get(&coefficients, p) {
+                continue;
+            }
+            match &self.rows[p] {
+                Some((row, row_data)) => {
+                    coefficients.iter_mut().zip(row).for_each(|(c, r)| *c ^= r);
+                    xor_into(&mut data, row_data);
+                }
+                None => {
+                    self.rows[p] = Some((coefficients, data));
+                    self.rank += 1;
+                    return;
+                }
+            }
+        }
+        //linearly dependent on what we already have
+    }
+
+    /// The reassembled data block, without padding, once every fragment is recoverable.
+    pub fn data(&self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+
+        let m = self.rows.len();
+        let mut solved: Vec<Vec<u8>> = vec![Vec::new(); m];
+        for p in (0..m).rev() {
+            let (row, row_data) = self.rows[p].as_ref().unwrap();
+            let mut data = row_data.clone();
+            for (j, s) in solved.iter().enumerate().skip(p + 1) {
+                if bit_get(row, j) {
+                    xor_into(&mut data, s);
+                }
+            }
+            solved[p] = data;
+        }
+
+        let mut image = solved.concat();
+        image.truncate(image.len() - self.setup.padding as usize);
+        Some(image)
+    }
+}
+
+#[derive(Debug)]
+pub struct MulticastMember {
+    pub node_id: u32,
+    pub dev_eui: EUI64,
+    pub position: Position,
+    last_f_cnt: Option<u32>,
+    frames_received: u32,
+    reassembler: Option<FragmentReassembler>,
+}
+
+impl MulticastMember {
+    fn on_frame(&mut self, frame: &McFrame) {
+        if self.last_f_cnt.is_some_and(|last| frame.f_cnt <= last) {
+            return;
+        }
+        self.last_f_cnt = Some(frame.f_cnt);
+        self.frames_received += 1;
+
+        if frame.f_port != FRAG_PORT || frame.payload.len() < 3 || frame.payload[0] != FRAG_DATA_BLOCK_CID {
+            return;
+        }
+        let index_and_n = u16::from_le_bytes([frame.payload[1], frame.payload[2]]);
+        if let Some(reassembler) = &mut self.reassembler {
+            if (index_and_n >> 14) as u8 == reassembler.setup().frag_index {
+                reassembler.add_fragment(index_and_n & 0x3fff, &frame.payload[3..]);
+            }
+        }
+    }
+
+    pub fn reassembler(&self) -> Option<&FragmentReassembler> {
+        self.reassembler.as_ref()
+    }
+}
+
+#[derive(Debug)]
+pub struct MulticastGroup {
+    config: MulticastGroupConfig,
+    mc_app_s_key: [u8; 16],
+    mc_nwk_s_key: [u8; 16],
+    last_f_cnt: u32,
+    members: Vec<MulticastMember>,
+}
+
+impl MulticastGroup {
+    pub fn new(config: MulticastGroupConfig) -> Self {
+        Self {
+            mc_app_s_key: config.setup.mc_app_s_key(),
+            mc_nwk_s_key: config.setup.mc_nwk_s_key(),
+            last_f_cnt: config.setup.min_mc_f_cnt,
+            config,
+            members: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MulticastGroupConfig {
+        &self.config
+    }
+
+    pub fn id(&self) -> u8 {
+        self.config.setup.mc_group_id
+    }
+
+    pub fn add_member(&mut self, node_id: u32, dev_eui: EUI64, position: Position) {
+        self.members.push(MulticastMember {
+            node_id,
+            dev_eui,
+            position,
+            last_f_cnt: None,
+            frames_received: 0,
+            reassembler: self.config.fragmentation.map(FragmentReassembler::new),
+        });
+    }
+
+    pub fn members(&self) -> &[MulticastMember] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [MulticastMember] {
+        &mut self.members
+    }
+
+    /// Checks that a downlink belongs to this group and decrypts it, once for all the members.
+    pub fn accept(&mut self, t: &Transmission) -> Option<McFrame> {
+        let session = &self.config.session;
+        if t.uplink || t.frequency != session.frequency || t.spreading_factor != session.spreading_factor || t.bandwidth != session.bandwidth {
+            return None;
+        }
+
+        let frame = DataFrame::parse(&t.payload).ok()?;
+        if frame.dev_addr != self.config.setup.mc_addr || frame.mtype() != MType::UnconfirmedDataDown {
+            return None;
+        }
+
+        let f_cnt = frame.full_f_cnt(self.last_f_cnt);
+        if f_cnt < self.config.setup.min_mc_f_cnt || f_cnt > self.config.setup.max_mc_f_cnt {
+            return None;
+        }
+        frame.verify_mic(&self.mc_nwk_s_key, f_cnt).ok()?;
+        self.last_f_cnt = f_cnt;
+
+        let f_port = frame.f_port?;
+        let payload = crypt_frm_payload(&self.mc_app_s_key, &frame.dev_addr, f_cnt, false, &frame.frm_payload);
+        Some(McFrame { f_cnt, f_port, payload })
+    }
+
+    pub fn deliver(&mut self, member: usize, frame: &McFrame) {
+        self.members[member].on_frame(frame);
+    }
+
+    pub fn report(&self) -> Vec<FuotaNodeReport> {
+        self.members
+            .iter()
+            .map(|m| FuotaNodeReport {
+                dev_eui: m.dev_eui,
+                mc_group_id: self.id(),
+                frames_received: m.frames_received,
+                fragments_received: m.reassembler.as_ref().map_or(0, |r| r.fragments_received()),
+                nb_frag: m.reassembler.as_ref().map_or(0, |r| r.setup().nb_frag),
+                recoverable_fragments: m.reassembler.as_ref().map_or(0, |r| r.rank() as u16),
+                complete: m.reassembler.as_ref().is_some_and(|r| r.is_complete()),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FuotaNodeReport {
+    pub dev_eui: EUI64,
+    pub mc_group_id: u8,
+    pub frames_received: u32,
+    pub fragments_received: u32,
+    pub nb_frag: u16,
+    pub recoverable_fragments: u16,
+    pub complete: bool,
+}
+
+impl FuotaNodeReport {
+    pub fn completeness(&self) -> f64 {
+        if self.nb_frag == 0 {
+            0.0
+        } else {
+            self.recoverable_fragments as f64 / self.nb_frag as f64
+        }
+    }
+}
+
+/// A firmware image pushed by the simulator itself through a gateway, for NSs without FUOTA support.
+#[derive(Clone, Debug)]
+pub struct FuotaCampaign {
+    pub mc_group_id: u8,
+    pub gateway_position: Position,
+    pub transmission_power_dbm: f32,
+    pub start_delay: Duration,
+    pub interval: Duration,
+    pub image: Vec<u8>,
+    pub redundancy: u16,
+}
+
+impl FuotaCampaign {
+    /// Downlinks of the campaign in sending order, the first one uses `min_mc_f_cnt` as counter.
+    pub fn build_downlinks(&self, group: &MulticastGroupConfig) -> Vec<Vec<u8>> {
+        let setup = group.fragmentation.expect("FUOTA campaigns need a group with a fragmentation session");
+        encode_fragments(&self.image, setup.frag_size, self.redundancy)
+            .into_iter()
+            .enumerate()
+            .map(|(i, fragment)| {
+                let index_and_n = ((setup.frag_index as u16) << 14) | (i as u16 + 1);
+                let mut payload = vec![FRAG_DATA_BLOCK_CID];
+                payload.extend_from_slice(&index_and_n.to_le_bytes());
+                payload.extend(fragment);
+                group.build_downlink(group.setup.min_mc_f_cnt + i as u32, FRAG_PORT, &payload)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn fragment_reassembly_with_losses() {
+    let image = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect::<Vec<_>>();
+    let fragments = encode_fragments(&image, 50, 10);
+    let setup = FragSessionSetup::for_image(0, image.len(), 50);
+    assert_eq!(setup.nb_frag, 20);
+    assert_eq!(fragments.len(), 30);
+
+    let mut reassembler = FragmentReassembler::new(setup);
+    for (i, fragment) in fragments.iter().enumerate() {
+        //lose two uncoded fragments, the coded ones have to fill the gap
+        if i == 3 || i == 11 {
+            continue;
+        }
+        reassembler.add_fragment(i as u16 + 1, fragment);
+    }
+
+    assert!(reassembler.is_complete());
+    assert_eq!(reassembler.data().unwrap(), image);
+}
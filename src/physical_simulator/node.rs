@@ -1,4 +1,7 @@
-use lorawan::utils::{eui::EUI64, PrettyHexSlice};
+use lorawan::{
+    physical_parameters::{LoRaBandwidth, SpreadingFactor},
+    utils::eui::EUI64,
+};
 use lorawan_device::{
     communicator::{
         CommunicatorError, LoRaWANCommunicator, Position, ReceivedTransmission, Transmission,
@@ -8,7 +11,6 @@ use lorawan_device::{
         lorawan_device::LoRaWANDevice
     , split_communicator::{LoRaReceiver, LoRaSender, SplitCommunicator},
 };
-use rand::{distributions::Distribution, Rng, SeedableRng};
 use std::{
     ops::{Deref, DerefMut},
     sync::Arc,
@@ -22,10 +24,7 @@ use tokio::{
     time::Instant,
 };
 
-use crate::{
-    constants::{FIXED_JOIN_DELAY, NUM_PACKETS, RANDOM_JOIN_DELAY},
-    physical_simulator::world::LOGGER, traffic_models::REGULAR_TRAFFIC_DISTRIBUTION,
-};
+use crate::traffic_models::TrafficModel;
 
 use super::{utils::get_sensitivity, world::World};
 
@@ -43,6 +42,8 @@ pub struct NodeConfig {
 
     pub node_state: Arc<Mutex<NodeState>>,
     pub radio_config: RadioDeviceConfig,
+    //when empty the node listens on radio_config, otherwise on the receive windows opened by the last uplink
+    pub rx_windows: Arc<Mutex<Vec<RadioParams>>>,
 }
 
 impl PartialEq for NodeConfig {
@@ -56,17 +57,74 @@ impl NodeConfig {
         *self.node_state.lock().await
     }
 
+    /// The uplink this node puts on air now, with the radio settings of `tx_params` or of its configuration.
+    pub fn uplink_transmission(&self, payload: &[u8], tx_params: Option<TxParams>) -> Transmission {
+        let tx_params = tx_params.unwrap_or(TxParams {
+            radio: RadioParams::from_radio_config(&self.radio_config),
+            power_dbm: self.transmission_power_dbm,
+        });
+        Transmission {
+            start_position: self.position,
+            start_time: World::now(),
+            frequency: tx_params.radio.frequency,
+            bandwidth: tx_params.radio.bandwidth,
+            spreading_factor: tx_params.radio.spreading_factor,
+            code_rate: self.radio_config.code_rate,
+            starting_power: tx_params.power_dbm,
+            uplink: true,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub async fn set_rx_windows(&self, windows: Vec<RadioParams>) {
+        *self.rx_windows.lock().await = windows;
+    }
+
+    async fn listening_on(&self, t: &Transmission) -> bool {
+        let windows = self.rx_windows.lock().await;
+        if windows.is_empty() {
+            RadioParams::from_radio_config(&self.radio_config).matches(t)
+        } else {
+            windows.iter().any(|w| w.matches(t))
+        }
+    }
+
     pub async fn can_receive_transmission(&self, t: &ReceivedTransmission) -> bool {
         self.position != t.transmission.start_position &&
         self.get_state().await == NodeState::Receiving &&
         !t.transmission.uplink &&                                                //is downlink
-        t.transmission.frequency == self.radio_config.freq &&                    //same frequency
-        t.transmission.bandwidth == self.radio_config.bandwidth &&               //same bandwidth
-        t.transmission.spreading_factor == self.radio_config.spreading_factor && //same spreading factor
+        self.listening_on(&t.transmission).await &&                             //same frequency, bandwidth and spreading factor
         t.arrival_stats.rssi > get_sensitivity(&t.transmission) //signal strength is greater than receiver sensitivity
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RadioParams {
+    pub frequency: f64,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: LoRaBandwidth,
+}
+
+impl RadioParams {
+    pub fn from_radio_config(radio_config: &RadioDeviceConfig) -> Self {
+        Self {
+            frequency: radio_config.freq,
+            spreading_factor: radio_config.spreading_factor,
+            bandwidth: radio_config.bandwidth,
+        }
+    }
+
+    pub fn matches(&self, t: &Transmission) -> bool {
+        t.frequency == self.frequency && t.bandwidth == self.bandwidth && t.spreading_factor == self.spreading_factor
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TxParams {
+    pub radio: RadioParams,
+    pub power_dbm: f32,
+}
+
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
 pub enum NodeState {
     Idle,
@@ -79,19 +137,19 @@ pub enum NodeState {
 pub struct Node {
     pub node_id: u32,
     pub device: LoRaWANDevice<NodeCommunicator>,
-    pub regular_traffic_model: bool,
+    pub traffic_model: TrafficModel,
 }
 
 impl Node {
     pub fn new(
         node_id: u32,
         device: LoRaWANDevice<NodeCommunicator>,
-        regular_traffic_model: bool,
+        traffic_model: TrafficModel,
     ) -> Node {
         Node {
             node_id,
             device,
-            regular_traffic_model,
+            traffic_model,
         }
     }
 
@@ -114,126 +172,6 @@ impl Node {
             .can_receive_transmission(t)
             .await
     }
-
-    pub async fn run(&mut self) {
-        let mut rng = rand::rngs::StdRng::from_entropy();
-
-        let mut periodic_delay =
-            REGULAR_TRAFFIC_DISTRIBUTION.sample(&mut rng) + (rng.gen_range(-60.0..60.0));
-        while periodic_delay < 100.0 {
-            periodic_delay =
-                REGULAR_TRAFFIC_DISTRIBUTION.sample(&mut rng) + (rng.gen_range(-60.0..60.0));
-        }
-
-        let sleep_time = rng.gen_range(0..RANDOM_JOIN_DELAY) as f64;
-        //let sleep_time = if self.regular_traffic_model {
-        //    periodic_delay
-        //} else {
-        //    let mut v = 0.0;
-        //    while v < 180.0 {
-        //        v = UNREGULAR_TRAFFIC_DISTRIBUTION.sample(&mut rng);
-        //    }
-        //    v
-        //};
-        tokio::time::sleep(Duration::from_secs_f64(rng.gen_range(0..1200) as f64)).await;
-        println!("Sleeping for {sleep_time:?}");
-        tokio::time::sleep(Duration::from_secs_f64(sleep_time)).await;
-
-        for _ in 0..1 {
-            let before = Instant::now();
-            if let Err(e) = self
-                .device
-                .join(
-                    Some(3),
-                    Some(Duration::from_secs_f64(
-                        rng.gen_range(FIXED_JOIN_DELAY..RANDOM_JOIN_DELAY) as f64,
-                    )),
-                )
-                .await
-            {
-                println!("Join failed: {e:?}, retrying...");
-            }
-            let rtt = before.elapsed().as_millis();
-            LOGGER.write(&format!(
-                "{},{},{}",
-                World::now(),
-                self.device.dev_eui(),
-                rtt
-            ));
-        }
-
-        if self.device.is_initialized() {
-            println!(
-                "Device {} initialized",
-                PrettyHexSlice(&**self.device.dev_eui())
-            );
-        } else {
-            println!(
-                "!!!!! Device {} NOT initialized !!!!!",
-                PrettyHexSlice(&**self.device.dev_eui())
-            );
-        }
-
-        let mut errors = 0;
-        let mut successes = 0;
-
-        println!(
-            "Initialized: {}",
-            PrettyHexSlice(self.device.session().unwrap().network_context().dev_addr())
-        );
-
-        for i in 0..NUM_PACKETS {
-            //let sleep_time = distribution.sample(&mut rng) + rand::random::<f64>() * 30.0;
-            //let sleep_time = rand::random::<u64>() % RANDOM_JOIN_DELAY + FIXED_JOIN_DELAY;
-            let sleep_time = rng.gen_range(FIXED_JOIN_DELAY..RANDOM_JOIN_DELAY);
-            tokio::time::sleep(Duration::from_secs(sleep_time)).await;
-            
-            let before = Instant::now();
-            match self
-                .device
-                .send_uplink(
-                    Some(format!("###  confirmed {i} message  ###").as_bytes()),
-                    true,
-                    Some(1),
-                    None,
-                )
-                .await
-            {
-                Ok(_) => {
-                    println!(
-                        "Device {} sent and received {i}-th message",
-                        PrettyHexSlice(&**self.device.dev_eui())
-                    );
-                    let rtt = before.elapsed().as_millis();
-                    successes += 1;
-                    LOGGER.write(&format!(
-                        "{},{},{}",
-                        World::now(),
-                        self.device.dev_eui(),
-                        rtt
-                    ))
-                }
-                Err(e) => {
-                    errors += 1;
-                    let rtt = before.elapsed().as_millis();
-                    LOGGER.write(&format!(
-                        "{},{},{}",
-                        World::now(),
-                        self.device.dev_eui(),
-                        rtt
-                    ));
-                    println!("Error sending confirmed message: {e:?}");
-                }
-            }
-        }
-
-        println!(
-            "Device {} finished with {} successes and {} errors",
-            PrettyHexSlice(&**self.device.dev_eui()),
-            successes,
-            errors
-        );
-    }
 }
 
 impl Deref for Node {
@@ -400,20 +338,10 @@ pub struct NodeReceiver {
 }
 
 impl LoRaSender for NodeSender {
-    type OptionalInfo=();
+    type OptionalInfo = TxParams;
 
-    async fn send(&self, bytes: &[u8], _: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
-        let t = Transmission {
-            start_position: self.config.position,
-            start_time: World::now(),
-            frequency: self.config.radio_config.freq,
-            bandwidth: self.config.radio_config.bandwidth,
-            spreading_factor: self.config.radio_config.spreading_factor,
-            code_rate: self.config.radio_config.code_rate,
-            starting_power: self.config.transmission_power_dbm,
-            uplink: true,
-            payload: bytes.to_vec(),
-        };
+    async fn send(&self, bytes: &[u8], tx_params: Option<Self::OptionalInfo>) -> Result<(), CommunicatorError> {
+        let t = self.config.uplink_transmission(bytes, tx_params);
 
         let toa = t.time_on_air();
         *self.config.node_state.lock().await = NodeState::Transmitting;
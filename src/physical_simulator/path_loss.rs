@@ -7,9 +7,11 @@ C: a constant that depends on the environment
 */
 
 use rand::Rng;
+use serde::Deserialize;
 
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PathLossModel {
     #[default]
     FreeSpace,
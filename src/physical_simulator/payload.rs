@@ -0,0 +1,196 @@
+//Application payloads of the simulated uplinks: their size sets the time on air, and with it the collisions
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+    sync::Arc,
+};
+
+use rand::{distributions::Distribution, Rng};
+use serde::Deserialize;
+
+use crate::{constants::CONFIRMED_SHARE, traffic_models::TrafficDistribution};
+
+#[derive(Debug)]
+pub enum PayloadError {
+    Io(io::Error),
+    Malformed { line: usize, message: String }, //line numbers start at 1
+    Empty,
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Io(e) => write!(f, "{e}"),
+            PayloadError::Malformed { line, message } => write!(f, "line {line}: {message}"),
+            PayloadError::Empty => write!(f, "no payloads"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+impl From<io::Error> for PayloadError {
+    fn from(e: io::Error) -> Self {
+        PayloadError::Io(e)
+    }
+}
+
+/// Synthetic sensors of CayenneLPP payloads, read on consecutive channels from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LppSensor {
+    DigitalInput,
+    AnalogInput, //a battery voltage
+    Illuminance,
+    Presence,
+    Temperature,
+    Humidity,
+    Barometer,
+    Gps,
+}
+
+impl LppSensor {
+    fn encode<R: Rng + ?Sized>(&self, channel: u8, rng: &mut R, out: &mut Vec<u8>) {
+        out.push(channel);
+        match self {
+            LppSensor::DigitalInput => out.extend_from_slice(&[0x00, rng.gen_range(0..=1)]),
+            LppSensor::AnalogInput => {
+                out.push(0x02);
+                out.extend_from_slice(&rng.gen_range(300..=360i16).to_be_bytes()); //0.01 V
+            }
+            LppSensor::Illuminance => {
+                out.push(0x65);
+                out.extend_from_slice(&rng.gen_range(0..=2000u16).to_be_bytes()); //lux
+            }
+            LppSensor::Presence => out.extend_from_slice(&[0x66, rng.gen_range(0..=1)]),
+            LppSensor::Temperature => {
+                out.push(0x67);
+                out.extend_from_slice(&rng.gen_range(-100..=350i16).to_be_bytes()); //0.1 °C
+            }
+            LppSensor::Humidity => out.extend_from_slice(&[0x68, rng.gen_range(40..=180)]), //0.5 %
+            LppSensor::Barometer => {
+                out.push(0x73);
+                out.extend_from_slice(&rng.gen_range(9800..=10400u16).to_be_bytes()); //0.1 hPa
+            }
+            LppSensor::Gps => {
+                out.push(0x88);
+                //latitude and longitude in 0.0001°, altitude in 0.01 m, on 3 bytes each
+                for value in [rng.gen_range(-900_000..=900_000i32), rng.gen_range(-1_800_000..=1_800_000), rng.gen_range(0..=50_000)] {
+                    out.extend_from_slice(&value.to_be_bytes()[1..]);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PayloadGenerator {
+    Text,                            //"###  confirmed {i} message  ###", what the simulator always sent
+    Fixed(usize),                    //random bytes
+    Sizes(Arc<TrafficDistribution>), //random bytes, as many as drawn from a value,probability table
+    CayenneLpp(Vec<LppSensor>),
+    Replay(Arc<Vec<Vec<u8>>>), //the payloads of a capture, in order and over again
+}
+
+impl PayloadGenerator {
+    /// Hex encoded payloads, one per line.
+    pub fn replay<T>(path: T) -> Result<Self, PayloadError>
+    where T: AsRef<Path> {
+        let content = std::fs::read_to_string(path)?;
+        let mut payloads = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let malformed = |message: String| PayloadError::Malformed { line: i + 1, message };
+            if !line.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(malformed(format!("{line:?} is not hex")));
+            }
+            if line.len() % 2 != 0 {
+                return Err(malformed(format!("odd number of hex digits in {line:?}")));
+            }
+            //pairs of hex digits by now
+            payloads.push((0..line.len()).step_by(2).map(|j| u8::from_str_radix(&line[j..j + 2], 16).unwrap()).collect());
+        }
+        if payloads.is_empty() {
+            return Err(PayloadError::Empty);
+        }
+        Ok(PayloadGenerator::Replay(Arc::new(payloads)))
+    }
+
+    /// Payload of the `index`-th uplink of a node. Sizes over the maximum of the data rate make the uplink fail.
+    pub fn payload<R: Rng + ?Sized>(&self, index: usize, rng: &mut R) -> Vec<u8> {
+        match self {
+            PayloadGenerator::Text => format!("###  confirmed {index} message  ###").into_bytes(),
+            PayloadGenerator::Fixed(size) => (0..*size).map(|_| rng.gen()).collect(),
+            PayloadGenerator::Sizes(sizes) => {
+                let size = sizes.sample(rng).round().max(0.0) as usize;
+                (0..size).map(|_| rng.gen()).collect()
+            }
+            PayloadGenerator::CayenneLpp(sensors) => {
+                let mut payload = Vec::new();
+                for (channel, sensor) in sensors.iter().enumerate() {
+                    sensor.encode(channel as u8 + 1, rng, &mut payload);
+                }
+                payload
+            }
+            PayloadGenerator::Replay(payloads) => payloads[index % payloads.len()].clone(),
+        }
+    }
+}
+
+/// What the uplinks of a node carry, and whether they are confirmed.
+#[derive(Debug, Clone)]
+pub struct PayloadProfile {
+    pub generator: PayloadGenerator,
+    pub f_port: u8,
+    pub confirmed_share: f64,
+}
+
+impl Default for PayloadProfile {
+    fn default() -> Self {
+        PayloadProfile {
+            generator: PayloadGenerator::Text,
+            f_port: 1,
+            confirmed_share: CONFIRMED_SHARE,
+        }
+    }
+}
+
+impl PayloadProfile {
+    pub fn confirmed<R: Rng + ?Sized>(&self, rng: &mut R) -> bool {
+        rng.gen_range(0.0..1.0) < self.confirmed_share
+    }
+}
+
+#[test]
+fn cayenne_lpp_payload() {
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let generator = PayloadGenerator::CayenneLpp(vec![LppSensor::Temperature, LppSensor::Humidity, LppSensor::Gps]);
+    let payload = generator.payload(0, &mut rng);
+    assert_eq!(payload.len(), 4 + 3 + 11);
+    assert_eq!((payload[0], payload[1]), (1, 0x67));
+    let temperature = i16::from_be_bytes([payload[2], payload[3]]);
+    assert!((-100..=350).contains(&temperature));
+    assert_eq!((payload[4], payload[5]), (2, 0x68));
+    assert_eq!((payload[7], payload[8]), (3, 0x88));
+
+    assert_eq!(PayloadGenerator::Fixed(23).payload(7, &mut rng).len(), 23);
+    assert_eq!(PayloadGenerator::Text.payload(7, &mut rng), b"###  confirmed 7 message  ###");
+    let replay = PayloadGenerator::Replay(Arc::new(vec![vec![1], vec![2, 3]]));
+    assert_eq!(replay.payload(3, &mut rng), vec![2, 3]);
+
+    let path = std::env::temp_dir().join("deloran_cayenne_lpp_payload.txt");
+    std::fs::write(&path, "01\n\n0203\n").unwrap();
+    assert_eq!(PayloadGenerator::replay(&path).unwrap().payload(1, &mut rng), vec![2, 3]);
+    std::fs::write(&path, "01\n\n020\n").unwrap();
+    assert!(matches!(PayloadGenerator::replay(&path), Err(PayloadError::Malformed { line: 3, .. })));
+    std::fs::write(&path, "01\n+1\n").unwrap();
+    assert!(matches!(PayloadGenerator::replay(&path), Err(PayloadError::Malformed { line: 2, .. })));
+    std::fs::write(&path, "\n").unwrap();
+    assert!(matches!(PayloadGenerator::replay(&path), Err(PayloadError::Empty)));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,528 @@
+//Gateway speaking the Semtech UDP packet forwarder protocol (GWMP, PROTOCOL.TXT version 2) to a network server
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lorawan_device::communicator::{Position, ReceivedTransmission, Transmission};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{Receiver, Sender},
+};
+
+use crate::compiled::gw::TxAckStatus;
+use crate::constants::{GATEWAY_STATS_INTERVAL, GWMP_PULL_INTERVAL};
+
+use super::{
+    gateway_state::{concentrator_channel, parse_code_rate, tx_ack_name, DownlinkRequest, GatewayCounters, GatewayState, LoRaModulation},
+    node::NodeConfig,
+    utils::{concentrator_counter, counter_to_unix_ms, get_sensitivity, gps_epoch_to_unix_ms, unix_to_gps_epoch_ms, GeoPosition},
+    world::World,
+};
+
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0x00;
+const PUSH_ACK: u8 = 0x01;
+const PULL_DATA: u8 = 0x02;
+const PULL_RESP: u8 = 0x03;
+const PULL_ACK: u8 = 0x04;
+const TX_ACK: u8 = 0x05;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rxpk {
+    pub time: String, //UTC, ISO 8601
+    pub tmms: u64,    //ms since the GPS epoch
+    pub tmst: u32,    //concentrator counter, µs
+    pub freq: f64,    //MHz
+    pub chan: u32,
+    pub rfch: u32,
+    pub stat: i8, //1 for a correct CRC
+    pub modu: String,
+    pub datr: String, //like SF7BW125
+    pub codr: String,
+    pub rssi: i32,
+    pub lsnr: f32,
+    pub size: usize,
+    pub data: String, //base64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stat {
+    pub time: String, //like 2014-01-12 08:59:28 GMT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lati: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alti: Option<i32>,
+    pub rxnb: u32,
+    pub rxok: u32,
+    pub rxfw: u32,
+    pub ackr: f32, //% of upstream datagrams acknowledged
+    pub dwnb: u32,
+    pub txnb: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PushData {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rxpk: Vec<Rxpk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stat: Option<Stat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Txpk {
+    #[serde(default)]
+    pub imme: bool,
+    pub tmst: Option<u32>,
+    pub tmms: Option<u64>,
+    pub freq: f64, //MHz
+    #[serde(default)]
+    pub rfch: u32,
+    #[serde(default)]
+    pub powe: i32,
+    pub modu: String,
+    pub datr: serde_json::Value, //a string like SF7BW125 for LoRa, bits per second for FSK
+    pub codr: Option<String>,
+    #[serde(default)]
+    pub ipol: bool,
+    pub size: Option<usize>,
+    pub data: String,
+    #[serde(default)]
+    pub ncrc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullResp {
+    pub txpk: Txpk,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxpkAck {
+    pub error: String, //NONE when the downlink is scheduled
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxAck {
+    pub txpk_ack: TxpkAck,
+}
+
+/// A datagram of the protocol: version, random token, identifier, then the gateway EUI for the upstream ones and the JSON object, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub token: u16,
+    pub identifier: u8,
+    pub gateway_eui: Option<[u8; 8]>,
+    pub json: Vec<u8>,
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![PROTOCOL_VERSION];
+        bytes.extend_from_slice(&self.token.to_be_bytes());
+        bytes.push(self.identifier);
+        if let Some(eui) = self.gateway_eui {
+            bytes.extend_from_slice(&eui);
+        }
+        bytes.extend_from_slice(&self.json);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < 4 || !(1..=PROTOCOL_VERSION).contains(&bytes[0]) {
+            return None;
+        }
+        let token = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let identifier = bytes[3];
+        let (gateway_eui, json) = match identifier {
+            PUSH_DATA | PULL_DATA | TX_ACK => (Some(bytes.get(4..12)?.try_into().ok()?), &bytes[12..]),
+            PUSH_ACK | PULL_RESP | PULL_ACK => (None, &bytes[4..]),
+            _ => return None,
+        };
+        Some(Packet { token, identifier, gateway_eui, json: json.to_vec() })
+    }
+}
+
+fn datr(m: &LoRaModulation) -> String {
+    format!("SF{}BW{}", m.spreading_factor, m.bandwidth / 1000)
+}
+
+//SF7BW125 as spreading factor and bandwidth in Hz
+fn parse_datr(datr: &str) -> Option<(u8, u32)> {
+    let (sf, bw) = datr.strip_prefix("SF")?.split_once("BW")?;
+    Some((sf.parse().ok()?, bw.parse::<u32>().ok()? * 1000))
+}
+
+//TX_ACK error of a status
+fn tx_ack_error(status: TxAckStatus) -> &'static str {
+    match status {
+        TxAckStatus::Ok => "NONE",
+        status => tx_ack_name(status),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SemtechUdpBridgeConfig {
+    pub gateway_eui: [u8; 8],
+    pub server_address: SocketAddr,
+    pub node_config: NodeConfig,
+    pub location: Option<GeoPosition>, //sent in the stat messages, none when the scenario has no origin
+}
+
+impl SemtechUdpBridgeConfig {
+    pub fn can_receive_transmission(&self, t: &ReceivedTransmission) -> bool {
+        self.node_config.position != t.transmission.start_position &&
+        t.transmission.uplink &&                                       //is uplink
+        t.arrival_stats.rssi > get_sensitivity(&t.transmission)        //signal strength is greater than receiver sensitivity
+    }
+}
+
+#[derive(Debug)]
+pub struct SemtechUdpBridge {
+    id: u32,
+    gateway_eui: [u8; 8],
+    server_address: SocketAddr,
+    node_config: NodeConfig,
+    location: Option<GeoPosition>,
+    sender: Sender<Transmission>,
+    receiver: Receiver<ReceivedTransmission>,
+}
+
+impl SemtechUdpBridge {
+    pub fn new(id: u32, sender: Sender<Transmission>, receiver: Receiver<ReceivedTransmission>, config: SemtechUdpBridgeConfig) -> Self {
+        Self {
+            id,
+            gateway_eui: config.gateway_eui,
+            server_address: config.server_address,
+            node_config: config.node_config,
+            location: config.location,
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn radio_sensitivity(&self) -> f32 {
+        self.node_config.receiver_sensitivity
+    }
+
+    pub fn get_position(&self) -> Position {
+        self.node_config.position
+    }
+
+    pub fn can_receive_transmission(&self, t: &ReceivedTransmission) -> bool {
+        self.node_config.position != t.transmission.start_position &&
+        t.transmission.uplink &&                                       //is uplink
+        t.arrival_stats.rssi > get_sensitivity(&t.transmission)        //signal strength is greater than receiver sensitivity
+    }
+
+    /// None when the uplink is outside of the channel plan, the concentrator does not hear it.
+    fn create_rxpk(t: &ReceivedTransmission) -> Option<Rxpk> {
+        let modulation = LoRaModulation::of(&t.transmission);
+        let (chan, frequency, rfch) = concentrator_channel(t.transmission.frequency, modulation.bandwidth)?;
+        //timestamps of the end of the uplink, RX delays count from there
+        let end_time = t.transmission.start_time + t.transmission.time_on_air();
+        let time = prost_types::Timestamp { seconds: (end_time / 1000) as i64, nanos: (end_time % 1000) as i32 * 1_000_000 };
+        Some(Rxpk {
+            time: time.to_string(),
+            tmms: unix_to_gps_epoch_ms(end_time) as u64,
+            tmst: concentrator_counter(end_time),
+            freq: frequency as f64 / 1_000_000.0,
+            chan,
+            rfch,
+            stat: 1, //the world only delivers frames that survived collisions
+            modu: String::from("LORA"),
+            datr: datr(&modulation),
+            codr: format!("4/{}", modulation.code_rate),
+            rssi: t.arrival_stats.rssi as i32,
+            lsnr: t.arrival_stats.snr,
+            size: t.transmission.payload.len(),
+            data: STANDARD.encode(&t.transmission.payload),
+        })
+    }
+
+    fn create_stat(location: Option<GeoPosition>, c: &GatewayCounters, datagrams_sent: u32, datagrams_acked: u32) -> Stat {
+        let now = prost_types::Timestamp { seconds: (World::now() / 1000) as i64, nanos: 0 };
+        Stat {
+            time: now.to_string().replace('T', " ").replace('Z', " GMT"),
+            lati: location.map(|l| l.latitude),
+            long: location.map(|l| l.longitude),
+            alti: location.map(|l| l.altitude.round() as i32),
+            rxnb: c.rx_received,
+            rxok: c.rx_received_ok,
+            rxfw: c.rx_received_ok,
+            ackr: if datagrams_sent > 0 { 100.0 * datagrams_acked as f32 / datagrams_sent as f32 } else { 0.0 },
+            dwnb: c.tx_received,
+            txnb: c.tx_emitted,
+        }
+    }
+
+    /// The transmission a txpk asks for, or why the gateway cannot send it.
+    fn plan_txpk(txpk: &Txpk, position: Position, now: u128) -> Result<Transmission, TxAckStatus> {
+        //the simulated radio only speaks LoRa
+        if txpk.modu != "LORA" {
+            return Err(TxAckStatus::InternalError);
+        }
+        let (spreading_factor, bandwidth) = txpk.datr.as_str().and_then(parse_datr).ok_or(TxAckStatus::InternalError)?;
+        let code_rate = txpk.codr.as_deref().map_or(Some(5), parse_code_rate).ok_or(TxAckStatus::InternalError)?;
+        let payload = STANDARD.decode(&txpk.data).map_err(|_| TxAckStatus::InternalError)?;
+        let start_time = match (txpk.imme, txpk.tmst, txpk.tmms) {
+            (true, _, _) => None,
+            (false, Some(tmst), _) => Some(counter_to_unix_ms(tmst, now)),
+            (false, None, Some(tmms)) => Some(gps_epoch_to_unix_ms(tmms as u128)),
+            (false, None, None) => return Err(TxAckStatus::InternalError),
+        };
+        DownlinkRequest {
+            modulation: LoRaModulation { bandwidth, spreading_factor, code_rate },
+            frequency: (txpk.freq * 1_000_000.0).round() as u32,
+            power: txpk.powe,
+            start_time,
+            payload,
+        }
+        .plan(position, now)
+    }
+
+    pub async fn start(mut self) {
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("SemtechUdpBridge {} not started, could not bind a UDP socket: {e}", self.id);
+                return;
+            }
+        };
+        if let Err(e) = socket.connect(self.server_address).await {
+            eprintln!("SemtechUdpBridge {} not started, could not reach {}: {e}", self.id, self.server_address);
+            return;
+        }
+        println!("SemtechUdpBridge {} started, forwarding to {}", self.id, self.server_address);
+
+        let id = self.id;
+        let eui = self.gateway_eui;
+        let socket = Arc::new(socket);
+        let state = Arc::new(Mutex::new(GatewayState::default()));
+        //upstream datagrams sent and acknowledged since the last stat
+        let datagrams_sent = Arc::new(AtomicU32::new(0));
+        let datagrams_acked = Arc::new(AtomicU32::new(0));
+        let send = move |socket: Arc<UdpSocket>, identifier: u8, json: Vec<u8>, sent: Arc<AtomicU32>| async move {
+            let packet = Packet { token: rand::random(), identifier, gateway_eui: Some(eui), json };
+            sent.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = socket.send(&packet.to_bytes()).await {
+                eprintln!("[GW{id}] Could not send to the network server: {e}");
+            }
+        };
+
+        //PULL_DATA opens the downstream path through NATs and keeps it open
+        let keepalive = tokio::spawn({
+            let (socket, sent) = (socket.clone(), datagrams_sent.clone());
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(GWMP_PULL_INTERVAL));
+                loop {
+                    interval.tick().await;
+                    send(socket.clone(), PULL_DATA, Vec::new(), sent.clone()).await;
+                }
+            }
+        });
+
+        let stats = tokio::spawn({
+            let (socket, state, sent, acked, location) = (socket.clone(), state.clone(), datagrams_sent.clone(), datagrams_acked.clone(), self.location);
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(GATEWAY_STATS_INTERVAL));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let counters = state.lock().unwrap().take_counters();
+                    let stat = Self::create_stat(location, &counters, sent.swap(0, Ordering::Relaxed), acked.swap(0, Ordering::Relaxed));
+                    let json = serde_json::to_vec(&PushData { rxpk: Vec::new(), stat: Some(stat) }).unwrap();
+                    send(socket.clone(), PUSH_DATA, json, sent.clone()).await;
+                }
+            }
+        });
+
+        let t1 = tokio::spawn({
+            let (socket, state, sent) = (socket.clone(), state.clone(), datagrams_sent.clone());
+            async move {
+                while let Some(received_transmission) = self.receiver.recv().await {
+                    let Some(rxpk) = Self::create_rxpk(&received_transmission) else {
+                        eprintln!("[GW{id}] Uplink on {} Hz outside of the channel plan ignored", received_transmission.transmission.frequency);
+                        continue;
+                    };
+                    state.lock().unwrap().uplink_received(&received_transmission.transmission, true);
+                    let json = serde_json::to_vec(&PushData { rxpk: vec![rxpk], stat: None }).unwrap();
+                    send(socket.clone(), PUSH_DATA, json, sent.clone()).await;
+                }
+                eprintln!("SemtechUdpBridge {id} stopped forwarding uplinks, world channel closed");
+            }
+        });
+
+        let sender = self.sender;
+        let position = self.node_config.position;
+        let t2 = tokio::spawn(async move {
+            let mut buffer = [0u8; 65_536];
+            loop {
+                let size = match socket.recv(&mut buffer).await {
+                    Ok(size) => size,
+                    Err(e) => {
+                        //an ICMP port unreachable while the network server is down, the next keepalive tries again
+                        eprintln!("[GW{id}] Could not receive from the network server: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let Some(packet) = Packet::from_bytes(&buffer[..size]) else {
+                    eprintln!("[GW{id}] Malformed datagram from the network server ignored");
+                    continue;
+                };
+                match packet.identifier {
+                    PUSH_ACK | PULL_ACK => {
+                        datagrams_acked.fetch_add(1, Ordering::Relaxed);
+                    }
+                    PULL_RESP => {
+                        let now = World::now();
+                        let planned = match serde_json::from_slice::<PullResp>(&packet.json) {
+                            Ok(resp) => Self::plan_txpk(&resp.txpk, position, now),
+                            Err(e) => {
+                                eprintln!("[GW{id}] Malformed PULL_RESP: {e}");
+                                Err(TxAckStatus::InternalError)
+                            }
+                        };
+                        let planned = {
+                            let mut state = state.lock().unwrap();
+                            state.downlink_requested();
+                            let planned = planned.and_then(|t| state.schedule(&t, now).map(|_| t));
+                            state.downlink_acked(planned.as_ref().err().copied().unwrap_or(TxAckStatus::Ok));
+                            planned
+                        };
+
+                        let status = planned.as_ref().err().copied().unwrap_or(TxAckStatus::Ok);
+                        let ack = TxAck { txpk_ack: TxpkAck { error: tx_ack_error(status).to_string() } };
+                        let ack = Packet { token: packet.token, identifier: TX_ACK, gateway_eui: Some(eui), json: serde_json::to_vec(&ack).unwrap() };
+                        if let Err(e) = socket.send(&ack.to_bytes()).await {
+                            eprintln!("[GW{id}] Could not send TX_ACK: {e}");
+                        }
+
+                        let Ok(transmission) = planned else {
+                            eprintln!("[GW{id}] Downlink not sent: {}", tx_ack_error(status));
+                            continue;
+                        };
+                        let (sender, state) = (sender.clone(), state.clone());
+                        tokio::spawn(async move {
+                            let wait = transmission.start_time.saturating_sub(World::now());
+                            tokio::time::sleep(Duration::from_millis(wait as u64)).await;
+                            let transmission = Transmission { start_time: World::now(), ..transmission };
+                            state.lock().unwrap().downlink_emitted(&transmission);
+                            if let Err(e) = sender.send(transmission).await {
+                                eprintln!("Error sending message to world: {:?}", e);
+                            }
+                        });
+                    }
+                    identifier => eprintln!("[GW{id}] Unexpected datagram {identifier:#04x} from the network server"),
+                }
+            }
+        });
+
+        let (_r1, _r2) = tokio::join!(t1, t2);
+        keepalive.abort();
+        stats.abort();
+        println!("SemtechUdpBridge {id} stopped");
+    }
+}
+
+#[tokio::test]
+async fn packet_forwarder_with_udp_stand_in() {
+    use lorawan::physical_parameters::{CodeRate, LoRaBandwidth, SpreadingFactor};
+    use lorawan_device::communicator::ArrivalStats;
+
+    let limit = Duration::from_secs(5);
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (world_sender, mut world) = tokio::sync::mpsc::channel(10);
+    let (uplinks, receiver) = tokio::sync::mpsc::channel(10);
+    let gateway_eui = [0x00, 0x80, 0x00, 0x00, 0xa0, 0x00, 0x17, 0x93];
+    let config = SemtechUdpBridgeConfig {
+        gateway_eui,
+        server_address: server.local_addr().unwrap(),
+        node_config: crate::scenario::gateway_config(Position { x: 0.0, y: 0.0, z: 30.0 }),
+        location: None,
+    };
+    tokio::spawn(SemtechUdpBridge::new(0, world_sender, receiver, config).start());
+
+    let mut uplink = Transmission {
+        start_position: Position { x: 100.0, y: 0.0, z: 1.5 },
+        start_time: 0,
+        frequency: 868_100_000.0,
+        bandwidth: LoRaBandwidth::BW125,
+        spreading_factor: SpreadingFactor::SF7,
+        code_rate: CodeRate::CR4_5,
+        starting_power: 14.0,
+        uplink: true,
+        payload: vec![0x40, 1, 2, 3, 4, 0, 1, 0, 1, 0xaa, 0xbb, 0xcc, 0xdd],
+    };
+    uplink.start_time = World::now() - uplink.time_on_air();
+    let end_time = uplink.start_time + uplink.time_on_air();
+    uplinks.send(ReceivedTransmission { transmission: uplink.clone(), arrival_stats: ArrivalStats { time: end_time, rssi: -90.0, snr: 7.5 } }).await.unwrap();
+
+    //the keepalive and the uplink, in any order, both acked
+    let mut buffer = [0u8; 4096];
+    let (mut rxpk, mut gateway) = (None, None);
+    while rxpk.is_none() || gateway.is_none() {
+        let (size, from) = tokio::time::timeout(limit, server.recv_from(&mut buffer)).await.unwrap().unwrap();
+        let packet = Packet::from_bytes(&buffer[..size]).unwrap();
+        assert_eq!(packet.gateway_eui, Some(gateway_eui));
+        let ack = match packet.identifier {
+            PULL_DATA => {
+                gateway = Some(from);
+                PULL_ACK
+            }
+            PUSH_DATA => {
+                rxpk = serde_json::from_slice::<PushData>(&packet.json).unwrap().rxpk.pop();
+                PUSH_ACK
+            }
+            other => panic!("unexpected datagram {other}"),
+        };
+        server.send_to(&Packet { token: packet.token, identifier: ack, gateway_eui: None, json: Vec::new() }.to_bytes(), from).await.unwrap();
+    }
+    let (rxpk, gateway) = (rxpk.unwrap(), gateway.unwrap());
+    assert_eq!((rxpk.freq, rxpk.chan, rxpk.datr.as_str(), rxpk.codr.as_str()), (868.1, 0, "SF7BW125", "4/5"));
+    assert_eq!(rxpk.tmst, concentrator_counter(end_time));
+    assert_eq!(STANDARD.decode(&rxpk.data).unwrap(), uplink.payload);
+
+    //RX1, then a second downlink for the same slot
+    let txpk = Txpk {
+        imme: false,
+        tmst: Some(rxpk.tmst.wrapping_add(1_000_000)),
+        tmms: None,
+        freq: 868.1,
+        rfch: 0,
+        powe: 14,
+        modu: String::from("LORA"),
+        datr: serde_json::Value::from("SF7BW125"),
+        codr: Some(String::from("4/5")),
+        ipol: true,
+        size: Some(4),
+        data: STANDARD.encode([0x60, 1, 2, 3]),
+        ncrc: true,
+    };
+    for (token, error) in [(1u16, "NONE"), (2, "COLLISION_PACKET")] {
+        let resp = Packet { token, identifier: PULL_RESP, gateway_eui: None, json: serde_json::to_vec(&PullResp { txpk: txpk.clone() }).unwrap() };
+        server.send_to(&resp.to_bytes(), gateway).await.unwrap();
+        let (size, _) = tokio::time::timeout(limit, server.recv_from(&mut buffer)).await.unwrap().unwrap();
+        let ack = Packet::from_bytes(&buffer[..size]).unwrap();
+        assert_eq!((ack.identifier, ack.token), (TX_ACK, token));
+        assert_eq!(serde_json::from_slice::<TxAck>(&ack.json).unwrap().txpk_ack.error, error);
+    }
+
+    let downlink = tokio::time::timeout(limit, world.recv()).await.unwrap().unwrap();
+    assert!(!downlink.uplink);
+    assert_eq!((downlink.frequency, downlink.payload), (868_100_000.0, vec![0x60, 1, 2, 3]));
+    assert!(downlink.start_time + 50 >= end_time + 1_000);
+}
@@ -0,0 +1,103 @@
+//Device state (session keys, frame counters, DevNonce) kept across runs, so that a stateful network server
+//does not reject the next run for frame counter or DevNonce replay
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use lorawan::{device::Device, utils::eui::EUI64};
+
+/// One JSON `Device` per line, the same format as the `node_sessions` files.
+#[derive(Clone, Debug)]
+pub struct SessionStore {
+    path: PathBuf,
+    devices: Arc<Mutex<HashMap<EUI64, Device>>>,
+}
+
+impl SessionStore {
+    /// Opens the store at `path`, restoring the devices saved by the previous run if the file exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SessionStore> {
+        let path = path.as_ref().to_path_buf();
+        let mut devices = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                for (i, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                    let device: Device = serde_json::from_str(line)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {e}", path.display(), i + 1)))?;
+                    devices.insert(*device.dev_eui(), device);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(SessionStore { path, devices: Arc::new(Mutex::new(devices)) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn restore(&self, dev_eui: &EUI64) -> Option<Device> {
+        self.devices.lock().unwrap().get(dev_eui).cloned()
+    }
+
+    /// Records the latest state of a device, written out by the next `save`.
+    pub fn update(&self, device: &Device) {
+        self.devices.lock().unwrap().insert(*device.dev_eui(), device.clone());
+    }
+
+    /// Writes every device to a temporary file first, so that an interrupted save keeps the previous store.
+    pub fn save(&self) -> io::Result<()> {
+        let mut content = String::new();
+        for device in self.devices.lock().unwrap().values() {
+            content.push_str(&serde_json::to_string(device)?);
+            content.push('\n');
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+#[test]
+fn save_and_restore() {
+    use super::activation::{provision, ActivationMode};
+    use crate::device_source::generate_device;
+
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let path = std::env::temp_dir().join("deloran_save_and_restore.jsonl");
+    let _ = fs::remove_file(&path);
+    let store = SessionStore::open(&path).unwrap();
+    assert!(store.is_empty());
+
+    let mut device = provision(generate_device(1.0, &mut rng), ActivationMode::AbpGeneratedKeys, None, &mut rng);
+    device.set_dev_nonce(17);
+    let session = device.session_mut().unwrap();
+    session.network_context_mut().update_f_cnt_up(731);
+    session.network_context_mut().update_nf_cnt_dwn(12);
+    session.application_context_mut().update_af_cnt_dwn(5);
+    store.update(&device);
+    store.update(&generate_device(0.0, &mut rng));
+    store.save().unwrap();
+    //the temporary file took the place of the store
+    assert!(!path.with_extension("tmp").exists());
+
+    let restored = SessionStore::open(&path).unwrap();
+    assert_eq!(restored.len(), 2);
+    let device = restored.restore(device.dev_eui()).unwrap();
+    assert_eq!(device.dev_nonce(), 17);
+    let session = device.session().unwrap();
+    assert_eq!((session.network_context().f_cnt_up(), session.network_context().nf_cnt_dwn()), (731, 12));
+    assert_eq!(session.application_context().af_cnt_dwn(), 5);
+
+    fs::write(&path, "{\"not\": \"a device\"}\n").unwrap();
+    assert_eq!(SessionStore::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    fs::remove_file(&path).unwrap();
+}
@@ -1,5 +1,6 @@
 use lorawan::physical_parameters::{LoRaBandwidth, SpreadingFactor};
-use lorawan_device::communicator::Transmission;
+use lorawan_device::communicator::{Position, Transmission};
+use serde::Deserialize;
 
 //fn dbmw2mw(dbm: f64) -> f64 {
 //    // Conversion formula: P(mW) = 1mW * 10^(P(dBm)/10)
@@ -10,9 +11,10 @@ use lorawan_device::communicator::Transmission;
 //function returns sensitivity -- according to LoRa documentation, it changes with LoRa parameters
 //Sensitivity values from Semtech SX1272/73 datasheet, table 10, Rev 3.1, March 2017
 pub fn get_sensitivity(transmission: &Transmission) -> f32 {
-    let sf = transmission.spreading_factor;
-    let bw = transmission.bandwidth;
+    sensitivity(transmission.spreading_factor, transmission.bandwidth)
+}
 
+pub fn sensitivity(sf: SpreadingFactor, bw: LoRaBandwidth) -> f32 {
     match sf {
         SpreadingFactor::SF7 => match bw {
             LoRaBandwidth::BW125 => -124.0,
@@ -45,4 +47,47 @@ pub fn get_sensitivity(transmission: &Transmission) -> f32 {
             LoRaBandwidth::BW500 => -129.0,
         },
     }
-}
\ No newline at end of file
+}
+
+const GPS_EPOCH_UNIX_MS: u128 = 315_964_800_000;
+const GPS_LEAP_SECONDS: u128 = 18; //GPS time is ahead of UTC since the end of 2016
+
+//free running microsecond counter of a concentrator, wrapping every ~71 minutes, taken from the world clock
+pub fn concentrator_counter(unix_ms: u128) -> u32 {
+    (unix_ms * 1000) as u32
+}
+
+//world time of a counter value, the occurrence closest to `now_ms`
+pub fn counter_to_unix_ms(counter: u32, now_ms: u128) -> u128 {
+    let delta_us = counter.wrapping_sub(concentrator_counter(now_ms)) as i32 as i128;
+    (now_ms as i128 + delta_us / 1000) as u128
+}
+
+pub fn unix_to_gps_epoch_ms(unix_ms: u128) -> u128 {
+    unix_ms + GPS_LEAP_SECONDS * 1000 - GPS_EPOCH_UNIX_MS
+}
+
+pub fn gps_epoch_to_unix_ms(gps_ms: u128) -> u128 {
+    gps_ms + GPS_EPOCH_UNIX_MS - GPS_LEAP_SECONDS * 1000
+}
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+//geographic coordinates of a point of the simulated area, degrees and metres above sea level
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub altitude: f64,
+}
+
+impl GeoPosition {
+    //`position` taken from this point as origin, x pointing east and y north. Simulated areas span a few km, a flat earth is enough
+    pub fn offset(&self, position: Position) -> GeoPosition {
+        let latitude = self.latitude + (position.y as f64 / EARTH_RADIUS_M).to_degrees();
+        let longitude = self.longitude + (position.x as f64 / (EARTH_RADIUS_M * self.latitude.to_radians().cos())).to_degrees();
+        GeoPosition { latitude, longitude, altitude: self.altitude + position.z as f64 }
+    }
+}
@@ -1,37 +1,46 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use lazy_static::lazy_static;
-use lorawan::{device::Device, physical_parameters::LoRaBandwidth};
+use lorawan::{device::Device, physical_parameters::LoRaBandwidth, utils::eui::EUI64};
 use lorawan_device::{
     communicator::{ArrivalStats, Position, ReceivedTransmission, Transmission},
     devices::lorawan_device::LoRaWANDevice,
 };
-use rand::{prelude::Distribution, Rng, SeedableRng};
 use tokio::sync::{
     mpsc::{self, Sender},
     Mutex, Notify,
 };
 
 use crate::{
-    constants::{ACTIVE_LOGGER, LOGGER_PRINTLN, PRINT_LOG_PATH, RTT_LOG_PATH, STARTING_DEV_NONCE},
-    logger::Logger, traffic_models::REGULAR_TRAFFIC_DISTRIBUTION,
+    constants::{ACTIVE_LOGGER, FUOTA_REPORT_PATH, LOGGER_PRINTLN, PRINT_LOG_PATH, RTT_LOG_PATH, STARTING_DEV_NONCE},
+    logger::Logger, traffic_models::{TrafficModel, TrafficProfile},
 };
 
 use super::{
+    alarm::AlarmEvent,
     chirpstack_bridge::{ChirpstackBridge, ChirpstackBridgeConfig},
+    fleet::{FirmwareProfile, FleetEvent},
     multi_node::MultiNode,
+    mqtt::SharedMqttClient,
+    multicast::{FuotaCampaign, FuotaNodeReport, MulticastGroup, MulticastGroupConfig},
     network_controller_bridge::{NetworkControllerBridge, NetworkControllerBridgeConfig},
     node::{Node, NodeCommunicator, NodeConfig},
     path_loss::PathLossModel,
+    payload::PayloadProfile,
+    semtech_udp_bridge::{SemtechUdpBridge, SemtechUdpBridgeConfig},
+    session_store::SessionStore,
+    utils::get_sensitivity,
 };
 
 lazy_static! {
     pub static ref LOGGER: Logger = Logger::new(RTT_LOG_PATH, ACTIVE_LOGGER, LOGGER_PRINTLN);
     pub static ref PRINTER_LOGGER: Logger = Logger::new(PRINT_LOG_PATH, ACTIVE_LOGGER, LOGGER_PRINTLN);
+    pub static ref FUOTA_LOGGER: Logger = Logger::new(FUOTA_REPORT_PATH, ACTIVE_LOGGER, false);
     //pub static ref LOGGER_DEVICES: Logger = Logger::new("devices_complete.csv");
 }
 
@@ -39,7 +48,8 @@ lazy_static! {
 pub enum EntityConfig {
     Node(NodeConfig),
     NetworkController(NetworkControllerBridgeConfig),
-    ChipstackBridge(ChirpstackBridgeConfig),
+    ChipstackBridge(Box<ChirpstackBridgeConfig>), //boxed, its MQTT settings make it much larger than the others
+    SemtechUdpBridge(SemtechUdpBridgeConfig),
 }
 
 #[derive(Debug)]
@@ -47,6 +57,7 @@ pub enum Entity {
     Node(Node),
     NetworkController(NetworkControllerBridge),
     ChipstackBridge(ChirpstackBridge),
+    SemtechUdpBridge(SemtechUdpBridge),
 }
 
 impl EntityConfig {
@@ -55,6 +66,7 @@ impl EntityConfig {
             EntityConfig::Node(node) => node.position,
             EntityConfig::NetworkController(nc) => nc.node_config.position,
             EntityConfig::ChipstackBridge(c) => c.node_config.position,
+            EntityConfig::SemtechUdpBridge(s) => s.node_config.position,
         }
     }
 
@@ -63,10 +75,36 @@ impl EntityConfig {
             EntityConfig::Node(node) => node.can_receive_transmission(t).await,
             EntityConfig::NetworkController(nc) => nc.can_receive_transmission(t),
             EntityConfig::ChipstackBridge(c) => c.can_receive_transmission(t),
+            EntityConfig::SemtechUdpBridge(s) => s.can_receive_transmission(t),
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum WorldError {
+    UnknownNode(EUI64),
+    UnknownMulticastGroup(u8),
+    DuplicateMulticastGroup(u8),
+    NoFragmentationSession(u8), //FUOTA campaigns need a group with one
+    ImageSizeMismatch { mc_group_id: u8, expected: usize, actual: usize }, //bytes of the fragmentation session and of the image
+}
+
+impl Display for WorldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldError::UnknownNode(dev_eui) => write!(f, "node {dev_eui} not found"),
+            WorldError::UnknownMulticastGroup(id) => write!(f, "multicast group {id} not found"),
+            WorldError::DuplicateMulticastGroup(id) => write!(f, "multicast group {id} already exists"),
+            WorldError::NoFragmentationSession(id) => write!(f, "multicast group {id} has no fragmentation session"),
+            WorldError::ImageSizeMismatch { mc_group_id, expected, actual } => {
+                write!(f, "image of {actual} bytes but the fragmentation session of multicast group {mc_group_id} carries {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WorldError {}
+
 pub struct WorldConfig {
     pub path_loss_model: PathLossModel,
     //TODO: add more configuration options
@@ -89,6 +127,19 @@ pub struct World {
 
     nc_counter: u32,
     node_counter: u32,
+    node_positions: HashMap<EUI64, (u32, Position)>, //node id and position of each node added
+
+    multicast_groups: Vec<MulticastGroup>,
+    fuota_campaigns: Vec<FuotaCampaign>,
+
+    fleet_events: Vec<FleetEvent>,
+    alarm_events: Vec<AlarmEvent>,
+    firmware_profiles: HashMap<EUI64, FirmwareProfile>,
+    default_firmware_profile: FirmwareProfile,
+    payload_profiles: HashMap<EUI64, PayloadProfile>,
+    default_payload_profile: PayloadProfile,
+    session_store: Option<SessionStore>,
+    traffic_profile: Option<TrafficProfile>,
 
     collision_counter: u32,
     successful_upload_counter: u32,
@@ -128,6 +179,17 @@ impl World {
             start_notifier,
             nc_counter: 0,
             node_counter: 0,
+            node_positions: HashMap::new(),
+            multicast_groups: Vec::new(),
+            fuota_campaigns: Vec::new(),
+            fleet_events: Vec::new(),
+            alarm_events: Vec::new(),
+            firmware_profiles: HashMap::new(),
+            default_firmware_profile: FirmwareProfile::default(),
+            payload_profiles: HashMap::new(),
+            default_payload_profile: PayloadProfile::default(),
+            session_store: None,
+            traffic_profile: None,
             collision_counter: 0,
             successful_upload_counter: 0,
         }
@@ -155,11 +217,7 @@ impl World {
         multi_node.run().await;
     }
 
-    pub async fn device_routine(mut node: Node) {
-        node.run().await;
-    }
-
-    pub fn add_node(&mut self, device: Device, config: NodeConfig, regular_traffic_model: bool) {
+    pub fn add_node(&mut self, device: Device, config: NodeConfig, traffic_model: TrafficModel) {
         let (sender, receiver) = mpsc::channel(1000);
 
         let c2 = config.clone();
@@ -170,9 +228,10 @@ impl World {
                 device,
                 NodeCommunicator::new(self.sender.clone(), receiver, config),
             ),
-            regular_traffic_model,
+            traffic_model,
         );
         node.set_dev_nonce(STARTING_DEV_NONCE);
+        self.node_positions.insert(*node.dev_eui(), (self.node_counter, c2.position));
 
         self.entities.push(Entity::Node(node));
         self.node_counter += 1;
@@ -211,11 +270,118 @@ impl World {
 
         self.entities.push(Entity::ChipstackBridge(cb));
 
-        let nc = EntityConfig::ChipstackBridge(c_config);
+        let nc = EntityConfig::ChipstackBridge(Box::new(c_config));
         self.nc_counter += 1;
         self.entity_configs.push((nc, sender));
     }
 
+    pub fn add_semtech_udp_gw(&mut self, s_config: SemtechUdpBridgeConfig) {
+        let (sender, receiver) = tokio::sync::mpsc::channel::<ReceivedTransmission>(10000);
+        let sb = SemtechUdpBridge::new(
+            self.nc_counter,
+            self.sender.clone(),
+            receiver,
+            s_config.clone(),
+        );
+
+        self.entities.push(Entity::SemtechUdpBridge(sb));
+
+        let nc = EntityConfig::SemtechUdpBridge(s_config);
+        self.nc_counter += 1;
+        self.entity_configs.push((nc, sender));
+    }
+
+    pub fn add_multicast_group(&mut self, config: MulticastGroupConfig) -> Result<(), WorldError> {
+        if self.multicast_groups.iter().any(|g| g.id() == config.setup.mc_group_id) {
+            return Err(WorldError::DuplicateMulticastGroup(config.setup.mc_group_id));
+        }
+        self.multicast_groups.push(MulticastGroup::new(config));
+        Ok(())
+    }
+
+    //must be called after add_node and before run
+    pub fn add_multicast_member(&mut self, mc_group_id: u8, dev_eui: EUI64) -> Result<(), WorldError> {
+        let (node_id, position) = *self.node_positions.get(&dev_eui).ok_or(WorldError::UnknownNode(dev_eui))?;
+        let group = self.multicast_groups.iter_mut().find(|g| g.id() == mc_group_id).ok_or(WorldError::UnknownMulticastGroup(mc_group_id))?;
+        group.add_member(node_id, dev_eui, position);
+        Ok(())
+    }
+
+    pub fn add_fuota_campaign(&mut self, campaign: FuotaCampaign) -> Result<(), WorldError> {
+        let group = self.multicast_groups.iter().find(|g| g.id() == campaign.mc_group_id).ok_or(WorldError::UnknownMulticastGroup(campaign.mc_group_id))?;
+        let fragmentation = group.config().fragmentation.ok_or(WorldError::NoFragmentationSession(campaign.mc_group_id))?;
+        let expected = fragmentation.nb_frag as usize * fragmentation.frag_size as usize - fragmentation.padding as usize;
+        if expected != campaign.image.len() {
+            return Err(WorldError::ImageSizeMismatch { mc_group_id: campaign.mc_group_id, expected, actual: campaign.image.len() });
+        }
+        self.fuota_campaigns.push(campaign);
+        Ok(())
+    }
+
+    /// Schedules a reboot storm or a mass rejoin over the nodes simulated by the multi-node engine.
+    pub fn add_fleet_event(&mut self, event: FleetEvent) {
+        assert!((0.0..=1.0).contains(&event.fraction), "Fleet event fraction must be between 0 and 1");
+        self.fleet_events.push(event);
+    }
+
+    /// Makes every node reached by the event send an alarm uplink on top of its regular traffic.
+    pub fn add_alarm_event(&mut self, event: AlarmEvent) {
+        assert!(event.propagation_speed > 0.0, "Alarm events must propagate at a positive speed");
+        self.alarm_events.push(event);
+    }
+
+    pub fn set_firmware_profile(&mut self, dev_eui: EUI64, profile: FirmwareProfile) {
+        self.firmware_profiles.insert(dev_eui, profile);
+    }
+
+    pub fn set_default_firmware_profile(&mut self, profile: FirmwareProfile) {
+        self.default_firmware_profile = profile;
+    }
+
+    pub fn set_payload_profile(&mut self, dev_eui: EUI64, profile: PayloadProfile) {
+        self.payload_profiles.insert(dev_eui, profile);
+    }
+
+    pub fn set_default_payload_profile(&mut self, profile: PayloadProfile) {
+        self.default_payload_profile = profile;
+    }
+
+    /// Device states are saved to `store` at the end of the run or on Ctrl-C.
+    pub fn set_session_store(&mut self, store: SessionStore) {
+        self.session_store = Some(store);
+    }
+
+    /// Modulates the rate of every node's traffic model by the time of day, or of the week.
+    pub fn set_traffic_profile(&mut self, profile: TrafficProfile) {
+        self.traffic_profile = Some(profile);
+    }
+
+    pub fn multicast_report(&self) -> Vec<FuotaNodeReport> {
+        self.multicast_groups.iter().flat_map(|g| g.report()).collect()
+    }
+
+    async fn fuota_campaign_routine(campaign: FuotaCampaign, group: MulticastGroupConfig, sender: Sender<Transmission>) {
+        tokio::time::sleep(campaign.start_delay).await;
+        for payload in campaign.build_downlinks(&group) {
+            let t = Transmission {
+                start_position: campaign.gateway_position,
+                start_time: World::now(),
+                frequency: group.session.frequency,
+                bandwidth: group.session.bandwidth,
+                spreading_factor: group.session.spreading_factor,
+                code_rate: Default::default(),
+                starting_power: campaign.transmission_power_dbm,
+                uplink: false,
+                payload,
+            };
+            if sender.send(t).await.is_err() {
+                eprintln!("FUOTA campaign for group {} stopped, world channel closed", campaign.mc_group_id);
+                return;
+            }
+            tokio::time::sleep(campaign.interval).await;
+        }
+    }
+
     pub fn path_loss_model(&self) -> &PathLossModel {
         &self.path_loss_model
     }
@@ -304,6 +470,24 @@ impl World {
         }
     }
 
+    //multicast members are not entities, every member in range gets the frame unless an interferer wins the power check
+    fn upload_multicast(&mut self, t: &Transmission, interferers: &[&Transmission]) {
+        let path_loss_model = self.path_loss_model;
+        for group in self.multicast_groups.iter_mut() {
+            let Some(frame) = group.accept(t) else { continue };
+            for i in 0..group.members().len() {
+                let position = group.members()[i].position;
+                let rssi = t.starting_power - path_loss_model.get_path_loss(position.distance(&t.start_position).into(), t.frequency);
+                let captured = interferers.iter().all(|other| {
+                    World::power_collision(t, other, position, &path_loss_model).is_some_and(|winner| std::ptr::eq(winner, t))
+                });
+                if rssi > get_sensitivity(t) && captured {
+                    group.deliver(i, &frame);
+                }
+            }
+        }
+    }
+
     async fn check_collisions_and_upload(&mut self) {
         let ended_transmissions = {
             let mut transmissions = self.transmissions_on_air.lock().await;
@@ -365,41 +549,99 @@ impl World {
                 sender.send(t).await.unwrap();
             }
         }
+
+        if !self.multicast_groups.is_empty() {
+            for (i, t) in ended_transmissions.iter().enumerate().filter(|(_, t)| !t.uplink) {
+                let interferers = ended_transmissions
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, t2)| *j != i && World::full_collision_check(t, t2))
+                    .map(|(_, t2)| t2)
+                    .collect::<Vec<_>>();
+                self.upload_multicast(t, &interferers);
+            }
+        }
     }
 
     pub async fn run(&mut self, duration: Option<Duration>) {
         self.start_notifier.notify_waiters();
         let mut multi_node = MultiNode::default();
+        multi_node.set_default_firmware_profile(self.default_firmware_profile);
+        for (dev_eui, profile) in std::mem::take(&mut self.firmware_profiles) {
+            multi_node.set_firmware_profile(dev_eui, profile);
+        }
+        multi_node.set_default_payload_profile(self.default_payload_profile.clone());
+        for (dev_eui, profile) in std::mem::take(&mut self.payload_profiles) {
+            multi_node.set_payload_profile(dev_eui, profile);
+        }
+        for event in std::mem::take(&mut self.fleet_events) {
+            multi_node.add_fleet_event(event);
+        }
+        for event in std::mem::take(&mut self.alarm_events) {
+            multi_node.add_alarm_event(event);
+        }
+        if let Some(profile) = self.traffic_profile.take() {
+            multi_node.set_traffic_profile(profile);
+        }
+        if let Some(store) = &self.session_store {
+            multi_node.set_session_store(store.clone());
+            let store = store.clone();
+            let handler = ctrlc::set_handler(move || {
+                match store.save() {
+                    Ok(()) => println!("Saved {} device sessions", store.len()),
+                    Err(e) => eprintln!("Could not save the device sessions: {e}"),
+                }
+                std::process::exit(130);
+            });
+            if let Err(e) = handler {
+                eprintln!("Sessions will not be saved on Ctrl-C: {e}");
+            }
+        }
 
         let entities = std::mem::take(&mut self.entities);
 
+        //one connection per broker and credentials, opened once every gateway of it has subscribed its topic
+        let mut mqtt_clients: Vec<SharedMqttClient> = Vec::new();
         for entity in entities {
             match entity {
-                Entity::Node(node) => {
-                    let mut rng = rand::rngs::StdRng::from_entropy();
-                    
-                    let node_delay = if rng.gen_range(0.0..1.0) < 0.86 {
-                        let mut delay = 0.0;
-                        while delay < 30.0 {
-                            delay = REGULAR_TRAFFIC_DISTRIBUTION.sample(&mut rng)
-                        }
-                        Duration::from_secs_f64(delay)
-                    } else {
-                        Duration::ZERO
-                    };
-                    multi_node.add_node(node, node_delay);
-                }
+                Entity::Node(node) => multi_node.add_node(node),
                 Entity::NetworkController(nc) => {
                     tokio::spawn(World::network_controller_routine(nc));
                 }
                 Entity::ChipstackBridge(c) => {
-                    tokio::spawn(c.start());
+                    let client = match mqtt_clients.iter().find(|client| client.config().same_connection(c.mqtt_config())) {
+                        Some(client) => client,
+                        None => match SharedMqttClient::new(c.mqtt_config()) {
+                            Ok(client) => {
+                                mqtt_clients.push(client);
+                                mqtt_clients.last().unwrap()
+                            }
+                            Err(e) => {
+                                eprintln!("ChirpstackBridge {} not started, invalid MQTT settings: {e}", c.id());
+                                continue;
+                            }
+                        },
+                    };
+                    tokio::spawn(c.start(client));
+                }
+                Entity::SemtechUdpBridge(s) => {
+                    tokio::spawn(s.start());
                 }
             }
         }
+        for client in mqtt_clients.iter_mut() {
+            if let Err(e) = client.connect() {
+                eprintln!("Gateways of the MQTT broker {} not connected: {e}", client.config().broker_url);
+            }
+        }
 
         tokio::spawn(World::multi_node_routine(multi_node));
 
+        for campaign in std::mem::take(&mut self.fuota_campaigns) {
+            let group = self.multicast_groups.iter().find(|g| g.id() == campaign.mc_group_id).unwrap().config().clone();
+            tokio::spawn(World::fuota_campaign_routine(campaign, group, self.sender.clone()));
+        }
+
         tokio::spawn(async move {
             use tokio::runtime::Handle;
             let handle = Handle::current().metrics();
@@ -426,12 +668,35 @@ impl World {
             }
         }
 
+        if let Some(store) = &self.session_store {
+            match store.save() {
+                Ok(()) => println!("Saved {} device sessions", store.len()),
+                Err(e) => eprintln!("Could not save the device sessions: {e}"),
+            }
+        }
+        for client in mqtt_clients.iter() {
+            client.disconnect().await;
+        }
+
         println!("END STATS: ");
         println!("Number of collisions: {}", self.collision_counter);
         println!(
             "Number of successful uploads: {}",
             self.successful_upload_counter
         );
+
+        let report = self.multicast_report();
+        if !report.is_empty() {
+            let complete = report.iter().filter(|r| r.complete).count();
+            println!("FUOTA: {complete}/{} nodes reassembled the data block", report.len());
+            FUOTA_LOGGER.write("dev_eui,mc_group_id,frames_received,fragments_received,nb_frag,recoverable_fragments,completeness");
+            for r in report.iter() {
+                FUOTA_LOGGER.write(&format!(
+                    "{},{},{},{},{},{},{}",
+                    r.dev_eui, r.mc_group_id, r.frames_received, r.fragments_received, r.nb_frag, r.recoverable_fragments, r.completeness()
+                ));
+            }
+        }
         println!("Simulation ended");
     }
 }
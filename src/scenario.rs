@@ -0,0 +1,1417 @@
+//Declarative description of a run, in TOML or JSON, instead of editing main.rs and the constants
+
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use lorawan::{
+    device::LoRaWANVersion,
+    physical_parameters::{CodeRate, DataRate, LoRaBandwidth, SpreadingFactor},
+    regional_parameters::region::Region,
+    utils::eui::EUI64,
+};
+use lorawan_device::{communicator::Position, configs::RadioDeviceConfig};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    chirpstack::{ChirpstackClient, ChirpstackError},
+    constants::{
+        ABP_GENERATED_KEYS_SHARE, CONFIRMED_SHARE, GATEWAY_MAX_TX_POWER_DBM, LOED_REGULAR_TRAFFIC_PATH, LOED_UNREGULAR_TRAFFIC_PATH, LORAWAN_1_1_SHARE,
+        OTAA_SHARE,
+    },
+    credentials::CredentialGenerator,
+    device_source::{ChirpstackSource, CsvSource, DeviceSource, DeviceSourceError, GeneratedSource, JsonLinesSource},
+    physical_simulator::{
+        activation::{provision, ActivationMode, ActivationSplit},
+        alarm::AlarmEvent,
+        chirpstack_bridge::ChirpstackBridgeConfig,
+        fleet::{FirmwareProfile, FleetEvent, FleetEventKind},
+        mqtt::MqttConfig,
+        multicast::{FragSessionSetup, FuotaCampaign, McClass, McGroupSetup, McSession, MulticastGroupConfig},
+        network_controller_bridge::NetworkControllerBridgeConfig,
+        node::{NodeConfig, NodeState},
+        path_loss::PathLossModel,
+        payload::{LppSensor, PayloadGenerator, PayloadProfile},
+        semtech_udp_bridge::SemtechUdpBridgeConfig,
+        session_store::SessionStore,
+        utils::GeoPosition,
+        world::{World, WorldConfig, WorldError},
+    },
+    traffic_models::{loed_intervals, TrafficDistribution, TrafficModel, TrafficProfile},
+};
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(io::Error),
+    Parse(String), //syntax and type errors, located by line and column
+    Invalid { field: String, message: String },
+}
+
+impl Display for ScenarioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioError::Io(e) => write!(f, "{e}"),
+            ScenarioError::Parse(e) => write!(f, "{e}"),
+            ScenarioError::Invalid { field, message } => write!(f, "{field}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+impl From<io::Error> for ScenarioError {
+    fn from(e: io::Error) -> Self {
+        ScenarioError::Io(e)
+    }
+}
+
+fn invalid<T>(field: impl Into<String>, message: impl Into<String>) -> Result<T, ScenarioError> {
+    Err(ScenarioError::Invalid { field: field.into(), message: message.into() })
+}
+
+fn check_share(field: String, share: f64) -> Result<(), ScenarioError> {
+    if !(0.0..=1.0).contains(&share) {
+        return invalid(field, format!("{share} is not between 0 and 1"));
+    }
+    Ok(())
+}
+
+fn check_positive(field: String, value: f64) -> Result<(), ScenarioError> {
+    if !(value > 0.0 && value.is_finite()) {
+        return invalid(field, format!("{value} is not positive"));
+    }
+    Ok(())
+}
+
+fn check_duration(field: String, seconds: f64) -> Result<Duration, ScenarioError> {
+    if !(seconds >= 0.0 && seconds.is_finite()) {
+        return invalid(field, format!("{seconds} is not a duration in seconds"));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn hex_array<const N: usize>(field: String, value: &str) -> Result<[u8; N], ScenarioError> {
+    if value.len() != 2 * N || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return invalid(field, format!("{value:?} is not {N} hex bytes"));
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(bytes)
+}
+
+fn chirpstack_client(url: &Option<String>, token: &Option<String>, field: &str) -> Result<ChirpstackClient, ScenarioError> {
+    if let Some(url) = url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return invalid(format!("{field}.url"), format!("{url:?} is not an HTTP URL"));
+        }
+    }
+    ChirpstackClient::new(url.clone(), token.clone()).or_else(|e| match e {
+        ChirpstackError::MissingUrl => invalid(format!("{field}.url"), e.to_string()),
+        e => invalid(format!("{field}.token"), e.to_string()),
+    })
+}
+
+fn gateway_eui(gateway_id: &str, index: usize) -> Result<[u8; 8], ScenarioError> {
+    match u64::from_str_radix(gateway_id, 16) {
+        Ok(eui) if gateway_id.len() == 16 && gateway_id.chars().all(|c| c.is_ascii_hexdigit()) => Ok(eui.to_be_bytes()),
+        _ => invalid(format!("gateways[{index}].gateway_id"), format!("{gateway_id:?} is not a 64 bit hex gateway ID")),
+    }
+}
+
+fn check_mqtt(field: &str, mqtt: &MqttConfig) -> Result<(), ScenarioError> {
+    if !["tcp://", "ssl://", "mqtt://", "mqtts://", "ws://", "wss://"].iter().any(|scheme| mqtt.broker_url.starts_with(scheme)) {
+        return invalid(format!("{field}.broker_url"), format!("{:?} is not an MQTT broker URL", mqtt.broker_url));
+    }
+    if !(0..=2).contains(&mqtt.qos) {
+        return invalid(format!("{field}.qos"), format!("{} is not 0, 1 or 2", mqtt.qos));
+    }
+    if mqtt.topic_prefix.is_empty() || mqtt.topic_prefix.contains(['+', '#']) {
+        return invalid(format!("{field}.topic_prefix"), format!("{:?} is not a topic prefix", mqtt.topic_prefix));
+    }
+    if let Some(tls) = &mqtt.tls {
+        check_file(format!("{field}.tls.ca_cert"), &tls.ca_cert)?;
+        for (name, path) in [("client_cert", &tls.client_cert), ("client_key", &tls.client_key)] {
+            if let Some(path) = path {
+                check_file(format!("{field}.tls.{name}"), path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_file(field: String, path: &Path) -> Result<(), ScenarioError> {
+    if !path.is_file() {
+        return invalid(field, format!("{} does not exist", path.display()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub world: WorldSettings,
+    pub gateways: Vec<GatewaySettings>,
+    pub device_groups: Vec<DeviceGroup>,
+    #[serde(default)]
+    pub fleet_events: Vec<FleetEventSettings>,
+    #[serde(default)]
+    pub alarm_events: Vec<AlarmEventSettings>,
+    #[serde(default)]
+    pub multicast_groups: Vec<MulticastGroupSettings>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorldSettings {
+    #[serde(default)]
+    pub path_loss_model: PathLossModel,
+    pub duration_s: Option<u64>, //without it the run goes on until stopped
+    pub seed: Option<u64>,       //of the generated fleet: keys, placement, radio and traffic draws
+    pub traffic_profile: Option<TrafficProfileSettings>,
+    pub session_store: Option<PathBuf>,
+    pub chirpstack_provisioning: Option<ChirpstackProvisioning>,
+    #[serde(default)]
+    pub mqtt: MqttConfig, //of the ChirpStack gateways
+    pub origin: Option<GeoPosition>, //of position (0, 0, 0), x pointing east and y north, to locate the gateways
+    pub payload: Option<PayloadSettings>,   //of the groups without one
+    pub firmware: Option<FirmwareSettings>, //of the groups without one
+}
+
+/// Creates the fleet in a ChirpStack application before the run, and deletes it afterwards.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChirpstackProvisioning {
+    pub url: Option<String>,   //CHIRPSTACK_API_URL otherwise
+    pub token: Option<String>, //CHIRPSTACK_API_TOKEN otherwise
+    pub application_id: String,
+    pub device_profile_id: String,
+    pub device_profile_id_1_1: Option<String>, //for the 1.1 devices, the profile of the others otherwise
+    #[serde(default = "default_remove_after")]
+    pub remove_after: bool,
+}
+
+fn default_remove_after() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficProfileSettings {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub utc_offset_h: f64,
+}
+
+impl TrafficProfileSettings {
+    fn profile(&self) -> Result<TrafficProfile, ScenarioError> {
+        TrafficProfile::from_csv(&self.path, self.utc_offset_h).or_else(|e| invalid("world.traffic_profile.path", e.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum GatewaySettings {
+    NetworkController { address: SocketAddr, position: Position },
+    Chirpstack { gateway_id: String, position: Position, mqtt: Option<MqttConfig> }, //world.mqtt otherwise
+    SemtechUdp { gateway_id: String, server: SocketAddr, position: Position }, //packet forwarder protocol, ChirpStack and most LNS listen on port 1700
+}
+
+impl GatewaySettings {
+    pub fn position(&self) -> Position {
+        match self {
+            GatewaySettings::NetworkController { position, .. } | GatewaySettings::Chirpstack { position, .. } | GatewaySettings::SemtechUdp { position, .. } => *position,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceGroup {
+    #[serde(default)]
+    pub name: String,
+    pub count: usize,
+    #[serde(default)]
+    pub source: DeviceSourceSettings,
+    pub placement: Placement,
+    #[serde(default)]
+    pub radio: RadioSettings,
+    pub traffic: TrafficSettings,
+    #[serde(default)]
+    pub activation: ActivationSettings,
+    #[serde(default = "default_lorawan_1_1_share")]
+    pub lorawan_1_1_share: f64, //of the generated, seeded and CSV devices, the others use 1.0.4
+    pub payload: Option<PayloadSettings>,   //world.payload otherwise
+    pub firmware: Option<FirmwareSettings>, //world.firmware otherwise
+}
+
+fn default_lorawan_1_1_share() -> f64 {
+    LORAWAN_1_1_SHARE
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DeviceSourceSettings {
+    #[default]
+    Generated, //random DevEUIs and keys
+    SessionsFile { path: PathBuf }, //serialized devices, one per line, like node_sessions_loed.txt
+    Csv { path: PathBuf },          //DevEUI,JoinEUI,AppKey rows, like devices_augmented.csv
+    //the fleet generate-devices exports with the same seed and prefixes
+    Seeded {
+        seed: u64,
+        #[serde(default)]
+        dev_eui_prefix: String,
+        #[serde(default)]
+        join_eui_prefix: String,
+    },
+    Chirpstack {
+        url: Option<String>,   //CHIRPSTACK_API_URL otherwise
+        token: Option<String>, //CHIRPSTACK_API_TOKEN otherwise
+        application_id: String,
+        #[serde(default = "default_fetch_sessions")]
+        fetch_sessions: bool,
+    },
+}
+
+fn default_fetch_sessions() -> bool {
+    true
+}
+
+impl DeviceSourceSettings {
+    fn source(&self, lorawan_1_1_share: f64, field: &str) -> Result<Box<dyn DeviceSource>, ScenarioError> {
+        Ok(match self {
+            DeviceSourceSettings::Generated => Box::new(GeneratedSource { lorawan_1_1_share }),
+            DeviceSourceSettings::SessionsFile { path } => Box::new(JsonLinesSource { path: path.clone() }),
+            DeviceSourceSettings::Csv { path } => Box::new(CsvSource { path: path.clone(), lorawan_1_1_share }),
+            DeviceSourceSettings::Seeded { seed, dev_eui_prefix, join_eui_prefix } => {
+                let generator = CredentialGenerator::new(*seed)
+                    .with_dev_eui_prefix(dev_eui_prefix)
+                    .or_else(|e| invalid(format!("{field}.dev_eui_prefix"), e.to_string()))?
+                    .with_join_eui_prefix(join_eui_prefix)
+                    .or_else(|e| invalid(format!("{field}.join_eui_prefix"), e.to_string()))?;
+                Box::new(generator.with_lorawan_1_1_share(lorawan_1_1_share))
+            }
+            DeviceSourceSettings::Chirpstack { url, token, application_id, fetch_sessions } => Box::new(ChirpstackSource {
+                client: chirpstack_client(url, token, field)?,
+                application_id: application_id.clone(),
+                fetch_sessions: *fetch_sessions,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Placement {
+    Box { min: Position, max: Position },
+    Disc { centre: Position, radius: f32 }, //at the height of the centre
+    //in a square of side `side` from each gateway position, in turn, up to `max_height`
+    NearGateways { side: f32, #[serde(default = "default_max_height")] max_height: f32 },
+}
+
+fn default_max_height() -> f32 {
+    10.0
+}
+
+/// Devices take the combinations of spreading factor, bandwidth and frequency in turn.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RadioSettings {
+    pub spreading_factors: Vec<u8>,
+    pub bandwidths_khz: Vec<u32>,
+    pub frequencies_hz: Vec<f64>,
+    pub tx_power_dbm: f32,
+}
+
+impl Default for RadioSettings {
+    fn default() -> Self {
+        RadioSettings {
+            spreading_factors: vec![7],
+            bandwidths_khz: vec![125],
+            frequencies_hz: vec![868_100_000.0, 868_300_000.0],
+            tx_power_dbm: 14.0,
+        }
+    }
+}
+
+impl RadioSettings {
+    fn combinations(&self) -> Vec<(SpreadingFactor, LoRaBandwidth, f64)> {
+        let mut combinations = Vec::new();
+        for sf in self.spreading_factors.iter() {
+            for bw in self.bandwidths_khz.iter() {
+                for freq in self.frequencies_hz.iter() {
+                    combinations.push((spreading_factor(*sf).unwrap(), bandwidth(*bw).unwrap(), *freq));
+                }
+            }
+        }
+        combinations
+    }
+}
+
+pub fn spreading_factor(sf: u8) -> Option<SpreadingFactor> {
+    match sf {
+        7 => Some(SpreadingFactor::SF7),
+        8 => Some(SpreadingFactor::SF8),
+        9 => Some(SpreadingFactor::SF9),
+        10 => Some(SpreadingFactor::SF10),
+        11 => Some(SpreadingFactor::SF11),
+        12 => Some(SpreadingFactor::SF12),
+        _ => None,
+    }
+}
+
+pub fn bandwidth(khz: u32) -> Option<LoRaBandwidth> {
+    match khz {
+        125 => Some(LoRaBandwidth::BW125),
+        250 => Some(LoRaBandwidth::BW250),
+        500 => Some(LoRaBandwidth::BW500),
+        _ => None,
+    }
+}
+
+//EU868 data rates
+fn data_rate(sf: SpreadingFactor, bandwidth: LoRaBandwidth) -> DataRate {
+    match (sf, bandwidth) {
+        (SpreadingFactor::SF7, LoRaBandwidth::BW250) => DataRate::DR6,
+        (SpreadingFactor::SF12, _) => DataRate::DR0,
+        (SpreadingFactor::SF11, _) => DataRate::DR1,
+        (SpreadingFactor::SF10, _) => DataRate::DR2,
+        (SpreadingFactor::SF9, _) => DataRate::DR3,
+        (SpreadingFactor::SF8, _) => DataRate::DR4,
+        (SpreadingFactor::SF7, _) => DataRate::DR5,
+    }
+}
+
+fn loed_regular_path() -> PathBuf {
+    PathBuf::from(LOED_REGULAR_TRAFFIC_PATH)
+}
+
+fn loed_unregular_path() -> PathBuf {
+    PathBuf::from(LOED_UNREGULAR_TRAFFIC_PATH)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TrafficSettings {
+    //a period drawn once per node from the LOED table of periods
+    LoedRegular { #[serde(default = "loed_regular_path")] path: PathBuf },
+    LoedUnregular { #[serde(default = "loed_unregular_path")] path: PathBuf },
+    Mix { models: Vec<TrafficShare> }, //each node of the group follows one of the models
+    Periodic { period: f64 },
+    PeriodicWithJitter { period: f64, jitter: f64 },
+    Poisson { mean: f64 },
+    Uniform { min: f64, max: f64 },
+    OnOff { on_mean: f64, off_mean: f64, interval_mean: f64 },
+    LogNormal { mu: f64, sigma: f64 },
+    Weibull { shape: f64, scale: f64 },
+    Empirical { path: PathBuf },
+    Table { path: PathBuf, #[serde(default)] interpolate: bool }, //value,probability lines
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficShare {
+    pub share: f64,
+    pub model: TrafficSettings,
+}
+
+//what the nodes of a group draw their traffic model from
+enum GroupTraffic {
+    Model(TrafficModel),
+    Periods(Arc<TrafficDistribution>),
+    Mix(Vec<(f64, GroupTraffic)>),
+}
+
+impl GroupTraffic {
+    fn model<R: Rng + ?Sized>(&self, rng: &mut R) -> TrafficModel {
+        match self {
+            GroupTraffic::Model(model) => model.clone(),
+            GroupTraffic::Periods(periods) => TrafficModel::loed_regular(periods, rng),
+            GroupTraffic::Mix(models) => {
+                let mut draw = rng.gen_range(0.0..1.0);
+                for (share, traffic) in models.iter() {
+                    if draw < *share {
+                        return traffic.model(rng);
+                    }
+                    draw -= share;
+                }
+                //the shares sum to slightly less than 1
+                models[models.len() - 1].1.model(rng)
+            }
+        }
+    }
+}
+
+impl TrafficSettings {
+    fn validate(&self, field: &str) -> Result<(), ScenarioError> {
+        let positive = |name: &str, value: f64| check_positive(format!("{field}.{name}"), value);
+        match self {
+            TrafficSettings::Mix { models } => {
+                if models.is_empty() {
+                    return invalid(format!("{field}.models"), "no models");
+                }
+                for (k, m) in models.iter().enumerate() {
+                    check_share(format!("{field}.models[{k}].share"), m.share)?;
+                    m.model.validate(&format!("{field}.models[{k}].model"))?;
+                }
+                let total = models.iter().map(|m| m.share).sum::<f64>();
+                if (total - 1.0).abs() > 1e-9 {
+                    return invalid(format!("{field}.models"), format!("the shares add up to {total}, not 1"));
+                }
+                Ok(())
+            }
+            TrafficSettings::Periodic { period } => positive("period", *period),
+            TrafficSettings::PeriodicWithJitter { period, jitter } => {
+                positive("period", *period)?;
+                if *jitter < 0.0 {
+                    return invalid(format!("{field}.jitter"), format!("{jitter} is negative"));
+                }
+                Ok(())
+            }
+            TrafficSettings::Poisson { mean } => positive("mean", *mean),
+            TrafficSettings::Uniform { min, max } => {
+                if !(0.0 <= *min && min <= max) {
+                    return invalid(format!("{field}.max"), format!("[{min}, {max}] is not a range of positive intervals"));
+                }
+                Ok(())
+            }
+            TrafficSettings::OnOff { on_mean, off_mean, interval_mean } => {
+                positive("on_mean", *on_mean)?;
+                positive("off_mean", *off_mean)?;
+                positive("interval_mean", *interval_mean)
+            }
+            TrafficSettings::LogNormal { sigma, .. } => positive("sigma", *sigma),
+            TrafficSettings::Weibull { shape, scale } => {
+                positive("shape", *shape)?;
+                positive("scale", *scale)
+            }
+            //loaded like when building, so that a bad file is reported now
+            TrafficSettings::LoedRegular { .. } | TrafficSettings::LoedUnregular { .. } | TrafficSettings::Empirical { .. } | TrafficSettings::Table { .. } => {
+                self.model_source(field).map(|_| ())
+            }
+        }
+    }
+
+    //tables are loaded once and shared by the nodes of the group
+    fn model_source(&self, field: &str) -> Result<GroupTraffic, ScenarioError> {
+        let loed = |path: &PathBuf| loed_intervals(path).map(Arc::new).or_else(|e| invalid(format!("{field}.path"), e.to_string()));
+        Ok(GroupTraffic::Model(match self {
+            TrafficSettings::LoedRegular { path } => return Ok(GroupTraffic::Periods(loed(path)?)),
+            TrafficSettings::LoedUnregular { path } => TrafficModel::Custom(loed(path)?),
+            TrafficSettings::Mix { models } => {
+                let mut mix = Vec::with_capacity(models.len());
+                for (k, m) in models.iter().enumerate() {
+                    mix.push((m.share, m.model.model_source(&format!("{field}.models[{k}].model"))?));
+                }
+                return Ok(GroupTraffic::Mix(mix));
+            }
+            TrafficSettings::Periodic { period } => TrafficModel::Periodic(*period),
+            TrafficSettings::PeriodicWithJitter { period, jitter } => TrafficModel::PeriodicWithJitter { period: *period, jitter: *jitter },
+            TrafficSettings::Poisson { mean } => TrafficModel::Poisson { mean: *mean },
+            TrafficSettings::Uniform { min, max } => TrafficModel::Uniform { min: *min, max: *max },
+            TrafficSettings::OnOff { on_mean, off_mean, interval_mean } => {
+                TrafficModel::OnOff { on_mean: *on_mean, off_mean: *off_mean, interval_mean: *interval_mean }
+            }
+            TrafficSettings::LogNormal { mu, sigma } => TrafficModel::LogNormal { mu: *mu, sigma: *sigma },
+            TrafficSettings::Weibull { shape, scale } => TrafficModel::Weibull { shape: *shape, scale: *scale },
+            TrafficSettings::Empirical { path } => TrafficModel::empirical(path).or_else(|e| invalid(format!("{field}.path"), e.to_string()))?,
+            TrafficSettings::Table { path, interpolate } => {
+                let name = path.display().to_string();
+                let table = TrafficDistribution::new(path, name).or_else(|e| invalid(format!("{field}.path"), e.to_string()))?;
+                TrafficModel::Custom(Arc::new(table.with_interpolation(*interpolate)))
+            }
+        }))
+    }
+}
+
+/// Shares of the group activated by OTAA and by ABP with generated keys, see `ActivationSplit`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActivationSettings {
+    pub otaa: f64,
+    pub abp_generated_keys: f64,
+}
+
+impl Default for ActivationSettings {
+    fn default() -> Self {
+        ActivationSettings { otaa: OTAA_SHARE, abp_generated_keys: ABP_GENERATED_KEYS_SHARE }
+    }
+}
+
+/// What the uplinks of a group carry, see `PayloadProfile`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PayloadSettings {
+    pub generator: PayloadGeneratorSettings,
+    #[serde(default = "default_f_port")]
+    pub f_port: u8,
+    #[serde(default = "default_confirmed_share")]
+    pub confirmed_share: f64,
+}
+
+fn default_f_port() -> u8 {
+    PayloadProfile::default().f_port
+}
+
+fn default_confirmed_share() -> f64 {
+    CONFIRMED_SHARE
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PayloadGeneratorSettings {
+    Text,
+    Fixed { size: usize },
+    Sizes { path: PathBuf, #[serde(default)] interpolate: bool }, //value,probability lines, in bytes
+    CayenneLpp { sensors: Vec<LppSensor> },
+    Replay { path: PathBuf }, //hex payloads, one per line
+}
+
+impl PayloadSettings {
+    fn profile(&self, field: &str) -> Result<PayloadProfile, ScenarioError> {
+        if !(1..=223).contains(&self.f_port) {
+            return invalid(format!("{field}.f_port"), format!("{} is not an application port, from 1 to 223", self.f_port));
+        }
+        check_share(format!("{field}.confirmed_share"), self.confirmed_share)?;
+        let path = format!("{field}.generator.path");
+        let generator = match &self.generator {
+            PayloadGeneratorSettings::Text => PayloadGenerator::Text,
+            PayloadGeneratorSettings::Fixed { size } => PayloadGenerator::Fixed(*size),
+            PayloadGeneratorSettings::Sizes { path: table, interpolate } => {
+                let sizes = TrafficDistribution::new(table, table.display().to_string()).or_else(|e| invalid(path, e.to_string()))?;
+                PayloadGenerator::Sizes(Arc::new(sizes.with_interpolation(*interpolate)))
+            }
+            PayloadGeneratorSettings::CayenneLpp { sensors } => {
+                if sensors.is_empty() {
+                    return invalid(format!("{field}.generator.sensors"), "no sensors");
+                }
+                PayloadGenerator::CayenneLpp(sensors.clone())
+            }
+            PayloadGeneratorSettings::Replay { path: payloads } => PayloadGenerator::replay(payloads).or_else(|e| invalid(path, e.to_string()))?,
+        };
+        Ok(PayloadProfile { generator, f_port: self.f_port, confirmed_share: self.confirmed_share })
+    }
+}
+
+/// What the firmware of a group keeps across a reboot, see `FirmwareProfile`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirmwareSettings {
+    pub keeps_session: bool,
+    pub keeps_dev_nonce: bool,
+    pub boot_time_s: f64,
+    pub boot_jitter_s: f64,
+}
+
+impl Default for FirmwareSettings {
+    fn default() -> Self {
+        let profile = FirmwareProfile::default();
+        FirmwareSettings {
+            keeps_session: profile.keeps_session,
+            keeps_dev_nonce: profile.keeps_dev_nonce,
+            boot_time_s: profile.boot_time.as_secs_f64(),
+            boot_jitter_s: profile.boot_jitter.as_secs_f64(),
+        }
+    }
+}
+
+impl FirmwareSettings {
+    fn profile(&self, field: &str) -> Result<FirmwareProfile, ScenarioError> {
+        Ok(FirmwareProfile {
+            keeps_session: self.keeps_session,
+            keeps_dev_nonce: self.keeps_dev_nonce,
+            boot_time: check_duration(format!("{field}.boot_time_s"), self.boot_time_s)?,
+            boot_jitter: check_duration(format!("{field}.boot_jitter_s"), self.boot_jitter_s)?,
+        })
+    }
+}
+
+/// A reboot storm or a mass rejoin, see `FleetEvent`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetEventSettings {
+    pub kind: FleetEventKind,
+    pub at_s: f64,
+    pub fraction: f64,
+    #[serde(default)]
+    pub outage_s: f64, //of the reboots, rejoining nodes stay on
+}
+
+impl FleetEventSettings {
+    fn event(&self, field: &str) -> Result<FleetEvent, ScenarioError> {
+        let at = check_duration(format!("{field}.at_s"), self.at_s)?;
+        check_share(format!("{field}.fraction"), self.fraction)?;
+        let outage = check_duration(format!("{field}.outage_s"), self.outage_s)?;
+        if self.kind == FleetEventKind::Rejoin && !outage.is_zero() {
+            return invalid(format!("{field}.outage_s"), "rejoining nodes stay on");
+        }
+        Ok(FleetEvent { at, fraction: self.fraction, outage, kind: self.kind })
+    }
+}
+
+/// Alarms sent by every device reached by a physical event, see `AlarmEvent`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlarmEventSettings {
+    pub at_s: f64,
+    pub centre: Position,
+    pub radius: f32,
+    pub propagation_speed: f32,     //meters per second, inf for events sensed everywhere at once
+    pub reaction_delay_s: Option<f64>,  //1 s otherwise
+    pub reaction_jitter_s: Option<f64>, //2 s otherwise
+    pub payload: Option<PayloadSettings>, //the payload of each device otherwise
+}
+
+impl AlarmEventSettings {
+    fn event(&self, field: &str) -> Result<AlarmEvent, ScenarioError> {
+        let at = check_duration(format!("{field}.at_s"), self.at_s)?;
+        for (name, value) in [("radius", self.radius), ("propagation_speed", self.propagation_speed)] {
+            if value.is_nan() || value <= 0.0 {
+                return invalid(format!("{field}.{name}"), format!("{value} is not positive"));
+            }
+        }
+        let mut event = AlarmEvent::new(at, self.centre, self.radius, self.propagation_speed);
+        if let Some(delay) = self.reaction_delay_s {
+            event.reaction_delay = check_duration(format!("{field}.reaction_delay_s"), delay)?;
+        }
+        if let Some(jitter) = self.reaction_jitter_s {
+            event.reaction_jitter = check_duration(format!("{field}.reaction_jitter_s"), jitter)?;
+        }
+        if let Some(payload) = &self.payload {
+            event = event.with_payload(payload.profile(&format!("{field}.payload"))?);
+        }
+        Ok(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum McClassSettings {
+    B,
+    #[default]
+    C,
+}
+
+/// A multicast group of the devices of some groups, and the firmware image the simulator pushes to it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MulticastGroupSettings {
+    pub id: u8,          //McGroupID, from 0 to 3
+    pub mc_addr: String, //hex, MSB first
+    pub mc_key: String,  //hex
+    #[serde(default)]
+    pub min_f_cnt: u32,
+    #[serde(default = "default_max_mc_f_cnt")]
+    pub max_f_cnt: u32,
+    #[serde(default)]
+    pub class: McClassSettings,
+    #[serde(default)]
+    pub ping_slot_periodicity: u8, //of class B groups
+    #[serde(default = "default_mc_frequency")]
+    pub frequency_hz: f64,
+    #[serde(default = "default_mc_spreading_factor")]
+    pub spreading_factor: u8,
+    #[serde(default = "default_mc_bandwidth")]
+    pub bandwidth_khz: u32,
+    pub members: Vec<String>, //names of the device groups whose devices join it
+    pub fuota: Option<FuotaSettings>,
+}
+
+fn default_max_mc_f_cnt() -> u32 {
+    u32::MAX
+}
+
+//RX2 of EU868
+fn default_mc_frequency() -> f64 {
+    869_525_000.0
+}
+
+fn default_mc_spreading_factor() -> u8 {
+    12
+}
+
+fn default_mc_bandwidth() -> u32 {
+    125
+}
+
+/// A firmware image sent in fragments by a gateway of the scenario, see `FuotaCampaign`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FuotaSettings {
+    pub image: PathBuf,
+    pub frag_size: u8,
+    #[serde(default)]
+    pub redundancy: u16, //coded fragments sent after the uncoded ones
+    pub gateway: usize,  //index in gateways
+    #[serde(default = "default_fuota_tx_power")]
+    pub tx_power_dbm: f32,
+    #[serde(default)]
+    pub start_delay_s: f64,
+    pub interval_s: f64,
+}
+
+fn default_fuota_tx_power() -> f32 {
+    14.0
+}
+
+//largest EU868 FRMPayload of a downlink at 125 kHz
+fn max_frm_payload(sf: SpreadingFactor) -> usize {
+    match sf {
+        SpreadingFactor::SF12 | SpreadingFactor::SF11 | SpreadingFactor::SF10 => 51,
+        SpreadingFactor::SF9 => 115,
+        _ => 222,
+    }
+}
+
+impl MulticastGroupSettings {
+    fn group(&self, field: &str, scenario: &Scenario) -> Result<(MulticastGroupConfig, Option<FuotaCampaign>), ScenarioError> {
+        if self.id > 3 {
+            return invalid(format!("{field}.id"), format!("{} is not a multicast group ID, from 0 to 3", self.id));
+        }
+        if self.min_f_cnt > self.max_f_cnt {
+            return invalid(format!("{field}.max_f_cnt"), format!("{} is below min_f_cnt", self.max_f_cnt));
+        }
+        let class = match self.class {
+            McClassSettings::B if self.ping_slot_periodicity > 7 => {
+                return invalid(format!("{field}.ping_slot_periodicity"), format!("{} is not between 0 and 7", self.ping_slot_periodicity));
+            }
+            McClassSettings::B => McClass::B { ping_slot_periodicity: self.ping_slot_periodicity },
+            McClassSettings::C => McClass::C,
+        };
+        if !(863_000_000.0..=870_000_000.0).contains(&self.frequency_hz) {
+            return invalid(format!("{field}.frequency_hz"), format!("{} Hz is outside of the EU868 band", self.frequency_hz));
+        }
+        let Some(sf) = spreading_factor(self.spreading_factor) else {
+            return invalid(format!("{field}.spreading_factor"), format!("SF{} is not between SF7 and SF12", self.spreading_factor));
+        };
+        let Some(bw) = bandwidth(self.bandwidth_khz) else {
+            return invalid(format!("{field}.bandwidth_khz"), format!("{} kHz is not 125, 250 or 500", self.bandwidth_khz));
+        };
+        for (j, member) in self.members.iter().enumerate() {
+            if !scenario.device_groups.iter().any(|g| g.name == *member) {
+                return invalid(format!("{field}.members[{j}]"), format!("no device group is named {member:?}"));
+            }
+        }
+        let setup = McGroupSetup {
+            mc_group_id: self.id,
+            mc_addr: hex_array(format!("{field}.mc_addr"), &self.mc_addr)?,
+            mc_key: hex_array::<16>(format!("{field}.mc_key"), &self.mc_key)?.into(),
+            min_mc_f_cnt: self.min_f_cnt,
+            max_mc_f_cnt: self.max_f_cnt,
+        };
+        let session = McSession { class, frequency: self.frequency_hz, spreading_factor: sf, bandwidth: bw };
+        let Some(fuota) = &self.fuota else {
+            return Ok((MulticastGroupConfig { setup, session, fragmentation: None }, None));
+        };
+
+        let field = format!("{field}.fuota");
+        let image = std::fs::read(&fuota.image).or_else(|e| invalid(format!("{field}.image"), format!("{}: {e}", fuota.image.display())))?;
+        if image.is_empty() {
+            return invalid(format!("{field}.image"), format!("{} is empty", fuota.image.display()));
+        }
+        //the DataFragment header takes 3 bytes
+        if fuota.frag_size == 0 || 3 + fuota.frag_size as usize > max_frm_payload(sf) {
+            return invalid(format!("{field}.frag_size"), format!("{} bytes fragments do not fit in SF{} downlinks", fuota.frag_size, self.spreading_factor));
+        }
+        //fragment numbers take 14 bits, and every fragment a frame counter of the group
+        let fragments = image.len().div_ceil(fuota.frag_size as usize) + fuota.redundancy as usize;
+        if fragments >= 1 << 14 || fragments as u64 > (self.max_f_cnt - self.min_f_cnt) as u64 + 1 {
+            return invalid(format!("{field}.redundancy"), format!("{fragments} fragments are too many for the group"));
+        }
+        let Some(gateway) = scenario.gateways.get(fuota.gateway) else {
+            return invalid(format!("{field}.gateway"), format!("there is no gateway {}", fuota.gateway));
+        };
+        if fuota.tx_power_dbm > GATEWAY_MAX_TX_POWER_DBM as f32 {
+            return invalid(format!("{field}.tx_power_dbm"), format!("{} dBm is above the {GATEWAY_MAX_TX_POWER_DBM} dBm of a gateway", fuota.tx_power_dbm));
+        }
+        let interval = check_duration(format!("{field}.interval_s"), fuota.interval_s)?;
+        if interval.is_zero() {
+            return invalid(format!("{field}.interval_s"), "must be positive");
+        }
+        let fragmentation = FragSessionSetup::for_image(0, image.len(), fuota.frag_size);
+        let campaign = FuotaCampaign {
+            mc_group_id: self.id,
+            gateway_position: gateway.position(),
+            transmission_power_dbm: fuota.tx_power_dbm,
+            start_delay: check_duration(format!("{field}.start_delay_s"), fuota.start_delay_s)?,
+            interval,
+            image,
+            redundancy: fuota.redundancy,
+        };
+        Ok((MulticastGroupConfig { setup, session, fragmentation: Some(fragmentation) }, Some(campaign)))
+    }
+}
+
+pub fn device_config(position: Position, sf: SpreadingFactor, freq: f64, bandwidth: LoRaBandwidth, tx_power_dbm: f32) -> NodeConfig {
+    NodeConfig {
+        position,
+        transmission_power_dbm: tx_power_dbm,
+        receiver_sensitivity: -120.0,
+        tx_consumption: 0.0,
+        rx_consumption: 0.0,
+        idle_consumption: 0.0,
+        sleep_consumption: 0.0,
+        node_state: Arc::new(Mutex::new(NodeState::Idle)),
+        radio_config: RadioDeviceConfig {
+            region: Region::EU863_870,
+            spreading_factor: sf,
+            data_rate: data_rate(sf, bandwidth),
+            bandwidth,
+            freq,
+            sample_rate: 1.0,
+            rx_chan_id: 1,
+            tx_chan_id: 1,
+            code_rate: CodeRate::CR4_5,
+        },
+        rx_windows: Default::default(),
+    }
+}
+
+pub fn gateway_config(position: Position) -> NodeConfig {
+    NodeConfig {
+        position,
+        transmission_power_dbm: 14.0,
+        receiver_sensitivity: -120.0,
+        tx_consumption: 0.0,
+        rx_consumption: 0.0,
+        idle_consumption: 0.0,
+        sleep_consumption: 0.0,
+        node_state: Arc::new(Mutex::new(NodeState::Receiving)),
+        radio_config: RadioDeviceConfig {
+            region: Region::EU863_870,
+            spreading_factor: Default::default(), //not important
+            data_rate: DataRate::DR5,
+            bandwidth: Default::default(), //not important
+            freq: 0.0,                     //not important
+            sample_rate: 1.0,
+            rx_chan_id: 1,
+            tx_chan_id: 1,
+            code_rate: CodeRate::CR4_5,
+        },
+        rx_windows: Default::default(),
+    }
+}
+
+impl Scenario {
+    /// JSON for `.json` files, TOML otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scenario, ScenarioError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let scenario = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&content).map_err(|e| ScenarioError::Parse(format!("{}: {e}", path.display())))?
+        } else {
+            Scenario::from_toml(&content).map_err(|e| match e {
+                ScenarioError::Parse(e) => ScenarioError::Parse(format!("{}: {e}", path.display())),
+                e => e,
+            })?
+        };
+        Ok(scenario)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Scenario, ScenarioError> {
+        toml::from_str(content).map_err(|e| ScenarioError::Parse(e.to_string()))
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.world.duration_s.map(Duration::from_secs)
+    }
+
+    pub fn device_count(&self) -> usize {
+        self.device_groups.iter().map(|g| g.count).sum()
+    }
+
+    /// Scales every group so that the scenario has `total` devices, keeping their proportions and at least one device per group.
+    pub fn set_device_count(&mut self, total: usize) -> Result<(), ScenarioError> {
+        let current = self.device_count();
+        if current == 0 {
+            return Ok(());
+        }
+        let n = self.device_groups.len();
+        if total < n {
+            return invalid("devices", format!("{total} devices can not fill the {n} device groups"));
+        }
+        for group in self.device_groups.iter_mut() {
+            group.count = (((group.count * total) as f64 / current as f64).round() as usize).max(1);
+        }
+        //the largest group absorbs what rounding left, never below one device since the total covers every group
+        loop {
+            let assigned = self.device_count();
+            let largest = self.device_groups.iter_mut().max_by_key(|g| g.count).unwrap();
+            match assigned.cmp(&total) {
+                Ordering::Less => largest.count += total - assigned,
+                Ordering::Greater => largest.count -= (assigned - total).min(largest.count - 1),
+                Ordering::Equal => return Ok(()),
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ScenarioError> {
+        if self.world.duration_s == Some(0) {
+            return invalid("world.duration_s", "must be positive");
+        }
+        if let Some(payload) = &self.world.payload {
+            payload.profile("world.payload")?;
+        }
+        if let Some(firmware) = &self.world.firmware {
+            firmware.profile("world.firmware")?;
+        }
+        if let Some(profile) = &self.world.traffic_profile {
+            profile.profile()?;
+        }
+        if let Some(provisioning) = &self.world.chirpstack_provisioning {
+            chirpstack_client(&provisioning.url, &provisioning.token, "world.chirpstack_provisioning")?;
+        }
+        if let Some(origin) = &self.world.origin {
+            if !(-90.0..=90.0).contains(&origin.latitude) || !(-180.0..=180.0).contains(&origin.longitude) {
+                return invalid("world.origin", "latitude must be within ±90 degrees and longitude within ±180");
+            }
+        }
+        if self.gateways.is_empty() {
+            return invalid("gateways", "at least one gateway is needed");
+        }
+        check_mqtt("world.mqtt", &self.world.mqtt)?;
+        for (i, gateway) in self.gateways.iter().enumerate() {
+            match gateway {
+                GatewaySettings::Chirpstack { gateway_id, mqtt, .. } => {
+                    gateway_eui(gateway_id, i)?;
+                    if let Some(mqtt) = mqtt {
+                        check_mqtt(&format!("gateways[{i}].mqtt"), mqtt)?;
+                    }
+                }
+                GatewaySettings::SemtechUdp { gateway_id, .. } => {
+                    gateway_eui(gateway_id, i)?;
+                }
+                GatewaySettings::NetworkController { .. } => (),
+            }
+        }
+
+        for (i, group) in self.device_groups.iter().enumerate() {
+            let field = format!("device_groups[{i}]");
+            if group.count == 0 {
+                return invalid(format!("{field}.count"), "must be positive");
+            }
+            match &group.source {
+                DeviceSourceSettings::Generated => (),
+                DeviceSourceSettings::SessionsFile { path } | DeviceSourceSettings::Csv { path } => check_file(format!("{field}.source.path"), path)?,
+                DeviceSourceSettings::Seeded { .. } => {
+                    group.source.source(group.lorawan_1_1_share, &format!("{field}.source"))?;
+                }
+                DeviceSourceSettings::Chirpstack { .. } => {
+                    group.source.source(group.lorawan_1_1_share, &format!("{field}.source"))?;
+                    if self.world.chirpstack_provisioning.is_some() {
+                        return invalid(format!("{field}.source"), "devices of ChirpStack cannot be provisioned into it again");
+                    }
+                }
+            }
+            match &group.placement {
+                Placement::Box { min, max } => {
+                    if min.x > max.x || min.y > max.y || min.z > max.z {
+                        return invalid(format!("{field}.placement.max"), "must not be below min on any axis");
+                    }
+                }
+                Placement::Disc { radius, .. } => check_positive(format!("{field}.placement.radius"), *radius as f64)?,
+                Placement::NearGateways { side, max_height } => {
+                    check_positive(format!("{field}.placement.side"), *side as f64)?;
+                    if *max_height < 0.0 {
+                        return invalid(format!("{field}.placement.max_height"), format!("{max_height} is negative"));
+                    }
+                }
+            }
+
+            let radio = &group.radio;
+            for (name, empty) in [
+                ("spreading_factors", radio.spreading_factors.is_empty()),
+                ("bandwidths_khz", radio.bandwidths_khz.is_empty()),
+                ("frequencies_hz", radio.frequencies_hz.is_empty()),
+            ] {
+                if empty {
+                    return invalid(format!("{field}.radio.{name}"), "must not be empty");
+                }
+            }
+            for (j, sf) in radio.spreading_factors.iter().enumerate() {
+                if spreading_factor(*sf).is_none() {
+                    return invalid(format!("{field}.radio.spreading_factors[{j}]"), format!("SF{sf} is not between SF7 and SF12"));
+                }
+            }
+            for (j, bw) in radio.bandwidths_khz.iter().enumerate() {
+                if bandwidth(*bw).is_none() {
+                    return invalid(format!("{field}.radio.bandwidths_khz[{j}]"), format!("{bw} kHz is not 125, 250 or 500"));
+                }
+            }
+            for (j, freq) in radio.frequencies_hz.iter().enumerate() {
+                if !(863_000_000.0..=870_000_000.0).contains(freq) {
+                    return invalid(format!("{field}.radio.frequencies_hz[{j}]"), format!("{freq} Hz is outside of the EU868 band"));
+                }
+            }
+            if !(-10.0..=27.0).contains(&radio.tx_power_dbm) {
+                return invalid(format!("{field}.radio.tx_power_dbm"), format!("{} dBm is not between -10 and 27", radio.tx_power_dbm));
+            }
+
+            group.traffic.validate(&format!("{field}.traffic"))?;
+            check_share(format!("{field}.activation.otaa"), group.activation.otaa)?;
+            check_share(format!("{field}.activation.abp_generated_keys"), group.activation.abp_generated_keys)?;
+            if group.activation.otaa + group.activation.abp_generated_keys > 1.0 {
+                return invalid(format!("{field}.activation"), "the shares add up to more than 1");
+            }
+            check_share(format!("{field}.lorawan_1_1_share"), group.lorawan_1_1_share)?;
+            if let Some(payload) = &group.payload {
+                payload.profile(&format!("{field}.payload"))?;
+            }
+            if let Some(firmware) = &group.firmware {
+                firmware.profile(&format!("{field}.firmware"))?;
+            }
+        }
+
+        for (i, event) in self.fleet_events.iter().enumerate() {
+            event.event(&format!("fleet_events[{i}]"))?;
+        }
+        for (i, event) in self.alarm_events.iter().enumerate() {
+            event.event(&format!("alarm_events[{i}]"))?;
+        }
+        let mut ids = HashSet::new();
+        for (i, group) in self.multicast_groups.iter().enumerate() {
+            group.group(&format!("multicast_groups[{i}]"), self)?;
+            if !ids.insert(group.id) {
+                return invalid(format!("multicast_groups[{i}].id"), format!("group {} is defined twice", group.id));
+            }
+        }
+        Ok(())
+    }
+
+    fn position<R: Rng + ?Sized>(&self, placement: &Placement, index: usize, rng: &mut R) -> Position {
+        let between = |low: f32, high: f32, rng: &mut R| if low < high { rng.gen_range(low..high) } else { low };
+        match placement {
+            Placement::Box { min, max } => Position {
+                x: between(min.x, max.x, rng),
+                y: between(min.y, max.y, rng),
+                z: between(min.z, max.z, rng),
+            },
+            Placement::Disc { centre, radius } => {
+                //uniform over the area
+                let distance = radius * rng.gen_range(0.0..1.0f32).sqrt();
+                let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                Position { x: centre.x + distance * angle.cos(), y: centre.y + distance * angle.sin(), z: centre.z }
+            }
+            Placement::NearGateways { side, max_height } => {
+                let gateway = self.gateways[index % self.gateways.len()].position();
+                Position {
+                    x: between(gateway.x, gateway.x + side, rng),
+                    y: between(gateway.y, gateway.y + side, rng),
+                    z: between(0.0, *max_height, rng),
+                }
+            }
+        }
+    }
+
+    /// Validates the scenario and builds its world, with every gateway and device added. Needs a Tokio runtime.
+    pub async fn build(&self) -> Result<World, ScenarioError> {
+        self.validate()?;
+        let mut rng = match self.world.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut w = World::new(WorldConfig { path_loss_model: self.world.path_loss_model });
+
+        //devices saved by a previous run take the place of the loaded or generated ones, with their counters
+        let store = match &self.world.session_store {
+            Some(path) => Some(SessionStore::open(path).or_else(|e| invalid("world.session_store", e.to_string()))?),
+            None => None,
+        };
+
+        let provisioning = match &self.world.chirpstack_provisioning {
+            Some(p) => Some((chirpstack_client(&p.url, &p.token, "world.chirpstack_provisioning")?, p)),
+            None => None,
+        };
+
+        if let Some(payload) = &self.world.payload {
+            w.set_default_payload_profile(payload.profile("world.payload")?);
+        }
+        if let Some(firmware) = &self.world.firmware {
+            w.set_default_firmware_profile(firmware.profile("world.firmware")?);
+        }
+
+        //DevEUIs of each group, for the multicast groups
+        let mut members: Vec<Vec<EUI64>> = Vec::new();
+        for (i, group) in self.device_groups.iter().enumerate() {
+            let field = format!("device_groups[{i}]");
+            let radio = group.radio.combinations();
+            let traffic = group.traffic.model_source(&format!("{field}.traffic"))?;
+            let payload = group.payload.as_ref().map(|p| p.profile(&format!("{field}.payload"))).transpose()?;
+            let firmware = group.firmware.as_ref().map(|f| f.profile(&format!("{field}.firmware"))).transpose()?;
+            let split = ActivationSplit { otaa: group.activation.otaa, abp_generated_keys: group.activation.abp_generated_keys };
+            let mut source = group.source.source(group.lorawan_1_1_share, &format!("{field}.source"))?;
+            let devices = source.load(group.count, &mut rng).await.or_else(|e| match e {
+                DeviceSourceError::Exhausted { .. } => invalid(format!("{field}.count"), e.to_string()),
+                e => invalid(format!("{field}.source"), e.to_string()),
+            })?;
+            let mut group_members = Vec::with_capacity(devices.len());
+            for (j, device) in devices.into_iter().enumerate() {
+                let device = match store.as_ref().and_then(|s| s.restore(device.dev_eui())) {
+                    Some(saved) => saved,
+                    None => {
+                        //without keys to give, ABP devices get generated ones
+                        let mode = match split.pick(&mut rng) {
+                            ActivationMode::AbpGivenKeys if device.session().is_none() => ActivationMode::AbpGeneratedKeys,
+                            mode => mode,
+                        };
+                        provision(device, mode, None, &mut rng)
+                    }
+                };
+                if let Some((client, p)) = &provisioning {
+                    let profile = match &p.device_profile_id_1_1 {
+                        Some(profile) if *device.version() == LoRaWANVersion::V1_1 => profile,
+                        _ => &p.device_profile_id,
+                    };
+                    client.provision_device(&device, &p.application_id, profile).await.or_else(|e| invalid("world.chirpstack_provisioning", e.to_string()))?;
+                }
+                let dev_eui = *device.dev_eui();
+                if let Some(payload) = &payload {
+                    w.set_payload_profile(dev_eui, payload.clone());
+                }
+                if let Some(firmware) = firmware {
+                    w.set_firmware_profile(dev_eui, firmware);
+                }
+                let (sf, bw, freq) = radio[j % radio.len()];
+                let position = self.position(&group.placement, j, &mut rng);
+                w.add_node(device, device_config(position, sf, freq, bw, group.radio.tx_power_dbm), traffic.model(&mut rng));
+                group_members.push(dev_eui);
+            }
+            members.push(group_members);
+        }
+
+        for (i, event) in self.fleet_events.iter().enumerate() {
+            w.add_fleet_event(event.event(&format!("fleet_events[{i}]"))?);
+        }
+        for (i, event) in self.alarm_events.iter().enumerate() {
+            w.add_alarm_event(event.event(&format!("alarm_events[{i}]"))?);
+        }
+        for (i, mc_group) in self.multicast_groups.iter().enumerate() {
+            let field = format!("multicast_groups[{i}]");
+            let (config, campaign) = mc_group.group(&field, self)?;
+            let world_error = |e: WorldError| ScenarioError::Invalid { field: field.clone(), message: e.to_string() };
+            w.add_multicast_group(config).map_err(world_error)?;
+            for (group, dev_euis) in self.device_groups.iter().zip(members.iter()) {
+                if mc_group.members.contains(&group.name) {
+                    for dev_eui in dev_euis {
+                        w.add_multicast_member(mc_group.id, *dev_eui).map_err(world_error)?;
+                    }
+                }
+            }
+            if let Some(campaign) = campaign {
+                w.add_fuota_campaign(campaign).map_err(world_error)?;
+            }
+        }
+
+        for (i, gateway) in self.gateways.iter().enumerate() {
+            match gateway {
+                GatewaySettings::NetworkController { address, position } => w.add_network_controller(NetworkControllerBridgeConfig {
+                    network_controller_address: *address,
+                    node_config: gateway_config(*position),
+                }),
+                GatewaySettings::Chirpstack { gateway_id, position, mqtt } => w.add_chirpstack_gw(ChirpstackBridgeConfig {
+                    gwid: gateway_id.clone(),
+                    node_config: gateway_config(*position),
+                    mqtt: mqtt.clone().unwrap_or_else(|| self.world.mqtt.clone()),
+                    location: self.world.origin.map(|origin| origin.offset(*position)),
+                }),
+                GatewaySettings::SemtechUdp { gateway_id, server, position } => w.add_semtech_udp_gw(SemtechUdpBridgeConfig {
+                    gateway_eui: gateway_eui(gateway_id, i)?,
+                    server_address: *server,
+                    node_config: gateway_config(*position),
+                    location: self.world.origin.map(|origin| origin.offset(*position)),
+                }),
+            }
+        }
+
+        if let Some(profile) = &self.world.traffic_profile {
+            w.set_traffic_profile(profile.profile()?);
+        }
+        if let Some(store) = store {
+            w.set_session_store(store);
+        }
+        Ok(w)
+    }
+
+    /// Deletes the devices provisioned into ChirpStack, if asked to. Returns how many.
+    pub async fn remove_provisioned(&self) -> Result<usize, ScenarioError> {
+        match &self.world.chirpstack_provisioning {
+            Some(p) if p.remove_after => {
+                let client = chirpstack_client(&p.url, &p.token, "world.chirpstack_provisioning")?;
+                client.remove_simulated_devices(&p.application_id).await.or_else(|e| invalid("world.chirpstack_provisioning", e.to_string()))
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn scenario_validation() {
+    let scenario = r#"
+        [world]
+        path_loss_model = "log_distance_normal_shadowing"
+        duration_s = 3600
+
+        [[gateways]]
+        type = "network_controller"
+        address = "127.0.0.1:9090"
+        position = { x = 100.0, y = -100.0, z = 100.0 }
+
+        [[device_groups]]
+        name = "meters"
+        count = 100
+        placement = { type = "near_gateways", side = 500.0 }
+        traffic = { type = "poisson", mean = 600.0 }
+
+        [[device_groups]]
+        count = 10
+        placement = { type = "disc", centre = { x = 0.0, y = 0.0, z = 1.0 }, radius = 200.0 }
+        radio = { spreading_factors = [7, 13] }
+        traffic = { type = "mix", models = [{ share = 0.8, model = { type = "periodic", period = 300.0 } }, { share = 0.2, model = { type = "poisson", mean = 600.0 } }] }
+    "#;
+    let scenario = Scenario::from_toml(scenario).unwrap();
+    assert_eq!(scenario.device_count(), 110);
+    assert_eq!(scenario.duration(), Some(Duration::from_secs(3600)));
+    match scenario.validate() {
+        Err(ScenarioError::Invalid { field, .. }) => assert_eq!(field, "device_groups[1].radio.spreading_factors[1]"),
+        other => panic!("{other:?}"),
+    }
+    assert!(matches!(Scenario::from_toml("[[gateways]]\ntype = \"satellite\""), Err(ScenarioError::Parse(_))));
+
+    let mut scenario = scenario;
+    scenario.device_groups[1].radio.spreading_factors.pop();
+    scenario.validate().unwrap();
+    match &mut scenario.device_groups[1].traffic {
+        TrafficSettings::Mix { models } => models[1].share = 0.1,
+        other => panic!("{other:?}"),
+    }
+    match scenario.validate() {
+        Err(ScenarioError::Invalid { field, .. }) => assert_eq!(field, "device_groups[1].traffic.models"),
+        other => panic!("{other:?}"),
+    }
+
+    scenario.device_groups.pop();
+    scenario.world.mqtt.qos = 3;
+    match scenario.validate() {
+        Err(ScenarioError::Invalid { field, .. }) => assert_eq!(field, "world.mqtt.qos"),
+        other => panic!("{other:?}"),
+    }
+    scenario.world.mqtt.qos = 1;
+
+    //the files are read, not just found
+    let path = std::env::temp_dir().join("deloran_scenario_validation.csv");
+    std::fs::write(&path, "interval\n600\nsix hundred\n").unwrap();
+    scenario.device_groups[0].traffic = TrafficSettings::Empirical { path: path.clone() };
+    match scenario.validate() {
+        Err(ScenarioError::Invalid { field, message }) => assert_eq!((field.as_str(), message.as_str()), ("device_groups[0].traffic.path", "malformed line 3: \"six hundred\"")),
+        other => panic!("{other:?}"),
+    }
+    scenario.device_groups[0].traffic = TrafficSettings::Periodic { period: 600.0 };
+    scenario.world.traffic_profile = Some(TrafficProfileSettings { path: path.clone(), utc_offset_h: 1.0 });
+    match scenario.validate() {
+        Err(ScenarioError::Invalid { field, .. }) => assert_eq!(field, "world.traffic_profile.path"),
+        other => panic!("{other:?}"),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn scenario_events_and_profiles() {
+    let image = std::env::temp_dir().join("deloran_scenario_events_and_profiles.bin");
+    std::fs::write(&image, [7u8; 1000]).unwrap();
+    let scenario = format!(
+        r#"
+        [world]
+        payload = {{ generator = {{ type = "cayenne_lpp", sensors = ["temperature", "humidity"] }}, f_port = 2, confirmed_share = 0.1 }}
+        firmware = {{ keeps_session = true }}
+
+        [[gateways]]
+        type = "network_controller"
+        address = "127.0.0.1:9090"
+        position = {{ x = 100.0, y = -100.0, z = 100.0 }}
+
+        [[device_groups]]
+        name = "meters"
+        count = 10
+        placement = {{ type = "near_gateways", side = 500.0 }}
+        traffic = {{ type = "periodic", period = 600.0 }}
+        payload = {{ generator = {{ type = "fixed", size = 12 }} }}
+
+        [[fleet_events]]
+        kind = "reboot"
+        at_s = 7200
+        fraction = 0.4
+        outage_s = 600
+
+        [[alarm_events]]
+        at_s = 3600
+        centre = {{ x = 100.0, y = 100.0, z = 0.0 }}
+        radius = 300.0
+        propagation_speed = inf
+        payload = {{ generator = {{ type = "text" }}, f_port = 3 }}
+
+        [[multicast_groups]]
+        id = 1
+        mc_addr = "01020304"
+        mc_key = "000102030405060708090a0b0c0d0e0f"
+        members = ["meters"]
+        fuota = {{ image = {:?}, frag_size = 48, redundancy = 10, gateway = 0, interval_s = 2.0 }}
+    "#,
+        image.display().to_string()
+    );
+    let mut scenario = Scenario::from_toml(&scenario).unwrap();
+    scenario.validate().unwrap();
+    let (config, campaign) = scenario.multicast_groups[0].group("multicast_groups[0]", &scenario).unwrap();
+    assert_eq!(config.fragmentation.unwrap().nb_frag, 21);
+    assert_eq!(campaign.unwrap().gateway_position, scenario.gateways[0].position());
+
+    let invalid_field = |scenario: &Scenario| match scenario.validate() {
+        Err(ScenarioError::Invalid { field, .. }) => field,
+        other => panic!("{other:?}"),
+    };
+    scenario.fleet_events[0].fraction = 1.5;
+    assert_eq!(invalid_field(&scenario), "fleet_events[0].fraction");
+    scenario.fleet_events[0].fraction = 0.4;
+    scenario.alarm_events[0].payload.as_mut().unwrap().f_port = 0;
+    assert_eq!(invalid_field(&scenario), "alarm_events[0].payload.f_port");
+    scenario.alarm_events[0].payload = None;
+    scenario.device_groups[0].firmware = Some(FirmwareSettings { boot_time_s: -1.0, ..Default::default() });
+    assert_eq!(invalid_field(&scenario), "device_groups[0].firmware.boot_time_s");
+    scenario.device_groups[0].firmware = None;
+    scenario.multicast_groups[0].members.push(String::from("lights"));
+    assert_eq!(invalid_field(&scenario), "multicast_groups[0].members[1]");
+    scenario.multicast_groups[0].members.pop();
+    //SF12 downlinks carry 51 bytes
+    scenario.multicast_groups[0].fuota.as_mut().unwrap().frag_size = 49;
+    assert_eq!(invalid_field(&scenario), "multicast_groups[0].fuota.frag_size");
+    std::fs::remove_file(&image).unwrap();
+}
+
+#[test]
+fn device_count_override() {
+    let group = |count| format!("[[device_groups]]\ncount = {count}\nplacement = {{ type = \"disc\", centre = {{ x = 0.0, y = 0.0, z = 1.0 }}, radius = 200.0 }}\ntraffic = {{ type = \"poisson\", mean = 600.0 }}\n");
+    let mut scenario = Scenario::from_toml(&[String::from("gateways = []\n"), group(1), group(1), group(1)].concat()).unwrap();
+    scenario.set_device_count(5).unwrap();
+    assert_eq!(scenario.device_groups.iter().map(|g| g.count).collect::<Vec<_>>(), [2, 2, 1]);
+    //rounding would leave the last group empty
+    scenario.set_device_count(3).unwrap();
+    assert_eq!(scenario.device_groups.iter().map(|g| g.count).collect::<Vec<_>>(), [1, 1, 1]);
+    match scenario.set_device_count(2) {
+        Err(ScenarioError::Invalid { field, .. }) => assert_eq!(field, "devices"),
+        other => panic!("{other:?}"),
+    }
+
+    let mut scenario = Scenario::from_toml(&[String::from("gateways = []\n"), group(100), group(1), group(1)].concat()).unwrap();
+    scenario.set_device_count(10).unwrap();
+    assert_eq!(scenario.device_groups.iter().map(|g| g.count).collect::<Vec<_>>(), [8, 1, 1]);
+}
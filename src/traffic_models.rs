@@ -1,23 +1,119 @@
 use core::fmt;
-use std::fmt::{Debug, Formatter};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
 use std::path::Path;
+use std::sync::Arc;
 
-use lazy_static::lazy_static;
 use rand::Rng;
 use rand::distributions::Distribution;
 
+use crate::constants::MIN_UPLINK_INTERVAL;
 
-lazy_static! {
-    pub static ref REGULAR_TRAFFIC_DISTRIBUTION: TrafficDistribution = TrafficDistribution::new("loed_regular_traffic_distribution.csv", String::from("regular"));
-    pub static ref UNREGULAR_TRAFFIC_DISTRIBUTION: TrafficDistribution = TrafficDistribution::new("loed_unregular_traffic_distribution.csv", String::from("unregular"));
+//draws used to turn a parametric model into a table
+const DISCRETIZATION_SAMPLES: usize = 100_000;
+
+#[derive(Debug)]
+pub enum TrafficDistributionError {
+    Io(io::Error),
+    Malformed { line: usize, content: String }, //line numbers start at 1
+    Empty,
+    LengthMismatch(usize, usize),
+    InvalidProbabilities(f64), //their sum, when it is not 1 or one of them is negative
+    InvalidSample(f64),        //not finite, or not positive for the fits working on logarithms
+    NotEnoughSamples(usize),
+    NothingAbove(f64), //no value of a table reaches the minimum asked for
+    NoTraffic,         //a traffic profile with every multiplier at 0
 }
 
+impl Display for TrafficDistributionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TrafficDistributionError::Io(e) => write!(f, "{e}"),
+            TrafficDistributionError::Malformed { line, content } => write!(f, "malformed line {line}: {content:?}"),
+            TrafficDistributionError::Empty => write!(f, "no values"),
+            TrafficDistributionError::LengthMismatch(values, probabilities) => write!(f, "{values} values but {probabilities} probabilities"),
+            TrafficDistributionError::InvalidProbabilities(total) => write!(f, "probabilities must be positive and sum to 1, not {total}"),
+            TrafficDistributionError::InvalidSample(sample) => write!(f, "invalid sample {sample}"),
+            TrafficDistributionError::NotEnoughSamples(n) => write!(f, "not enough samples: {n}"),
+            TrafficDistributionError::NothingAbove(min) => write!(f, "no value of at least {min}"),
+            TrafficDistributionError::NoTraffic => write!(f, "every multiplier is 0"),
+        }
+    }
+}
+
+impl std::error::Error for TrafficDistributionError {}
+
+impl From<io::Error> for TrafficDistributionError {
+    fn from(e: io::Error) -> Self {
+        TrafficDistributionError::Io(e)
+    }
+}
+
+/// A LOED table without the intervals below `MIN_UPLINK_INTERVAL`, which the LOED nodes never use.
+pub fn loed_intervals<T>(path: T) -> Result<TrafficDistribution, TrafficDistributionError>
+where T: AsRef<Path> {
+    let name = path.as_ref().display().to_string();
+    TrafficDistribution::new(path, name)?.at_least(MIN_UPLINK_INTERVAL)
+}
+
+//one number per line, picked by `field`, blank lines skipped and a header line allowed
+fn read_column<T, F>(path: T, field: F) -> Result<Vec<f64>, TrafficDistributionError>
+where
+    T: AsRef<Path>,
+    F: Fn(&str) -> Option<&str>,
+{
+    let content = std::fs::read_to_string(path)?;
+    let mut values = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match field(line).and_then(|v| v.trim().parse::<f64>().ok()) {
+            Some(value) if value.is_finite() && value >= 0.0 => values.push(value),
+            Some(value) => return Err(TrafficDistributionError::InvalidSample(value)),
+            None if i == 0 => (),
+            None => return Err(TrafficDistributionError::Malformed { line: i + 1, content: line.to_string() }),
+        }
+    }
+    if values.is_empty() {
+        return Err(TrafficDistributionError::Empty);
+    }
+    Ok(values)
+}
+
+/// Inter-arrival times in seconds per DevEUI, from a trace of `dev_eui,timestamp` lines with timestamps
+/// in seconds, in any order. A header line is skipped.
+pub fn inter_arrival_times<T>(path: T) -> Result<HashMap<String, Vec<f64>>, TrafficDistributionError>
+where T: AsRef<Path> {
+    let content = std::fs::read_to_string(path)?;
+    let mut timestamps: HashMap<String, Vec<f64>> = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(',').and_then(|(dev_eui, t)| Some((dev_eui.trim(), t.trim().parse::<f64>().ok()?)));
+        match parsed {
+            Some((dev_eui, t)) => timestamps.entry(dev_eui.to_string()).or_default().push(t),
+            None if i == 0 => (),
+            None => return Err(TrafficDistributionError::Malformed { line: i + 1, content: line.to_string() }),
+        }
+    }
+    Ok(timestamps
+        .into_iter()
+        .map(|(dev_eui, mut t)| {
+            t.sort_by(f64::total_cmp);
+            (dev_eui, t.windows(2).map(|w| w[1] - w[0]).collect())
+        })
+        .collect())
+}
 
 // Define your custom distribution
 pub struct TrafficDistribution {
     name: String,
-    values: Vec<f64>,
+    values: Vec<f64>, //sorted
     probabilities: Vec<f64>,
+    interpolate: bool, //the probability of each value is spread uniformly down to the previous one
 }
 
 impl Debug for TrafficDistribution {
@@ -27,43 +123,147 @@ impl Debug for TrafficDistribution {
 }
 
 impl TrafficDistribution {
-    pub fn new<T>(path: T, name: String) -> Self
+    /// Table of `value,probability` lines, like `loed_regular_traffic_distribution.csv`.
+    pub fn new<T>(path: T, name: String) -> Result<Self, TrafficDistributionError>
     where T: AsRef<Path> {
-        let content = std::fs::read_to_string(path).unwrap();
+        let content = std::fs::read_to_string(path)?;
         let mut values = Vec::new();
         let mut probabilities = Vec::new();
 
-        content.split('\n').for_each(|line| {
-            let splitted = line.split(',').collect::<Vec<&str>>();
-            if splitted.len() == 2 {
-                values.push(splitted[0].parse::<f64>().unwrap());
-                probabilities.push(splitted[1].parse::<f64>().unwrap());
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = line.split_once(',').and_then(|(v, p)| Some((v.trim().parse::<f64>().ok()?, p.trim().parse::<f64>().ok()?)));
+            match parsed {
+                Some((value, probability)) => {
+                    values.push(value);
+                    probabilities.push(probability);
+                }
+                None => return Err(TrafficDistributionError::Malformed { line: i + 1, content: line.to_string() }),
             }
-        });
+        }
+        TrafficDistribution::from_table(name, values, probabilities)
+    }
 
-        assert_eq!(values.len(), probabilities.len(), "Values and probabilities must have the same length");
+    pub fn from_table(name: String, values: Vec<f64>, probabilities: Vec<f64>) -> Result<Self, TrafficDistributionError> {
+        if values.is_empty() {
+            return Err(TrafficDistributionError::Empty);
+        }
+        if values.len() != probabilities.len() {
+            return Err(TrafficDistributionError::LengthMismatch(values.len(), probabilities.len()));
+        }
+        if let Some(value) = values.iter().find(|v| !v.is_finite()) {
+            return Err(TrafficDistributionError::InvalidSample(*value));
+        }
         let total: f64 = probabilities.iter().sum();
-        assert!((total - 1.0).abs() < 1e-9, "Probabilities must sum to 1");
-        TrafficDistribution { values, probabilities, name }
-    
+        if probabilities.iter().any(|p| p.is_nan() || *p < 0.0) || (total - 1.0).abs() >= 1e-9 {
+            return Err(TrafficDistributionError::InvalidProbabilities(total));
+        }
+
+        let mut table = values.into_iter().zip(probabilities).collect::<Vec<(f64, f64)>>();
+        table.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (values, probabilities) = table.into_iter().unzip();
+        Ok(TrafficDistribution { values, probabilities, name, interpolate: false })
     }
 
-    pub fn mean(&self) -> f64 {
-        let mut sum = 0.0;
+    /// Histogram of `samples` in `bins` bins of the same width, sampled continuously within each bin.
+    pub fn from_samples(name: String, samples: &[f64], bins: usize) -> Result<Self, TrafficDistributionError> {
+        if samples.is_empty() || bins == 0 {
+            return Err(TrafficDistributionError::NotEnoughSamples(samples.len()));
+        }
+        if let Some(sample) = samples.iter().find(|s| !s.is_finite()) {
+            return Err(TrafficDistributionError::InvalidSample(*sample));
+        }
+        let min = samples.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = samples.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if min == max {
+            return TrafficDistribution::from_table(name, vec![min], vec![1.0]);
+        }
+
+        //the bin edges, the lower one with no probability of its own
+        let width = (max - min) / bins as f64;
+        let mut counts = vec![0usize; bins + 1];
+        for sample in samples {
+            counts[(((sample - min) / width).ceil() as usize).clamp(1, bins)] += 1;
+        }
+        let values = (0..=bins).map(|i| min + width * i as f64).collect();
+        let probabilities = counts.iter().map(|c| *c as f64 / samples.len() as f64).collect();
+        Ok(TrafficDistribution::from_table(name, values, probabilities)?.with_interpolation(true))
+    }
+
+    /// Inter-arrival times of every node of a trace, see `inter_arrival_times`.
+    pub fn from_trace<T>(path: T, name: String, bins: usize) -> Result<Self, TrafficDistributionError>
+    where T: AsRef<Path> {
+        let intervals = inter_arrival_times(path)?.into_values().flatten().collect::<Vec<f64>>();
+        TrafficDistribution::from_samples(name, &intervals, bins)
+    }
+
+    /// The table conditioned on values of at least `min`, as if the smaller ones were drawn again.
+    pub fn at_least(&self, min: f64) -> Result<Self, TrafficDistributionError> {
+        let mut values = Vec::new();
+        let mut probabilities = Vec::new();
+        let mut previous = self.values[0];
         for (&value, &probability) in self.values.iter().zip(&self.probabilities) {
-            sum += value * probability;
+            let low = if self.interpolate { previous } else { value };
+            previous = value;
+            if value < min {
+                continue;
+            }
+            if low < min {
+                //the segment crossing min keeps its part above it, from a new first point
+                values.push(min);
+                probabilities.push(0.0);
+                probabilities.push(probability * (value - min) / (value - low));
+            } else {
+                probabilities.push(probability);
+            }
+            values.push(value);
+        }
+        let total = probabilities.iter().sum::<f64>();
+        if total <= 0.0 {
+            return Err(TrafficDistributionError::NothingAbove(min));
         }
-        sum
+        let probabilities = probabilities.into_iter().map(|p| p / total).collect();
+        Ok(TrafficDistribution::from_table(self.name.clone(), values, probabilities)?.with_interpolation(self.interpolate))
     }
 
-    pub fn variance(&self) -> f64 {
-        let mean = self.mean();
-        let mut sum_of_squares = 0.0;
-        for (value, &probability) in self.values.iter().zip(&self.probabilities) {
-            let diff = value - mean;
-            sum_of_squares += diff * diff * probability;
+    pub fn with_interpolation(mut self, interpolate: bool) -> Self {
+        self.interpolate = interpolate;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Writes the table in the format read by `new`.
+    pub fn to_csv<T>(&self, path: T) -> io::Result<()>
+    where T: AsRef<Path> {
+        let content = self.values.iter().zip(&self.probabilities).map(|(v, p)| format!("{v},{p}\n")).collect::<String>();
+        std::fs::write(path, content)
+    }
+
+    //first and second moments, of the points or of the uniform segments between them
+    fn moments(&self) -> (f64, f64) {
+        let mut previous = self.values[0];
+        let mut moments = (0.0, 0.0);
+        for (&value, &probability) in self.values.iter().zip(&self.probabilities) {
+            let low = if self.interpolate { previous } else { value };
+            moments.0 += probability * (low + value) / 2.0;
+            moments.1 += probability * (low * low + low * value + value * value) / 3.0;
+            previous = value;
         }
-        sum_of_squares
+        moments
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.moments().0
+    }
+
+    pub fn variance(&self) -> f64 {
+        let (mean, square_mean) = self.moments();
+        (square_mean - mean * mean).max(0.0)
     }
 
     pub fn standard_deviation(&self) -> f64 {
@@ -75,28 +275,324 @@ impl Distribution<f64> for TrafficDistribution {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
         let mut cumulative_probability = 0.0;
         let random_value = rng.gen_range(0.0..1.0);
+        let mut previous = self.values[0];
         for (&value, &probability) in self.values.iter().zip(&self.probabilities) {
-            cumulative_probability += probability;
-            if random_value < cumulative_probability {
-                return value;
+            if random_value < cumulative_probability + probability {
+                if !self.interpolate {
+                    return value;
+                }
+                return previous + (value - previous) * (random_value - cumulative_probability) / probability;
             }
+            cumulative_probability += probability;
+            previous = value;
         }
-        panic!("Failed to sample from loed distribution, means that the sum of probabilities is not 1.");
+        //the probabilities sum to slightly less than 1
+        previous
     }
 }
 
-#[derive(Debug)]
+fn exponential<R: Rng + ?Sized>(mean: f64, rng: &mut R) -> f64 {
+    -mean * (1.0 - rng.gen_range(0.0..1.0f64)).ln()
+}
+
+//Box-Muller
+fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+    let u = 1.0 - rng.gen_range(0.0..1.0f64);
+    (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * rng.gen_range(0.0..1.0f64)).cos()
+}
+
+//Abramowitz and Stegun 7.1.26, absolute error below 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - polynomial * (-x * x).exp()).copysign(x)
+}
+
+fn check_samples(samples: &[f64], positive: bool) -> Result<(), TrafficDistributionError> {
+    if samples.len() < 2 {
+        return Err(TrafficDistributionError::NotEnoughSamples(samples.len()));
+    }
+    match samples.iter().find(|s| !s.is_finite() || (positive && **s <= 0.0) || **s < 0.0) {
+        Some(sample) => Err(TrafficDistributionError::InvalidSample(*sample)),
+        None => Ok(()),
+    }
+}
+
+/// Time in seconds between two uplinks of a node.
+#[derive(Debug, Clone)]
 pub enum TrafficModel {
-    Custom(TrafficDistribution),
+    Custom(Arc<TrafficDistribution>),
     Periodic(f64),
+    PeriodicWithJitter { period: f64, jitter: f64 }, //period plus a uniform delay up to jitter
+    Poisson { mean: f64 },                          //exponential intervals, mean is 1/rate
+    Uniform { min: f64, max: f64 },
+    //Markov-modulated on/off source: Poisson uplinks during exponentially long on periods, silence in the off ones
+    OnOff { on_mean: f64, off_mean: f64, interval_mean: f64 },
+    Empirical(Arc<Vec<f64>>), //intervals drawn from the ones observed in a trace
+    LogNormal { mu: f64, sigma: f64 }, //of the logarithm of the interval
+    Weibull { shape: f64, scale: f64 },
 }
 
+impl TrafficModel {
+    /// Intervals in seconds from the first column of a file, one per line, a header line is skipped.
+    pub fn empirical<T>(path: T) -> Result<Self, TrafficDistributionError>
+    where T: AsRef<Path> {
+        let intervals = read_column(path, |line| line.split(',').next())?;
+        Ok(TrafficModel::Empirical(Arc::new(intervals)))
+    }
+
+    /// Regular LOED node: a period drawn once from `periods`, see `loed_intervals`, with 5 s of jitter.
+    pub fn loed_regular<R: Rng + ?Sized>(periods: &TrafficDistribution, rng: &mut R) -> Self {
+        TrafficModel::PeriodicWithJitter { period: periods.sample(rng), jitter: 5.0 }
+    }
+
+    /// Maximum likelihood exponential intervals, as a Poisson model.
+    pub fn fit_exponential(samples: &[f64]) -> Result<Self, TrafficDistributionError> {
+        check_samples(samples, false)?;
+        Ok(TrafficModel::Poisson { mean: samples.iter().sum::<f64>() / samples.len() as f64 })
+    }
+
+    pub fn fit_log_normal(samples: &[f64]) -> Result<Self, TrafficDistributionError> {
+        check_samples(samples, true)?;
+        let n = samples.len() as f64;
+        let mu = samples.iter().map(|s| s.ln()).sum::<f64>() / n;
+        let sigma = (samples.iter().map(|s| (s.ln() - mu).powi(2)).sum::<f64>() / n).sqrt();
+        Ok(TrafficModel::LogNormal { mu, sigma })
+    }
+
+    /// Maximum likelihood Weibull intervals, with the shape found by bisection.
+    pub fn fit_weibull(samples: &[f64]) -> Result<Self, TrafficDistributionError> {
+        check_samples(samples, true)?;
+        //the shape does not depend on the unit, scaling to the largest sample keeps the powers below 1
+        let max = samples.iter().copied().fold(0.0, f64::max);
+        let scaled = samples.iter().map(|s| s / max).collect::<Vec<f64>>();
+        let log_mean = scaled.iter().map(|s| s.ln()).sum::<f64>() / scaled.len() as f64;
+        let power_mean = |shape: f64| scaled.iter().map(|s| s.powf(shape)).sum::<f64>() / scaled.len() as f64;
+        //increasing in the shape, zero at the estimate
+        let score = |shape: f64| {
+            let weighted_log_mean = scaled.iter().map(|s| s.powf(shape) * s.ln()).sum::<f64>() / scaled.len() as f64;
+            weighted_log_mean / power_mean(shape) - 1.0 / shape - log_mean
+        };
+        let (mut low, mut high) = (1e-3, 1e3);
+        for _ in 0..100 {
+            let shape = (low + high) / 2.0;
+            if score(shape) < 0.0 {
+                low = shape;
+            } else {
+                high = shape;
+            }
+        }
+        let shape = (low + high) / 2.0;
+        Ok(TrafficModel::Weibull { shape, scale: max * power_mean(shape).powf(1.0 / shape) })
+    }
+
+    /// The parametric fit closest to `samples`, with its Kolmogorov-Smirnov distance.
+    pub fn fit_best(samples: &[f64]) -> Result<(Self, f64), TrafficDistributionError> {
+        let mut fits = vec![TrafficModel::fit_exponential(samples)?];
+        //zero intervals (duplicated timestamps) rule out the fits on logarithms
+        if samples.iter().all(|s| *s > 0.0) {
+            fits.push(TrafficModel::fit_log_normal(samples)?);
+            fits.push(TrafficModel::fit_weibull(samples)?);
+        }
+        Ok(fits
+            .into_iter()
+            .map(|model| {
+                let distance = model.ks_distance(samples).unwrap();
+                (model, distance)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap())
+    }
+
+    /// Cumulative distribution function of the parametric models.
+    pub fn cdf(&self, x: f64) -> Option<f64> {
+        if x <= 0.0 {
+            return matches!(self, TrafficModel::Poisson { .. } | TrafficModel::LogNormal { .. } | TrafficModel::Weibull { .. }).then_some(0.0);
+        }
+        match self {
+            TrafficModel::Poisson { mean } => Some(1.0 - (-x / mean).exp()),
+            TrafficModel::LogNormal { mu, sigma } => Some(0.5 * (1.0 + erf((x.ln() - mu) / (sigma * std::f64::consts::SQRT_2)))),
+            TrafficModel::Weibull { shape, scale } => Some(1.0 - (-(x / scale).powf(*shape)).exp()),
+            _ => None,
+        }
+    }
+
+    /// Largest gap between the empirical distribution of `samples` and the model, None for the non parametric models.
+    pub fn ks_distance(&self, samples: &[f64]) -> Option<f64> {
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = sorted.len() as f64;
+        let mut distance: f64 = 0.0;
+        for (i, x) in sorted.iter().enumerate() {
+            let cdf = self.cdf(*x)?;
+            distance = distance.max((cdf - i as f64 / n).abs()).max(((i + 1) as f64 / n - cdf).abs());
+        }
+        Some(distance)
+    }
+
+    /// Table approximating the model, to be exported with `TrafficDistribution::to_csv`.
+    pub fn discretize<R: Rng + ?Sized>(&self, name: String, bins: usize, rng: &mut R) -> Result<TrafficDistribution, TrafficDistributionError> {
+        let samples = (0..DISCRETIZATION_SAMPLES).map(|_| self.sample(rng)).collect::<Vec<f64>>();
+        TrafficDistribution::from_samples(name, &samples, bins)
+    }
+}
 
 impl Distribution<f64> for TrafficModel {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
         match self {
             TrafficModel::Custom(distribution) => distribution.sample(rng),
             TrafficModel::Periodic(period) => *period,
+            TrafficModel::PeriodicWithJitter { period, jitter } => period + rng.gen_range(0.0..=*jitter),
+            TrafficModel::Poisson { mean } => exponential(*mean, rng),
+            TrafficModel::Uniform { min, max } => rng.gen_range(*min..=*max),
+            TrafficModel::OnOff { on_mean, off_mean, interval_mean } => {
+                //uplinks only happen in on periods and every duration is memoryless, so after an uplink the
+                //source is always on again and intervals are independent: the on period either outlasts
+                //the next uplink or ends first, adding an off period before the source starts over
+                let mut interval = 0.0;
+                loop {
+                    let next_uplink = exponential(*interval_mean, rng);
+                    let on_left = exponential(*on_mean, rng);
+                    if next_uplink < on_left {
+                        return interval + next_uplink;
+                    }
+                    interval += on_left + exponential(*off_mean, rng);
+                }
+            }
+            TrafficModel::Empirical(intervals) => intervals[rng.gen_range(0..intervals.len())],
+            TrafficModel::LogNormal { mu, sigma } => (mu + sigma * standard_normal(rng)).exp(),
+            TrafficModel::Weibull { shape, scale } => scale * (-(1.0 - rng.gen_range(0.0..1.0f64)).ln()).powf(1.0 / shape),
+        }
+    }
+}
+
+//1970-01-01 was a Thursday, weekly profiles start on Monday
+const MONDAY_EPOCH_OFFSET_S: f64 = 4.0 * 86400.0;
+
+/// Hourly rate multipliers, repeated every 24 hours for 24 values or every week (from Monday) for 168.
+/// A curve averaging 1 keeps the mean rate of the traffic models it modulates.
+#[derive(Debug, Clone)]
+pub struct TrafficProfile {
+    multipliers: Vec<f64>,
+    utc_offset_h: f64, //the profile follows local time
+}
+
+impl TrafficProfile {
+    const BUCKET_S: f64 = 3600.0;
+
+    pub fn new(multipliers: Vec<f64>, utc_offset_h: f64) -> Result<Self, TrafficDistributionError> {
+        if multipliers.is_empty() {
+            return Err(TrafficDistributionError::Empty);
+        }
+        if let Some(m) = multipliers.iter().find(|m| !(m.is_finite() && **m >= 0.0)) {
+            return Err(TrafficDistributionError::InvalidSample(*m));
+        }
+        if multipliers.iter().all(|m| *m == 0.0) {
+            return Err(TrafficDistributionError::NoTraffic);
+        }
+        Ok(TrafficProfile { multipliers, utc_offset_h })
+    }
+
+    /// One multiplier per line, as the last field of `hour,multiplier` lines or alone, a header line is skipped.
+    pub fn from_csv<T>(path: T, utc_offset_h: f64) -> Result<Self, TrafficDistributionError>
+    where T: AsRef<Path> {
+        TrafficProfile::new(read_column(path, |line| line.rsplit(',').next())?, utc_offset_h)
+    }
+
+    /// Real time taken by `interval` seconds of traffic at the nominal rate, starting at `from` (unix seconds):
+    /// intervals shrink in the buckets with a multiplier above 1 and stretch, or span, the quiet ones.
+    pub fn stretch(&self, from: f64, interval: f64) -> f64 {
+        let start = from + self.utc_offset_h * 3600.0 - MONDAY_EPOCH_OFFSET_S;
+        let mut t = start;
+        let mut left = interval;
+        while left > 0.0 {
+            let bucket = (t / Self::BUCKET_S).floor();
+            let multiplier = self.multipliers[bucket.rem_euclid(self.multipliers.len() as f64) as usize];
+            let bucket_end = (bucket + 1.0) * Self::BUCKET_S;
+            let capacity = (bucket_end - t) * multiplier;
+            if capacity >= left {
+                return t + left / multiplier - start;
+            }
+            left -= capacity;
+            t = bucket_end;
         }
+        t - start
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn traffic_model_means() {
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let mut mean = |model: TrafficModel| (0..100_000).map(|_| model.sample(&mut rng)).sum::<f64>() / 100_000.0;
+
+    assert_eq!(mean(TrafficModel::Periodic(60.0)), 60.0);
+    assert!((mean(TrafficModel::PeriodicWithJitter { period: 60.0, jitter: 10.0 }) - 65.0).abs() < 0.5);
+    assert!((mean(TrafficModel::Poisson { mean: 120.0 }) - 120.0).abs() < 2.0);
+    assert!((mean(TrafficModel::Uniform { min: 30.0, max: 90.0 }) - 60.0).abs() < 0.5);
+    //on a third of the time, so intervals are three times longer than in the on periods
+    assert!((mean(TrafficModel::OnOff { on_mean: 600.0, off_mean: 1200.0, interval_mean: 60.0 }) - 180.0).abs() < 5.0);
+    assert_eq!(mean(TrafficModel::Empirical(Arc::new(vec![10.0, 30.0]))).round(), 20.0);
+}
+
+#[test]
+fn traffic_profile_stretch() {
+    //quiet nights from midnight to noon, twice the rate in the afternoon and evening
+    let profile = TrafficProfile::new([vec![0.0; 12], vec![2.0; 12]].concat(), 0.0).unwrap();
+    let midnight = 19_000.0 * 86400.0;
+    assert_eq!(profile.stretch(midnight, 3600.0), 12.0 * 3600.0 + 1800.0);
+    assert_eq!(profile.stretch(midnight + 13.0 * 3600.0, 600.0), 300.0);
+    //a whole day of nominal traffic from midnight takes a day
+    assert_eq!(profile.stretch(midnight, 86400.0), 86400.0);
+    assert_eq!(TrafficProfile::new(vec![1.0], 2.0).unwrap().stretch(midnight + 123.0, 4567.0), 4567.0);
+    assert!(matches!(TrafficProfile::new(vec![0.0; 24], 0.0), Err(TrafficDistributionError::NoTraffic)));
+    assert!(matches!(TrafficProfile::new(vec![1.0, -1.0], 0.0), Err(TrafficDistributionError::InvalidSample(_))));
+
+    //bad lines are reported, past the header
+    let path = std::env::temp_dir().join("deloran_traffic_profile_stretch.csv");
+    std::fs::write(&path, "hour,multiplier\n0,0.5\n1,1.5\n").unwrap();
+    assert_eq!(TrafficProfile::from_csv(&path, 0.0).unwrap().multipliers, vec![0.5, 1.5]);
+    std::fs::write(&path, "hour,multiplier\n0,0.5\n\n2,x\n").unwrap();
+    assert!(matches!(TrafficProfile::from_csv(&path, 0.0), Err(TrafficDistributionError::Malformed { line: 4, .. })));
+    std::fs::write(&path, "interval\n10\n1O\n").unwrap();
+    assert!(matches!(TrafficModel::empirical(&path), Err(TrafficDistributionError::Malformed { line: 3, .. })));
+    std::fs::write(&path, "interval\n").unwrap();
+    assert!(matches!(TrafficModel::empirical(&path), Err(TrafficDistributionError::Empty)));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn traffic_distribution_toolkit() {
+    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+    let samples = (0..20_000).map(|_| TrafficModel::Weibull { shape: 1.5, scale: 600.0 }.sample(&mut rng)).collect::<Vec<f64>>();
+    let TrafficModel::Weibull { shape, scale } = TrafficModel::fit_weibull(&samples).unwrap() else { unreachable!() };
+    assert!((shape - 1.5).abs() < 0.05 && (scale - 600.0).abs() < 10.0);
+    assert!(matches!(TrafficModel::fit_best(&samples).unwrap().0, TrafficModel::Weibull { .. }));
+    let TrafficModel::LogNormal { mu, sigma } = TrafficModel::fit_log_normal(&samples).unwrap() else { unreachable!() };
+    assert!(TrafficModel::LogNormal { mu, sigma }.ks_distance(&samples).unwrap() > 0.01);
+
+    //export and read back a fitted table
+    let path = std::env::temp_dir().join("deloran_traffic_distribution_toolkit.csv");
+    let table = TrafficModel::Poisson { mean: 300.0 }.discretize(String::from("poisson"), 200, &mut rng).unwrap();
+    table.to_csv(&path).unwrap();
+    let table = TrafficDistribution::new(&path, String::from("poisson")).unwrap().with_interpolation(true);
+    assert!((table.mean() - 300.0).abs() < 5.0);
+    assert!((table.standard_deviation() - 300.0).abs() < 10.0);
+    let mean = (0..100_000).map(|_| table.sample(&mut rng)).sum::<f64>() / 100_000.0;
+    assert!((mean - 300.0).abs() < 5.0);
+
+    std::fs::write(&path, "10,0.5\n20;0.5\n").unwrap();
+    assert!(matches!(TrafficDistribution::new(&path, String::new()), Err(TrafficDistributionError::Malformed { line: 2, .. })));
+    std::fs::write(&path, "10,0.5\n20,0.4\n").unwrap();
+    assert!(matches!(TrafficDistribution::new(&path, String::new()), Err(TrafficDistributionError::InvalidProbabilities(_))));
+    std::fs::write(&path, "10,0.5\n20,0.25\n40,0.25\n").unwrap();
+    let table = TrafficDistribution::new(&path, String::new()).unwrap();
+    assert_eq!(table.at_least(15.0).unwrap().mean(), 30.0);
+    //a third of the kept probability is spread over [15, 20], the rest over [20, 40]
+    assert!((table.with_interpolation(true).at_least(15.0).unwrap().mean() - 77.5 / 3.0).abs() < 1e-9);
+    assert!(matches!(TrafficDistribution::new(&path, String::new()).unwrap().at_least(50.0), Err(TrafficDistributionError::NothingAbove(_))));
+    std::fs::write(&path, "dev_eui,timestamp\na,100\nb,5\na,40\na,160\nb,35\n").unwrap();
+    let intervals = inter_arrival_times(&path).unwrap();
+    assert_eq!(intervals["a"], vec![60.0, 60.0]);
+    assert_eq!(intervals["b"], vec![30.0]);
+    std::fs::remove_file(&path).unwrap();
+}